@@ -0,0 +1,231 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::storage::Storage;
+use crate::torrent::Torrent;
+
+/// Puts a write-back cache and a read cache in front of another storage. Blocks are held until
+/// their piece is complete and verified, then written out in one go, so a piece failing its hash
+/// check while still cached never reaches the disk. Pieces read back are kept whole for serving
+/// further blocks of them to peers.
+///
+/// Each cache holds up to `capacity` bytes; the oldest pieces are written out or dropped first.
+/// Blocks written out to make room have not been checked yet, and their piece is verified by
+/// reading it back from the inner storage.
+pub struct CachedStorage {
+    inner: Arc<dyn Storage>,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    // Blocks not yet written to the inner storage, by piece and offset. Blocks of a piece never
+    // overlap.
+    dirty: HashMap<usize, BTreeMap<usize, Vec<u8>>>,
+    // Pieces with dirty blocks, oldest first.
+    dirty_order: VecDeque<usize>,
+    dirty_bytes: usize,
+    // Whole pieces as stored, least recently used first.
+    clean: VecDeque<(usize, Arc<[u8]>)>,
+    clean_bytes: usize,
+}
+
+impl CacheState {
+    /// Removes the dirty blocks of a piece, in order.
+    fn take_dirty(&mut self, piece_index: usize) -> Option<BTreeMap<usize, Vec<u8>>> {
+        let blocks = self.dirty.remove(&piece_index)?;
+        self.dirty_order.retain(|&p| p != piece_index);
+        self.dirty_bytes -= blocks.values().map(Vec::len).sum::<usize>();
+        Some(blocks)
+    }
+
+    /// Removes the dirty blocks of a piece if they cover all of it, joined together.
+    fn take_complete(&mut self, piece_index: usize, piece_size: usize) -> Option<Vec<u8>> {
+        let blocks = self.dirty.get(&piece_index)?;
+        let mut covered = 0;
+        for (&begin, block) in blocks {
+            if begin != covered {
+                return None;
+            }
+            covered += block.len();
+        }
+        if covered != piece_size {
+            return None;
+        }
+        let blocks = self.take_dirty(piece_index)?;
+        Some(blocks.into_values().flatten().collect())
+    }
+
+    fn get_clean(&mut self, piece_index: usize) -> Option<Arc<[u8]>> {
+        let position = self.clean.iter().position(|(p, _)| *p == piece_index)?;
+        let entry = self.clean.remove(position)?;
+        let piece = entry.1.clone();
+        self.clean.push_back(entry);
+        Some(piece)
+    }
+
+    fn remove_clean(&mut self, piece_index: usize) {
+        if let Some(position) = self.clean.iter().position(|(p, _)| *p == piece_index) {
+            let (_, piece) = self.clean.remove(position).expect("position is in range");
+            self.clean_bytes -= piece.len();
+        }
+    }
+
+    fn insert_clean(&mut self, piece_index: usize, piece: Arc<[u8]>, capacity: usize) {
+        self.remove_clean(piece_index);
+        self.clean_bytes += piece.len();
+        self.clean.push_back((piece_index, piece));
+        while self.clean_bytes > capacity {
+            let Some((_, piece)) = self.clean.pop_front() else {
+                break;
+            };
+            self.clean_bytes -= piece.len();
+        }
+    }
+}
+
+impl CachedStorage {
+    pub fn new(inner: Arc<dyn Storage>, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        self.state.lock().expect("cache lock is not poisoned")
+    }
+
+    /// Writes out the dirty blocks of a piece, joining adjacent blocks into a single write.
+    fn write_back(&self, state: &mut CacheState, piece_index: usize) -> io::Result<()> {
+        let Some(blocks) = state.take_dirty(piece_index) else {
+            return Ok(());
+        };
+        let mut run: Option<(usize, Vec<u8>)> = None;
+        for (begin, block) in blocks {
+            match &mut run {
+                Some((start, data)) if *start + data.len() == begin => data.extend(block),
+                _ => {
+                    if let Some((start, data)) = run.replace((begin, block)) {
+                        self.inner.write_block(piece_index, start, &data)?;
+                    }
+                }
+            }
+        }
+        match run {
+            Some((start, data)) => self.inner.write_block(piece_index, start, &data),
+            None => Ok(()),
+        }
+    }
+
+    fn is_in_bounds(&self, piece_index: usize, begin: usize, len: usize) -> bool {
+        let info = &self.torrent().info;
+        piece_index < info.piece_count() && begin + len <= info.piece_size(piece_index)
+    }
+}
+
+impl Storage for CachedStorage {
+    fn torrent(&self) -> &Torrent {
+        self.inner.torrent()
+    }
+
+    fn read_block(&self, piece_index: usize, begin: usize, buf: &mut [u8]) -> io::Result<()> {
+        if !self.is_in_bounds(piece_index, begin, buf.len()) {
+            // Let the inner storage report the error.
+            return self.inner.read_block(piece_index, begin, buf);
+        }
+        let mut state = self.state();
+        // Reading through to the inner storage needs it to be current.
+        self.write_back(&mut state, piece_index)?;
+        let piece = match state.get_clean(piece_index) {
+            Some(piece) => piece,
+            None => {
+                let piece_size = self.torrent().info.piece_size(piece_index);
+                if piece_size > self.capacity {
+                    return self.inner.read_block(piece_index, begin, buf);
+                }
+                let mut piece = vec![0; piece_size];
+                self.inner.read_block(piece_index, 0, &mut piece)?;
+                let piece: Arc<[u8]> = piece.into();
+                state.insert_clean(piece_index, piece.clone(), self.capacity);
+                piece
+            }
+        };
+        buf.copy_from_slice(&piece[begin..begin + buf.len()]);
+        Ok(())
+    }
+
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        if !self.is_in_bounds(piece_index, begin, data.len()) {
+            return self.inner.write_block(piece_index, begin, data);
+        }
+        let mut state = self.state();
+        state.remove_clean(piece_index);
+        let end = begin + data.len();
+        let overlaps = state.dirty.get(&piece_index).is_some_and(|blocks| {
+            blocks
+                .range(..end)
+                .next_back()
+                .is_some_and(|(&start, block)| {
+                    start + block.len() > begin && (start != begin || block.len() != data.len())
+                })
+        });
+        if overlaps {
+            // Rather than splice blocks together, write the old ones out first.
+            self.write_back(&mut state, piece_index)?;
+        }
+        if !state.dirty.contains_key(&piece_index) {
+            state.dirty_order.push_back(piece_index);
+        }
+        let replaced = state
+            .dirty
+            .entry(piece_index)
+            .or_default()
+            .insert(begin, data.to_vec());
+        state.dirty_bytes += data.len();
+        state.dirty_bytes -= replaced.map_or(0, |block| block.len());
+        while state.dirty_bytes > self.capacity {
+            let Some(&oldest) = state.dirty_order.front() else {
+                break;
+            };
+            self.write_back(&mut state, oldest)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        {
+            let mut state = self.state();
+            while let Some(&piece_index) = state.dirty_order.front() {
+                self.write_back(&mut state, piece_index)?;
+            }
+        }
+        self.inner.flush()
+    }
+
+    fn verify(&self, piece_index: usize) -> io::Result<bool> {
+        let piece_size = self.torrent().info.piece_size(piece_index);
+        let piece = {
+            let mut state = self.state();
+            match state.take_complete(piece_index, piece_size) {
+                Some(piece) => piece,
+                None => {
+                    self.write_back(&mut state, piece_index)?;
+                    drop(state);
+                    return self.inner.verify(piece_index);
+                }
+            }
+        };
+        // Hashed without holding the lock; nothing else writes a piece while it is checked.
+        if !self.torrent().verify_piece(piece_index, &piece) {
+            return Ok(false);
+        }
+        self.inner.write_block(piece_index, 0, &piece)?;
+        self.state()
+            .insert_clean(piece_index, piece.into(), self.capacity);
+        Ok(true)
+    }
+}
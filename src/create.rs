@@ -0,0 +1,462 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde_bytes::ByteBuf;
+
+use crate::merkle;
+use crate::torrent::{FileEntry, FileTree, Info, Torrent, V2File};
+use crate::utils::compute_hash;
+
+/// Smallest piece length picked automatically; also the size of a single request.
+const MIN_PIECE_LENGTH: usize = 1 << 14;
+const MAX_PIECE_LENGTH: usize = 1 << 24;
+/// Automatic piece lengths aim for about this many pieces.
+const TARGET_PIECE_COUNT: usize = 1500;
+/// Pieces read ahead per hashing thread before handing a batch out.
+const PIECES_PER_THREAD: usize = 4;
+
+/// Picks a power of two piece length that splits `total_length` into roughly
+/// [`TARGET_PIECE_COUNT`] pieces.
+pub fn default_piece_length(total_length: usize) -> usize {
+    (total_length / TARGET_PIECE_COUNT)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Builds a [`Torrent`] describing a file or directory on disk.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use bittorrent_starter_rust::create::TorrentBuilder;
+///
+/// let torrent = TorrentBuilder::new("dataset")
+///     .tracker("http://tracker.example:6969/announce")
+///     .comment("nightly export")
+///     .build()
+///     .await?;
+/// std::fs::write("dataset.torrent", serde_bencode::to_bytes(&torrent)?)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    trackers: Vec<String>,
+    piece_length: Option<usize>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    web_seeds: Vec<String>,
+    meta_version: MetaVersion,
+}
+
+/// Which versions of the metadata format a created torrent carries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MetaVersion {
+    /// SHA-1 pieces only, understood by every client.
+    #[default]
+    V1,
+    /// Per-file SHA-256 merkle trees only (BEP 52).
+    V2,
+    /// Both, so the torrent can be shared in v1 and v2 swarms alike.
+    Hybrid,
+}
+
+impl TorrentBuilder {
+    /// Starts describing `path`, stamped with the current time and this crate as its creator.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64);
+        Self {
+            path: path.into(),
+            trackers: Vec::new(),
+            piece_length: None,
+            comment: None,
+            created_by: Some(format!(
+                "{} {}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )),
+            creation_date: Some(now),
+            private: false,
+            web_seeds: Vec::new(),
+            meta_version: MetaVersion::V1,
+        }
+    }
+
+    /// Adds a tracker. The first one becomes `announce`; with more than one, each gets a tier of
+    /// its own in `announce-list`, in the order they were added.
+    pub fn tracker(mut self, url: impl Into<String>) -> Self {
+        self.trackers.push(url.into());
+        self
+    }
+
+    /// Overrides the automatically chosen piece length, which must be a power of two of at
+    /// least 16 KiB.
+    pub fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: Option<String>) -> Self {
+        self.created_by = created_by;
+        self
+    }
+
+    /// Sets the creation date in seconds since the Unix epoch, or leaves it out, which makes
+    /// the output reproducible.
+    pub fn creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    /// Marks the torrent private, limiting peer discovery to its trackers (BEP 27).
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Adds a URL serving the content over HTTP (BEP 19).
+    pub fn web_seed(mut self, url: impl Into<String>) -> Self {
+        self.web_seeds.push(url.into());
+        self
+    }
+
+    pub fn meta_version(mut self, meta_version: MetaVersion) -> Self {
+        self.meta_version = meta_version;
+        self
+    }
+
+    /// Reads and hashes the content on a blocking thread, spreading the hashing over all cores.
+    pub async fn build(self) -> anyhow::Result<Torrent> {
+        tokio::task::spawn_blocking(move || self.build_blocking())
+            .await
+            .context("torrent creation panicked")?
+    }
+
+    fn build_blocking(self) -> anyhow::Result<Torrent> {
+        let announce = self
+            .trackers
+            .first()
+            .cloned()
+            .context("a torrent needs at least one tracker")?;
+        let name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} has no usable name", self.path.display()))?
+            .to_string();
+
+        let metadata = std::fs::metadata(&self.path)
+            .with_context(|| format!("read {}", self.path.display()))?;
+        let is_dir = metadata.is_dir();
+        let files = if is_dir {
+            let mut sources = Vec::new();
+            collect_files(&self.path, &mut sources)?;
+            sources.sort();
+            sources
+                .into_iter()
+                .map(|source| source_file(&self.path, source))
+                .collect::<anyhow::Result<Vec<_>>>()?
+        } else {
+            vec![SourceFile {
+                source: self.path.clone(),
+                path: vec![name.clone()],
+                length: metadata.len() as usize,
+            }]
+        };
+        let total_length: usize = files.iter().map(|file| file.length).sum();
+        anyhow::ensure!(total_length > 0, "{} is empty", self.path.display());
+
+        let piece_length = match self.piece_length {
+            Some(piece_length) => {
+                anyhow::ensure!(
+                    piece_length.is_power_of_two() && piece_length >= MIN_PIECE_LENGTH,
+                    "piece length must be a power of two of at least {MIN_PIECE_LENGTH} bytes"
+                );
+                piece_length
+            }
+            None => default_piece_length(total_length),
+        };
+
+        let mut info = Info {
+            name,
+            piece_length,
+            private: self.private.then_some(1),
+            ..Default::default()
+        };
+        let mut piece_layers = None;
+        match self.meta_version {
+            MetaVersion::V1 => {
+                let sources: Vec<PathBuf> = files.iter().map(|file| file.source.clone()).collect();
+                let pieces = hash_pieces(&sources, piece_length, compute_hash)?;
+                anyhow::ensure!(
+                    pieces.len() == total_length.div_ceil(piece_length),
+                    "{} changed while it was being hashed",
+                    self.path.display()
+                );
+                info.pieces = ByteBuf::from(pieces.concat());
+                if is_dir {
+                    info.files = Some(files.into_iter().map(SourceFile::entry).collect());
+                } else {
+                    info.length = Some(total_length);
+                }
+            }
+            MetaVersion::V2 | MetaVersion::Hybrid => {
+                let hybrid = self.meta_version == MetaVersion::Hybrid;
+                let v2 = hash_v2(&files, piece_length, hybrid)?;
+                info.meta_version = Some(2);
+                info.file_tree = Some(v2.file_tree);
+                piece_layers = Some(v2.piece_layers);
+                if hybrid {
+                    info.pieces = ByteBuf::from(v2.v1_pieces);
+                    if is_dir {
+                        info.files = Some(v2.v1_files);
+                    } else {
+                        info.length = Some(total_length);
+                    }
+                }
+            }
+        }
+
+        let announce_list = (self.trackers.len() > 1).then(|| {
+            self.trackers
+                .iter()
+                .map(|tracker| vec![tracker.clone()])
+                .collect()
+        });
+        Ok(Torrent {
+            announce,
+            announce_list,
+            comment: self.comment,
+            created_by: self.created_by,
+            creation_date: self.creation_date,
+            url_list: (!self.web_seeds.is_empty()).then_some(self.web_seeds),
+            info,
+            piece_layers,
+            ..Default::default()
+        })
+    }
+}
+
+/// A file to include, as found on disk.
+struct SourceFile {
+    source: PathBuf,
+    path: Vec<String>,
+    length: usize,
+}
+
+impl SourceFile {
+    fn entry(self) -> FileEntry {
+        FileEntry {
+            length: self.length,
+            path: self.path,
+            attr: None,
+        }
+    }
+}
+
+struct V2Metadata {
+    file_tree: FileTree,
+    piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+    /// v1 file list with padding files keeping every file aligned to a piece, for hybrids.
+    v1_files: Vec<FileEntry>,
+    v1_pieces: Vec<u8>,
+}
+
+/// Hashes every file into a merkle tree of its own, and for hybrids also hashes the v1 pieces
+/// of the same data laid out with padding so that both versions share piece boundaries.
+fn hash_v2(files: &[SourceFile], piece_length: usize, hybrid: bool) -> anyhow::Result<V2Metadata> {
+    let mut v2_files = Vec::new();
+    let mut piece_layers = BTreeMap::new();
+    let mut v1_files = Vec::new();
+    let mut v1_pieces = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        let padding = match i + 1 == files.len() {
+            true => 0,
+            false => file.length.next_multiple_of(piece_length) - file.length,
+        };
+        let file_length = file.length;
+        let hashes = hash_pieces(std::slice::from_ref(&file.source), piece_length, |piece| {
+            let v1_hash = hybrid.then(|| {
+                // Padding follows the short last piece of a file, zeros as far as v1 is
+                // concerned.
+                let mut padded = piece.clone();
+                if padding > 0 {
+                    padded.resize(piece_length, 0);
+                }
+                compute_hash(&padded)
+            });
+            (
+                v1_hash,
+                merkle::piece_hash(piece, file_length, piece_length),
+            )
+        })?;
+        anyhow::ensure!(
+            hashes.len() == file.length.div_ceil(piece_length),
+            "{} changed while it was being hashed",
+            file.source.display()
+        );
+
+        let (v1_hashes, layer): (Vec<_>, Vec<_>) = hashes.into_iter().unzip();
+        v1_pieces.extend(v1_hashes.into_iter().flatten().flatten());
+        let pieces_root = match layer.len() {
+            0 => None,
+            1 => Some(layer[0]),
+            _ => {
+                let root = merkle::root_from_piece_layer(&layer, piece_length);
+                piece_layers.insert(ByteBuf::from(root.to_vec()), ByteBuf::from(layer.concat()));
+                Some(root)
+            }
+        };
+        v2_files.push((
+            file.path.clone(),
+            V2File {
+                length: file.length,
+                pieces_root: pieces_root.map(|root| ByteBuf::from(root.to_vec())),
+            },
+        ));
+        v1_files.push(FileEntry {
+            length: file.length,
+            path: file.path.clone(),
+            attr: None,
+        });
+        if padding > 0 {
+            v1_files.push(FileEntry {
+                length: padding,
+                path: vec![String::from(".pad"), padding.to_string()],
+                attr: Some(String::from("p")),
+            });
+        }
+    }
+    Ok(V2Metadata {
+        file_tree: FileTree::from_files(v2_files),
+        piece_layers,
+        v1_files,
+        v1_pieces,
+    })
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("list {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn source_file(root: &Path, source: PathBuf) -> anyhow::Result<SourceFile> {
+    let length = std::fs::metadata(&source)
+        .with_context(|| format!("read {}", source.display()))?
+        .len() as usize;
+    let path = source
+        .strip_prefix(root)
+        .expect("files are collected below the root")
+        .components()
+        .map(|component| {
+            component
+                .as_os_str()
+                .to_str()
+                .map(String::from)
+                .with_context(|| format!("{} is not valid UTF-8", source.display()))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(SourceFile {
+        source,
+        path,
+        length,
+    })
+}
+
+/// Reads `sources` back to back as one stream, cut into pieces, and returns the `hash` of each
+/// piece. Reading stays sequential while batches of pieces are hashed in parallel.
+fn hash_pieces<H: Send>(
+    sources: &[PathBuf],
+    piece_length: usize,
+    hash: impl Fn(&Vec<u8>) -> H + Sync,
+) -> anyhow::Result<Vec<H>> {
+    let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+    let mut reader = ChainedReader::new(sources);
+    let mut hashes = Vec::new();
+    loop {
+        let mut batch = Vec::with_capacity(threads * PIECES_PER_THREAD);
+        while batch.len() < threads * PIECES_PER_THREAD {
+            let piece = reader.read_piece(piece_length)?;
+            if piece.is_empty() {
+                break;
+            }
+            batch.push(piece);
+        }
+        if batch.is_empty() {
+            return Ok(hashes);
+        }
+
+        let per_thread = batch.len().div_ceil(threads);
+        let hash = &hash;
+        let batch_hashes: Vec<H> = std::thread::scope(|scope| {
+            let workers: Vec<_> = batch
+                .chunks(per_thread)
+                .map(|pieces| scope.spawn(move || pieces.iter().map(hash).collect::<Vec<_>>()))
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("hashing does not panic"))
+                .collect()
+        });
+        hashes.extend(batch_hashes);
+    }
+}
+
+/// Reads a list of files as if they were concatenated.
+struct ChainedReader<'a> {
+    sources: std::slice::Iter<'a, PathBuf>,
+    current: Option<File>,
+}
+
+impl<'a> ChainedReader<'a> {
+    fn new(sources: &'a [PathBuf]) -> Self {
+        Self {
+            sources: sources.iter(),
+            current: None,
+        }
+    }
+
+    /// Reads up to `piece_length` bytes, crossing into the next files as needed. Only the last
+    /// piece comes back short, and an empty one means everything was read.
+    fn read_piece(&mut self, piece_length: usize) -> anyhow::Result<Vec<u8>> {
+        let mut piece = vec![0; piece_length];
+        let mut filled = 0;
+        while filled < piece_length {
+            let file = match &mut self.current {
+                Some(file) => file,
+                None => match self.sources.next() {
+                    Some(source) => self.current.insert(
+                        File::open(source).with_context(|| format!("open {}", source.display()))?,
+                    ),
+                    None => break,
+                },
+            };
+            match file.read(&mut piece[filled..]).context("read content")? {
+                0 => self.current = None,
+                n => filled += n,
+            }
+        }
+        piece.truncate(filled);
+        Ok(piece)
+    }
+}
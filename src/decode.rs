@@ -5,7 +5,7 @@ fn _decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
         Some('i') => {
             // Example: "i52e" -> 52
             if let Some((n, remainder)) =
-                (&encoded_value[1..])
+                encoded_value[1..]
                     .split_once('e')
                     .and_then(|(digits, remainder)| {
                         // ok() converts Result<T, E> to Option<T>
@@ -69,5 +69,5 @@ pub fn decode_bencoded_value(encoded_value: &str) -> serde_json::Value {
         eprintln!("Extra remainder: {remainder}");
         panic!("Invalid encoded value: {encoded_value}")
     }
-    return val;
+    val
 }
@@ -0,0 +1,818 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+
+use crate::utils::compute_hash;
+
+/// Bucket size and number of closest nodes returned by lookups.
+pub const K: usize = 8;
+/// Number of queries a lookup keeps in flight.
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Nodes that have not been heard from in this long may be evicted from a full bucket.
+const NODE_STALE_AFTER: Duration = Duration::from_secs(15 * 60);
+/// Queries a node may fail in a row before it is dropped; until then it is only questionable.
+const MAX_FAILURES: u32 = 3;
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// How often announced peers older than `PEER_TTL` are swept out.
+const PEER_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const SEARCH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const SEARCH_RETRY: Duration = Duration::from_secs(30);
+const MAX_PACKET: usize = 1500;
+
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+pub type NodeId = [u8; 20];
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0u8; 20];
+    d.iter_mut()
+        .zip(a.iter().zip(b))
+        .for_each(|(d, (a, b))| *d = a ^ b);
+    d
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+fn encode_addr(addr: &SocketAddrV4, out: &mut Vec<u8>) {
+    out.extend(addr.ip().octets());
+    out.extend(addr.port().to_be_bytes());
+}
+
+fn decode_addr(bytes: &[u8]) -> SocketAddrV4 {
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    SocketAddrV4::new(ip, u16::from_be_bytes([bytes[4], bytes[5]]))
+}
+
+/// Compact node info: the 20-byte node id followed by the compact IPv4 address.
+fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(26 * nodes.len());
+    for node in nodes {
+        bytes.extend(node.id);
+        encode_addr(&node.addr, &mut bytes);
+    }
+    bytes
+}
+
+fn decode_nodes(bytes: &[u8]) -> Vec<NodeInfo> {
+    bytes
+        .chunks_exact(26)
+        .map(|chunk| NodeInfo {
+            id: chunk[..20].try_into().expect("chunk has 26 bytes"),
+            addr: decode_addr(&chunk[20..]),
+        })
+        .collect()
+}
+
+struct RoutingEntry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl RoutingEntry {
+    fn questionable(&self, now: Instant) -> bool {
+        self.failures > 0 || now.duration_since(self.last_seen) > NODE_STALE_AFTER
+    }
+}
+
+/// Kademlia routing table with one bucket per shared prefix length with our own id.
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<RoutingEntry>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let d = distance(&self.own_id, id);
+        let byte = d.iter().position(|&b| b != 0)?;
+        Some(byte * 8 + d[byte].leading_zeros() as usize)
+    }
+
+    /// Records that `node` is alive. Returns false if its bucket is full of nodes that are all
+    /// still good, in which case the node is dropped. Questionable nodes, those that failed to
+    /// answer or were not heard from in a while, make room for it.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let Some(index) = self.bucket_index(&node.id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+        let now = Instant::now();
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            entry.node.addr = node.addr;
+            entry.last_seen = now;
+            entry.failures = 0;
+            return true;
+        }
+        if bucket.len() >= K {
+            let Some(questionable) = bucket.iter().position(|entry| entry.questionable(now)) else {
+                return false;
+            };
+            bucket.remove(questionable);
+        }
+        bucket.push(RoutingEntry {
+            node,
+            last_seen: now,
+            failures: 0,
+        });
+        true
+    }
+
+    /// Records that the node failed to answer a query. It stays in the table as questionable
+    /// and is only removed after `MAX_FAILURES` failures in a row.
+    pub fn failed(&mut self, id: &NodeId) {
+        let Some(index) = self.bucket_index(id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == *id) {
+            entry.failures += 1;
+        }
+        bucket.retain(|entry| entry.failures < MAX_FAILURES);
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.bucket_index(id) {
+            self.buckets[index].retain(|entry| entry.node.id != *id);
+        }
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.nodes().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = NodeInfo> + '_ {
+        self.buckets.iter().flatten().map(|entry| entry.node)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// What gets written to the state file between runs.
+#[derive(Debug, Serialize, Deserialize)]
+struct DhtState {
+    id: ByteBuf,
+    nodes: ByteBuf,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueryArgs {
+    id: ByteBuf,
+    target: Option<ByteBuf>,
+    info_hash: Option<ByteBuf>,
+    port: Option<i64>,
+    token: Option<ByteBuf>,
+    implied_port: Option<i64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ResponseValues {
+    id: ByteBuf,
+    nodes: Option<ByteBuf>,
+    values: Option<Vec<ByteBuf>>,
+    token: Option<ByteBuf>,
+}
+
+impl ResponseValues {
+    fn decoded_nodes(&self) -> Vec<NodeInfo> {
+        self.nodes
+            .as_ref()
+            .map(|nodes| decode_nodes(nodes))
+            .unwrap_or_default()
+    }
+}
+
+/// A KRPC message. `y` is `q` for queries, `r` for responses and `e` for errors.
+#[derive(Debug, Serialize, Deserialize)]
+struct KrpcMessage {
+    t: ByteBuf,
+    y: String,
+    q: Option<String>,
+    a: Option<QueryArgs>,
+    r: Option<ResponseValues>,
+    e: Option<(i64, String)>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KrpcError {
+    #[error("node answered with error {0}: {1}")]
+    Remote(i64, String),
+    #[error("node did not answer in time")]
+    Timeout,
+}
+
+type PendingQuery = oneshot::Sender<Result<ResponseValues, KrpcError>>;
+
+pub struct DhtConfig {
+    pub bind_addr: SocketAddr,
+    pub bootstrap_nodes: Vec<String>,
+    /// Where the node id and routing table are persisted. Nothing is saved if unset.
+    pub state_path: Option<PathBuf>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            bootstrap_nodes: DEFAULT_BOOTSTRAP_NODES
+                .iter()
+                .map(|node| node.to_string())
+                .collect(),
+            state_path: None,
+        }
+    }
+}
+
+struct NodeState {
+    table: RoutingTable,
+    peers: HashMap<[u8; 20], HashMap<SocketAddrV4, Instant>>,
+    pending: HashMap<[u8; 2], PendingQuery>,
+    next_transaction: u16,
+    secret: [u8; 16],
+    previous_secret: [u8; 16],
+    secret_rotated_at: Instant,
+}
+
+impl NodeState {
+    fn token(secret: &[u8; 16], ip: &Ipv4Addr) -> [u8; 20] {
+        let mut bytes = secret.to_vec();
+        bytes.extend(ip.octets());
+        compute_hash(&bytes)
+    }
+
+    fn rotate_secret(&mut self) {
+        if self.secret_rotated_at.elapsed() > TOKEN_ROTATION {
+            self.previous_secret = self.secret;
+            self.secret = rand::random();
+            self.secret_rotated_at = Instant::now();
+        }
+    }
+
+    fn valid_token(&self, token: &[u8], ip: &Ipv4Addr) -> bool {
+        token == Self::token(&self.secret, ip) || token == Self::token(&self.previous_secret, ip)
+    }
+
+    fn expire_peers(&mut self) {
+        self.peers.retain(|_, peers| {
+            peers.retain(|_, seen| seen.elapsed() < PEER_TTL);
+            !peers.is_empty()
+        });
+    }
+}
+
+struct DhtInner {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    state: Mutex<NodeState>,
+    config: DhtConfig,
+    shutdown: CancellationToken,
+}
+
+impl Drop for DhtInner {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// A node of the mainline DHT (BEP 5). Cloning yields another handle to the same node; the node
+/// stops answering once every handle is dropped.
+#[derive(Clone)]
+pub struct Dht {
+    inner: Arc<DhtInner>,
+}
+
+/// Outcome of an iterative lookup.
+struct Lookup {
+    peers: HashSet<SocketAddrV4>,
+    // Closest nodes that answered, with the announce token they handed out.
+    closest: Vec<(NodeInfo, Option<ByteBuf>)>,
+}
+
+impl Dht {
+    /// Binds the node and starts answering queries. The previous node id and routing table are
+    /// restored from `config.state_path` if it exists.
+    pub async fn bind(config: DhtConfig) -> anyhow::Result<Self> {
+        let socket = match UdpSocket::bind(config.bind_addr).await {
+            Ok(socket) => socket,
+            Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => {
+                let mut fallback = config.bind_addr;
+                fallback.set_port(0);
+                UdpSocket::bind(fallback).await?
+            }
+            Err(err) => return Err(err).context("bind DHT socket"),
+        };
+
+        let saved = match &config.state_path {
+            Some(path) if path.exists() => {
+                let bytes = std::fs::read(path).context("read DHT state")?;
+                Some(serde_bencode::from_bytes::<DhtState>(&bytes).context("parse DHT state")?)
+            }
+            _ => None,
+        };
+        let id = saved
+            .as_ref()
+            .and_then(|state| state.id[..].try_into().ok())
+            .unwrap_or_else(rand::random);
+        let mut table = RoutingTable::new(id);
+        for node in saved.iter().flat_map(|state| decode_nodes(&state.nodes)) {
+            table.insert(node);
+        }
+
+        let socket = Arc::new(socket);
+        let inner = Arc::new(DhtInner {
+            id,
+            socket: socket.clone(),
+            state: Mutex::new(NodeState {
+                table,
+                peers: HashMap::new(),
+                pending: HashMap::new(),
+                next_transaction: rand::random(),
+                secret: rand::random(),
+                previous_secret: rand::random(),
+                secret_rotated_at: Instant::now(),
+            }),
+            config,
+            shutdown: CancellationToken::new(),
+        });
+        tokio::spawn(receive_loop(
+            Arc::downgrade(&inner),
+            socket,
+            inner.shutdown.clone(),
+        ));
+        Ok(Self { inner })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+
+    pub fn routing_table_len(&self) -> usize {
+        self.state().table.len()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, NodeState> {
+        self.inner
+            .state
+            .lock()
+            .expect("DHT state lock is not poisoned")
+    }
+
+    /// Writes the node id and routing table to the configured state file.
+    pub fn save_state(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.inner.config.state_path else {
+            return Ok(());
+        };
+        let nodes: Vec<NodeInfo> = self.state().table.nodes().collect();
+        let state = DhtState {
+            id: ByteBuf::from(self.inner.id.to_vec()),
+            nodes: ByteBuf::from(encode_nodes(&nodes)),
+        };
+        let bytes = serde_bencode::to_bytes(&state).context("serialize DHT state")?;
+        // Replace the old file in one go, so a crash never leaves half of one behind.
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        std::fs::write(&tmp_path, bytes)
+            .and_then(|()| std::fs::rename(&tmp_path, path))
+            .context("write DHT state")
+    }
+
+    async fn query(
+        &self,
+        addr: SocketAddr,
+        method: &str,
+        args: QueryArgs,
+    ) -> anyhow::Result<ResponseValues> {
+        let (tx, rx) = oneshot::channel();
+        let transaction = {
+            let mut state = self.state();
+            let transaction = state.next_transaction.to_be_bytes();
+            state.next_transaction = state.next_transaction.wrapping_add(1);
+            state.pending.insert(transaction, tx);
+            transaction
+        };
+        let msg = KrpcMessage {
+            t: ByteBuf::from(transaction.to_vec()),
+            y: String::from("q"),
+            q: Some(method.to_string()),
+            a: Some(QueryArgs {
+                id: ByteBuf::from(self.inner.id.to_vec()),
+                ..args
+            }),
+            r: None,
+            e: None,
+        };
+        let bytes = serde_bencode::to_bytes(&msg).context("serialize KRPC query")?;
+        self.inner.socket.send_to(&bytes, addr).await?;
+
+        let res = match tokio::time::timeout(QUERY_TIMEOUT, rx).await {
+            Ok(Ok(res)) => res,
+            _ => {
+                self.state().pending.remove(&transaction);
+                Err(KrpcError::Timeout)
+            }
+        };
+        let res = res?;
+        if let (Ok(id), SocketAddr::V4(addr)) = (res.id[..].try_into(), addr) {
+            self.state().table.insert(NodeInfo { id, addr });
+        }
+        Ok(res)
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> anyhow::Result<NodeId> {
+        let res = self.query(addr, "ping", QueryArgs::default()).await?;
+        res.id[..].try_into().context("node id is 20 bytes")
+    }
+
+    pub async fn find_node(
+        &self,
+        addr: SocketAddr,
+        target: &NodeId,
+    ) -> anyhow::Result<Vec<NodeInfo>> {
+        let args = QueryArgs {
+            target: Some(ByteBuf::from(target.to_vec())),
+            ..Default::default()
+        };
+        let res = self.query(addr, "find_node", args).await?;
+        Ok(res.decoded_nodes())
+    }
+
+    async fn get_peers_from(
+        &self,
+        addr: SocketAddr,
+        info_hash: &[u8; 20],
+    ) -> anyhow::Result<ResponseValues> {
+        let args = QueryArgs {
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            ..Default::default()
+        };
+        self.query(addr, "get_peers", args).await
+    }
+
+    pub async fn announce_peer(
+        &self,
+        addr: SocketAddr,
+        info_hash: &[u8; 20],
+        port: u16,
+        token: &[u8],
+    ) -> anyhow::Result<()> {
+        let args = QueryArgs {
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            port: Some(port as i64),
+            token: Some(ByteBuf::from(token.to_vec())),
+            ..Default::default()
+        };
+        self.query(addr, "announce_peer", args).await?;
+        Ok(())
+    }
+
+    /// Pings a node we heard about from elsewhere, e.g. a peer's `Port` message, so that it
+    /// makes it into the routing table if it answers.
+    pub fn add_contact(&self, addr: SocketAddr) {
+        let dht = self.clone();
+        tokio::spawn(async move {
+            let _ = dht.ping(addr).await;
+        });
+    }
+
+    /// Pings the bootstrap nodes and then looks up our own id to fill the routing table.
+    pub async fn bootstrap(&self) -> anyhow::Result<()> {
+        let mut addrs = Vec::new();
+        for node in &self.inner.config.bootstrap_nodes {
+            match tokio::net::lookup_host(node).await {
+                Ok(resolved) => addrs.extend(resolved.filter(SocketAddr::is_ipv4)),
+                Err(err) => eprintln!("Resolve DHT bootstrap node {node}: {err}"),
+            }
+        }
+        join_all(addrs.into_iter().map(|addr| self.ping(addr))).await;
+        self.lookup(&self.inner.id, false).await;
+        anyhow::ensure!(
+            !self.state().table.is_empty(),
+            "no DHT node answered during bootstrap"
+        );
+        Ok(())
+    }
+
+    /// Iterative Kademlia lookup towards `target`. With `get_peers`, nodes are asked for peers
+    /// of `target` as an info hash instead of just closer nodes.
+    async fn lookup(&self, target: &NodeId, get_peers: bool) -> Lookup {
+        let mut candidates = self.state().table.closest(target, K);
+        let mut queried: HashSet<NodeId> = HashSet::new();
+        let mut responded: Vec<(NodeInfo, Option<ByteBuf>)> = Vec::new();
+        let mut peers = HashSet::new();
+
+        loop {
+            candidates.sort_by_key(|node| distance(&node.id, target));
+            candidates.dedup_by_key(|node| node.id);
+            let batch: Vec<NodeInfo> = candidates
+                .iter()
+                .take(K)
+                .filter(|node| !queried.contains(&node.id))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            queried.extend(batch.iter().map(|node| node.id));
+
+            let results = join_all(batch.iter().map(|node| async move {
+                let addr = SocketAddr::V4(node.addr);
+                if get_peers {
+                    self.get_peers_from(addr, target).await
+                } else {
+                    let nodes = self.find_node(addr, target).await?;
+                    Ok(ResponseValues {
+                        nodes: Some(ByteBuf::from(encode_nodes(&nodes))),
+                        ..Default::default()
+                    })
+                }
+            }))
+            .await;
+
+            for (node, result) in batch.into_iter().zip(results) {
+                match result {
+                    Ok(res) => {
+                        let found = res.decoded_nodes();
+                        candidates.extend(found.into_iter().filter(|n| n.id != self.inner.id));
+                        peers.extend(
+                            res.values
+                                .iter()
+                                .flatten()
+                                .filter(|value| value.len() == 6)
+                                .map(|value| decode_addr(value)),
+                        );
+                        responded.push((node, res.token));
+                    }
+                    Err(_) => {
+                        candidates.retain(|candidate| candidate.id != node.id);
+                        self.state().table.failed(&node.id);
+                    }
+                }
+            }
+        }
+
+        responded.sort_by_key(|(node, _)| distance(&node.id, target));
+        responded.truncate(K);
+        Lookup {
+            peers,
+            closest: responded,
+        }
+    }
+
+    /// Finds peers for `info_hash`.
+    pub async fn get_peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash, true).await;
+        lookup.peers.into_iter().map(SocketAddr::V4).collect()
+    }
+
+    /// Finds peers for `info_hash` and tells the closest nodes that we accept connections on
+    /// `port`.
+    pub async fn announce(&self, info_hash: &[u8; 20], port: u16) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash, true).await;
+        join_all(lookup.closest.iter().filter_map(|(node, token)| {
+            let token = token.as_ref()?;
+            Some(self.announce_peer(SocketAddr::V4(node.addr), info_hash, port, token))
+        }))
+        .await;
+        lookup.peers.into_iter().map(SocketAddr::V4).collect()
+    }
+
+    /// Periodically searches the DHT for peers of `info_hash` and forwards them to the download
+    /// engine, announcing ourselves if `port` is set, until `shutdown` fires.
+    pub async fn search_peers(
+        self,
+        info_hash: [u8; 20],
+        port: Option<u16>,
+        peers_tx: mpsc::Sender<SocketAddr>,
+        shutdown: CancellationToken,
+    ) {
+        loop {
+            let search = async {
+                if self.routing_table_len() < K {
+                    if let Err(err) = self.bootstrap().await {
+                        eprintln!("DHT bootstrap failed: {err:#}");
+                        return Vec::new();
+                    }
+                }
+                match port {
+                    Some(port) => self.announce(&info_hash, port).await,
+                    None => self.get_peers(&info_hash).await,
+                }
+            };
+            let peers = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                peers = search => peers,
+            };
+            for addr in &peers {
+                // The engine going away just means nobody needs peers anymore.
+                let _ = peers_tx.send(*addr).await;
+            }
+            if let Err(err) = self.save_state() {
+                eprintln!("Saving DHT state failed: {err:#}");
+            }
+            let wait = if peers.is_empty() {
+                SEARCH_RETRY
+            } else {
+                SEARCH_INTERVAL
+            };
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+        if let Err(err) = self.save_state() {
+            eprintln!("Saving DHT state failed: {err:#}");
+        }
+    }
+}
+
+async fn receive_loop(inner: Weak<DhtInner>, socket: Arc<UdpSocket>, shutdown: CancellationToken) {
+    let mut buf = vec![0u8; MAX_PACKET];
+    let mut expiry = tokio::time::interval(PEER_EXPIRY_INTERVAL);
+    loop {
+        let received = tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = expiry.tick() => {
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                Dht { inner }.state().expire_peers();
+                continue;
+            }
+            received = socket.recv_from(&mut buf) => received,
+        };
+        let Ok((n, SocketAddr::V4(from))) = received else {
+            continue;
+        };
+        // Only hold on to the node while handling a packet, so that dropping the last handle
+        // shuts it down.
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let dht = Dht { inner };
+        let Ok(msg) = serde_bencode::from_bytes::<KrpcMessage>(&buf[..n]) else {
+            continue;
+        };
+        match msg.y.as_str() {
+            "q" => {
+                let reply = dht.handle_query(msg, from);
+                if let Ok(bytes) = serde_bencode::to_bytes(&reply) {
+                    let _ = dht.inner.socket.send_to(&bytes, from).await;
+                }
+            }
+            "r" | "e" => {
+                let Ok(transaction) = <[u8; 2]>::try_from(&msg.t[..]) else {
+                    continue;
+                };
+                let Some(pending) = dht.state().pending.remove(&transaction) else {
+                    continue;
+                };
+                let result = match (msg.r, msg.e) {
+                    (Some(res), _) => Ok(res),
+                    (None, Some((code, message))) => Err(KrpcError::Remote(code, message)),
+                    (None, None) => continue,
+                };
+                let _ = pending.send(result);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dht {
+    fn handle_query(&self, msg: KrpcMessage, from: SocketAddrV4) -> KrpcMessage {
+        let reply = |r: Option<ResponseValues>, e: Option<(i64, String)>| KrpcMessage {
+            t: msg.t.clone(),
+            y: String::from(if e.is_some() { "e" } else { "r" }),
+            q: None,
+            a: None,
+            r,
+            e,
+        };
+        let error = |code: i64, message: &str| reply(None, Some((code, message.to_string())));
+
+        let Some(args) = msg.a.as_ref() else {
+            return error(203, "Protocol Error");
+        };
+        let Ok(sender_id) = <NodeId>::try_from(&args.id[..]) else {
+            return error(203, "Protocol Error");
+        };
+        let own_id = ByteBuf::from(self.inner.id.to_vec());
+
+        let mut state = self.state();
+        state.table.insert(NodeInfo {
+            id: sender_id,
+            addr: from,
+        });
+        state.rotate_secret();
+
+        let values = match msg.q.as_deref() {
+            Some("ping") => ResponseValues {
+                id: own_id,
+                ..Default::default()
+            },
+            Some("find_node") => {
+                let Some(target) = args.target.as_ref().and_then(|t| t[..].try_into().ok()) else {
+                    return error(203, "Protocol Error");
+                };
+                ResponseValues {
+                    id: own_id,
+                    nodes: Some(ByteBuf::from(encode_nodes(
+                        &state.table.closest(&target, K),
+                    ))),
+                    ..Default::default()
+                }
+            }
+            Some("get_peers") => {
+                let Some(info_hash) = args.info_hash.as_ref().and_then(|h| h[..].try_into().ok())
+                else {
+                    return error(203, "Protocol Error");
+                };
+                let token = NodeState::token(&state.secret, from.ip());
+                let peers: Vec<ByteBuf> = state
+                    .peers
+                    .get(&info_hash)
+                    .into_iter()
+                    .flatten()
+                    .filter(|(_, seen)| seen.elapsed() < PEER_TTL)
+                    .map(|(addr, _)| {
+                        let mut bytes = Vec::with_capacity(6);
+                        encode_addr(addr, &mut bytes);
+                        ByteBuf::from(bytes)
+                    })
+                    .collect();
+                ResponseValues {
+                    id: own_id,
+                    nodes: peers
+                        .is_empty()
+                        .then(|| ByteBuf::from(encode_nodes(&state.table.closest(&info_hash, K)))),
+                    values: (!peers.is_empty()).then_some(peers),
+                    token: Some(ByteBuf::from(token.to_vec())),
+                }
+            }
+            Some("announce_peer") => {
+                let (Some(info_hash), Some(token)) = (
+                    args.info_hash.as_ref().and_then(|h| h[..].try_into().ok()),
+                    args.token.as_ref(),
+                ) else {
+                    return error(203, "Protocol Error");
+                };
+                if !state.valid_token(token, from.ip()) {
+                    return error(203, "Bad Token");
+                }
+                let port = match (args.implied_port, args.port.map(u16::try_from)) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(Ok(port))) => port,
+                    _ => return error(203, "Protocol Error"),
+                };
+                let peers = state.peers.entry(info_hash).or_default();
+                peers.insert(SocketAddrV4::new(*from.ip(), port), Instant::now());
+                ResponseValues {
+                    id: own_id,
+                    ..Default::default()
+                }
+            }
+            _ => return error(204, "Method Unknown"),
+        };
+        reply(Some(values), None)
+    }
+}
@@ -1,22 +1,206 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
 use anyhow::Context;
 use futures_util::{SinkExt, StreamExt};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
 use tokio_util::codec::Framed;
 
+use crate::dht::Dht;
+use crate::extension::{self, extended_message, parse_extended, ExtensionHandshake};
+use crate::handshake::{perform_handshake, Extensions, Handshake};
+use crate::hash_pool::HashPool;
 use crate::message::{
     Message, MessageFramer, MessageTag, PieceMessagePayload, RequestMessagePayload,
 };
+use crate::mse::EncryptionPolicy;
+use crate::pex::{PexMessage, PexState, MAX_PEX_PEERS};
+use crate::rate_limit::RateLimiter;
+use crate::resume::ResumeState;
+use crate::selection::{FilePriority, FileSelection};
+use crate::storage::Storage;
+use crate::stream::StreamControl;
 use crate::torrent::Torrent;
-use crate::utils::compute_hash;
+use crate::transport::{self, PeerStream};
+use crate::utp::UtpSocket;
+use crate::webseed::WebSeed;
 
-async fn init_download(framed: &mut Framed<&mut TcpStream, MessageFramer>) -> anyhow::Result<()> {
-    let bitfield = framed
-        .next()
-        .await
-        .expect("peer always sends a bitfields")
-        .context("peer message was invalid")?;
-    assert_eq!(bitfield.tag, MessageTag::Bitfield);
+const MAX_PEERS: usize = 30;
+/// Addresses kept to connect to once a slot frees up, while `MAX_PEERS` are connected.
+const MAX_WAITING_PEERS: usize = 200;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Failed requests in a row after which a web seed is abandoned.
+const WEB_SEED_ATTEMPTS: u32 = 3;
+const WEB_SEED_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How long a download waits for new peers once it has nobody left to fetch from.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Pause after a peer rejects a request, doubling with every further rejection in a row.
+const REJECT_BACKOFF: Duration = Duration::from_millis(100);
+/// Rejections in a row after which a peer is given up on.
+const MAX_REJECTIONS: u32 = 5;
+
+/// Transfer counters reported to trackers.
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    pub uploaded: AtomicUsize,
+    pub downloaded: AtomicUsize,
+    pub left: AtomicUsize,
+}
+
+impl TransferStats {
+    pub fn new(left: usize) -> Self {
+        Self {
+            left: AtomicUsize::new(left),
+            ..Default::default()
+        }
+    }
+}
+
+/// What we learned about a peer from messages outside of the request/response flow.
+#[derive(Debug, Default)]
+struct PeerState {
+    dht_port: Option<u16>,
+    extensions: Option<ExtensionHandshake>,
+    pex: PexState,
+    pex_added: Vec<SocketAddr>,
+    /// Both sides advertised the Fast extension (BEP 6).
+    fast: bool,
+    unchoked: bool,
+    /// Pieces the peer lets us request even while choking us.
+    allowed_fast: HashSet<usize>,
+    /// Requests the peer rejected since it last sent us a block.
+    rejections: u32,
+}
+
+/// Blocks written for pieces that are not verified yet, so that a piece handed back half done
+/// is finished by whoever takes it next instead of being fetched all over again.
+#[derive(Debug, Default)]
+struct PartialPieces(Mutex<HashMap<usize, HashSet<usize>>>);
+
+impl PartialPieces {
+    fn blocks(&self) -> std::sync::MutexGuard<'_, HashMap<usize, HashSet<usize>>> {
+        self.0.lock().expect("partial pieces lock is not poisoned")
+    }
+
+    fn has_block(&self, piece_index: usize, offset: usize) -> bool {
+        self.blocks()
+            .get(&piece_index)
+            .is_some_and(|offsets| offsets.contains(&offset))
+    }
+
+    fn block_written(&self, piece_index: usize, offset: usize) {
+        self.blocks().entry(piece_index).or_default().insert(offset);
+    }
+
+    /// Forgets the blocks of a piece that was verified, or has to be fetched again.
+    fn clear(&self, piece_index: usize) {
+        self.blocks().remove(&piece_index);
+    }
+}
+
+fn piece_index_payload(msg: &Message) -> anyhow::Result<usize> {
+    let index: [u8; 4] = msg.payload[..]
+        .try_into()
+        .with_context(|| format!("{:?} message must carry 4 bytes", msg.tag))?;
+    Ok(u32::from_be_bytes(index) as usize)
+}
+
+/// Reads the next message that matters to the download, recording the ones that only update
+/// what we know about the peer. Choke, unchoke and allowed fast messages are recorded and still
+/// returned, since they change what may be requested.
+async fn recv_message<S: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<S, MessageFramer>,
+    peer: &mut PeerState,
+) -> anyhow::Result<Message> {
+    loop {
+        let msg = framed
+            .next()
+            .await
+            .context("peer closed the connection")?
+            .context("peer message was invalid")?;
+        match msg.tag {
+            MessageTag::Port => {
+                let port: [u8; 2] = msg.payload[..]
+                    .try_into()
+                    .context("port message must carry 2 bytes")?;
+                peer.dht_port = Some(u16::from_be_bytes(port));
+            }
+            MessageTag::Extended => {
+                let (id, payload) = parse_extended(&msg)?;
+                match id {
+                    extension::HANDSHAKE_ID => {
+                        let handshake = serde_bencode::from_bytes(payload)
+                            .context("parse extension handshake")?;
+                        peer.extensions = Some(handshake);
+                    }
+                    extension::UT_PEX_ID if peer.pex.accept_incoming() => {
+                        let pex = serde_bencode::from_bytes::<PexMessage>(payload)
+                            .context("parse ut_pex message")?;
+                        let dropped = pex.dropped_peers();
+                        peer.pex_added.retain(|addr| !dropped.contains(addr));
+                        let added = pex.added_peers();
+                        peer.pex_added.extend(added.into_iter().take(MAX_PEX_PEERS));
+                    }
+                    // Extensions we never advertised, and peer lists sent too soon.
+                    _ => {}
+                }
+            }
+            // We fetch one block at a time, so neither availability updates nor suggestions
+            // change what we ask for next.
+            MessageTag::Have | MessageTag::Suggest => {}
+            MessageTag::Choke => {
+                peer.unchoked = false;
+                return Ok(msg);
+            }
+            MessageTag::Unchoke => {
+                peer.unchoked = true;
+                return Ok(msg);
+            }
+            MessageTag::AllowedFast => {
+                peer.allowed_fast.insert(piece_index_payload(&msg)?);
+                return Ok(msg);
+            }
+            _ => return Ok(msg),
+        }
+    }
+}
+
+async fn init_download<S: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<S, MessageFramer>,
+    peer: &mut PeerState,
+) -> anyhow::Result<()> {
+    if peer.fast {
+        // With Fast, announcing what we have is mandatory; we have nothing to offer.
+        framed
+            .send(Message {
+                tag: MessageTag::HaveNone,
+                payload: Vec::new(),
+            })
+            .await
+            .context("send have none message")?;
+    }
+
+    // A peer with the Fast extension may also send allowed fast pieces before its bitfield.
+    let bitfield = loop {
+        let msg = recv_message(framed, peer)
+            .await
+            .context("wait for bitfield")?;
+        if msg.tag != MessageTag::AllowedFast {
+            break msg;
+        }
+    };
+    match bitfield.tag {
+        MessageTag::Bitfield => {}
+        MessageTag::HaveAll | MessageTag::HaveNone if peer.fast => {}
+        tag => anyhow::bail!("expected a bitfield, got {tag:?}"),
+    }
     // NOTE: we assume that the bitfield covers all pieces
 
     framed
@@ -27,99 +211,837 @@ async fn init_download(framed: &mut Framed<&mut TcpStream, MessageFramer>) -> an
         .await
         .context("send interested message")?;
 
-    let unchoke = framed
-        .next()
-        .await
-        .expect("peer always sends an unchoke")
-        .context("peer message was invalid")?;
-    assert_eq!(unchoke.tag, MessageTag::Unchoke);
-    assert!(unchoke.payload.is_empty());
+    Ok(())
+}
 
+async fn wait_for_unchoke<S: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<S, MessageFramer>,
+    peer: &mut PeerState,
+) -> anyhow::Result<()> {
+    while !peer.unchoked {
+        let msg = recv_message(framed, peer)
+            .await
+            .context("wait for unchoke")?;
+        match msg.tag {
+            MessageTag::Choke | MessageTag::Unchoke | MessageTag::AllowedFast => {}
+            tag => anyhow::bail!("expected an unchoke, got {tag:?}"),
+        }
+    }
     Ok(())
 }
 
-async fn _download_piece(
+/// Downloads a single piece into `storage` block by block, leaving it to the caller to verify.
+/// Blocks in `partial` were written by an earlier attempt and are skipped. With swarm `options`,
+/// so are the blocks their resume state holds from an earlier run, the ones written are recorded
+/// in it, and requests wait their turn under the download limit.
+/// Returns `false` if the peer turned the request down, either explicitly or by choking us, so
+/// that the rest of the piece can be fetched elsewhere.
+async fn _download_piece<S: AsyncRead + AsyncWrite + Unpin>(
     torrent: &Torrent,
-    framed: &mut Framed<&mut TcpStream, MessageFramer>,
+    framed: &mut Framed<S, MessageFramer>,
+    peer: &mut PeerState,
     piece_index: usize,
-) -> anyhow::Result<Vec<u8>> {
-    let piece_size = if piece_index == torrent.info.pieces.chunks_exact(20).len() - 1 {
-        let size = torrent.info.length % torrent.info.piece_length;
-        if size == 0 {
-            torrent.info.piece_length
-        } else {
-            size
-        }
-    } else {
-        torrent.info.piece_length
-    };
+    storage: &Arc<dyn Storage>,
+    partial: &PartialPieces,
+    options: Option<&SwarmOptions>,
+) -> anyhow::Result<bool> {
+    let piece_size = torrent.info.piece_size(piece_index);
+    let resume = options.and_then(|options| options.resume.as_ref());
+    let download_limit = options.and_then(|options| options.download_limit.as_ref());
 
-    let mut piece_bytes: Vec<u8> = Vec::with_capacity(piece_size);
     for (block_index, offset) in (0..piece_size).step_by(1 << 14).enumerate() {
-        let block_size = std::cmp::min(&piece_size - offset, 1 << 14);
+        if partial.has_block(piece_index, offset)
+            || resume.is_some_and(|resume| resume.has_block(piece_index, offset))
+        {
+            continue;
+        }
+        if !peer.unchoked && !peer.allowed_fast.contains(&piece_index) {
+            return Ok(false);
+        }
+        let block_size = std::cmp::min(piece_size - offset, 1 << 14);
+        if let Some(download_limit) = download_limit {
+            download_limit.acquire(block_size).await;
+        }
         let mut req =
             RequestMessagePayload::new(piece_index as u32, offset as u32, block_size as u32);
         let request_bytes = Vec::from(req.as_bytes_mut());
         framed
             .send(Message {
                 tag: MessageTag::Request,
-                payload: request_bytes,
+                payload: request_bytes.clone(),
             })
             .await
             .with_context(|| format!("send request for block {block_index}"))?;
 
-        let piece = framed
-            .next()
-            .await
-            .expect("peer always sends a piece")
-            .context("peer message was invalid")?;
-        assert_eq!(piece.tag, MessageTag::Piece);
-        assert!(!piece.payload.is_empty());
+        let piece = loop {
+            let msg = recv_message(framed, peer)
+                .await
+                .with_context(|| format!("wait for block {block_index}"))?;
+            match msg.tag {
+                MessageTag::Piece => break msg,
+                // A rejection echoes the request it refers to.
+                MessageTag::RejectRequest if msg.payload == request_bytes => {
+                    peer.rejections += 1;
+                    return Ok(false);
+                }
+                // Without Fast, choking silently drops every pending request. With it, the peer
+                // answers them with a piece or a rejection either way.
+                MessageTag::Choke if !peer.fast => return Ok(false),
+                MessageTag::Choke
+                | MessageTag::Unchoke
+                | MessageTag::AllowedFast
+                | MessageTag::RejectRequest => {}
+                tag => anyhow::bail!("expected a piece, got {tag:?}"),
+            }
+        };
 
-        let piece = PieceMessagePayload::ref_from_bytes(&piece.payload[..])
-            .expect("always get all Piece response fields from peer");
-        assert_eq!(piece.index() as usize, piece_index);
-        assert_eq!(piece.begin() as usize, offset);
-        assert_eq!(piece.block().len(), block_size);
-        piece_bytes.extend(piece.block());
+        let payload = PieceMessagePayload::ref_from_bytes(&piece.payload[..])
+            .context("piece message is truncated")?;
+        anyhow::ensure!(
+            payload.index() as usize == piece_index
+                && payload.begin() as usize == offset
+                && payload.block().len() == block_size,
+            "peer sent a block we did not ask for"
+        );
+        peer.rejections = 0;
+        // The block is written straight out of the message, past the index and offset.
+        let block = piece.payload;
+        let block_start = block.len() - block_size;
+        with_storage(storage, move |storage| {
+            storage.write_block(piece_index, offset, &block[block_start..])
+        })
+        .await
+        .with_context(|| format!("write block {block_index} of piece {piece_index}"))?;
+        partial.block_written(piece_index, offset);
+        if let Some(resume) = resume {
+            resume.block_written(piece_index, offset);
+        }
     }
 
-    assert_eq!(piece_bytes.len(), piece_size);
+    Ok(true)
+}
+
+/// Fetches a piece from the only peer we have, waiting out any choking.
+async fn download_piece_from<S: AsyncRead + AsyncWrite + Unpin>(
+    torrent: &Torrent,
+    framed: &mut Framed<S, MessageFramer>,
+    peer: &mut PeerState,
+    piece_index: usize,
+    storage: &Arc<dyn Storage>,
+) -> anyhow::Result<()> {
+    let partial = PartialPieces::default();
+    loop {
+        wait_for_unchoke(framed, peer).await?;
+        if _download_piece(torrent, framed, peer, piece_index, storage, &partial, None).await? {
+            anyhow::ensure!(
+                verify_piece(storage, piece_index).await?,
+                "piece {piece_index} failed hash check"
+            );
+            return Ok(());
+        }
+        // Without Fast a rejection can only come from being choked, which we wait out. With it,
+        // a peer that keeps unchoking us and still turns the piece down does not have it.
+        anyhow::ensure!(!peer.fast, "peer refused piece {piece_index}");
+    }
+}
 
-    let hash = compute_hash(&piece_bytes);
-    let piece_hashes = torrent.info.piece_hashes();
-    let piece_hash = piece_hashes
-        .iter()
-        .nth(piece_index)
-        .context("Piece index is valid")?;
+/// Runs `f` on a blocking thread, since storage may have to wait for the disk.
+async fn with_storage<T: Send + 'static>(
+    storage: &Arc<dyn Storage>,
+    f: impl FnOnce(&dyn Storage) -> io::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    let storage = storage.clone();
+    let result = tokio::task::spawn_blocking(move || f(&*storage))
+        .await
+        .context("storage task panicked")?;
+    Ok(result?)
+}
 
-    assert_eq!(&hash, piece_hash);
+async fn verify_piece(storage: &Arc<dyn Storage>, piece_index: usize) -> anyhow::Result<bool> {
+    with_storage(storage, move |storage| storage.verify(piece_index))
+        .await
+        .with_context(|| format!("read piece {piece_index} back"))
+}
 
-    Ok(piece_bytes)
+async fn flush_storage(storage: &Arc<dyn Storage>) -> anyhow::Result<()> {
+    with_storage(storage, |storage| storage.flush())
+        .await
+        .context("flush storage")
 }
 
-pub async fn download_piece(
+pub async fn download_piece<S: AsyncRead + AsyncWrite + Unpin>(
     torrent: &Torrent,
-    framed: &mut Framed<&mut TcpStream, MessageFramer>,
+    framed: &mut Framed<S, MessageFramer>,
     piece_index: usize,
-    file: &mut tokio::fs::File,
+    storage: &Arc<dyn Storage>,
 ) -> anyhow::Result<()> {
-    init_download(framed).await?;
-    let bytes = _download_piece(torrent, framed, piece_index).await?;
-    file.write_all(&bytes).await?;
-    Ok(())
+    let mut peer = PeerState::default();
+    init_download(framed, &mut peer).await?;
+    download_piece_from(torrent, framed, &mut peer, piece_index, storage).await?;
+    flush_storage(storage).await
 }
 
-pub async fn download_file(
+pub async fn download_file<S: AsyncRead + AsyncWrite + Unpin>(
     torrent: &Torrent,
-    framed: &mut Framed<&mut TcpStream, MessageFramer>,
-    file: &mut tokio::fs::File,
+    framed: &mut Framed<S, MessageFramer>,
+    storage: &Arc<dyn Storage>,
 ) -> anyhow::Result<()> {
-    init_download(framed).await?;
-    for piece_index in 0..torrent.info.length.div_ceil(torrent.info.piece_length) {
-        let piece_bytes = _download_piece(&torrent, framed, piece_index).await?;
-        // file_bytes.extend(piece_bytes);
-        file.write_all(&piece_bytes).await?;
+    let mut peer = PeerState::default();
+    init_download(framed, &mut peer).await?;
+    for piece_index in 0..torrent.info.piece_count() {
+        download_piece_from(torrent, framed, &mut peer, piece_index, storage).await?;
+    }
+    flush_storage(storage).await
+}
+
+/// Pieces still to be fetched, shared by every peer connection of a download.
+struct PieceQueue {
+    state: Mutex<PieceQueueState>,
+    notify: Notify,
+    // Readers whose pieces go before any other.
+    streaming: Option<StreamControl>,
+}
+
+struct PieceQueueState {
+    // Highest priority first, then in order.
+    pending: BTreeSet<(Reverse<FilePriority>, usize)>,
+    priorities: Vec<FilePriority>,
+    outstanding: usize, // pending pieces plus pieces currently being downloaded
+}
+
+impl PieceQueue {
+    /// Queues every piece not to be skipped.
+    fn new(priorities: Vec<FilePriority>, streaming: Option<StreamControl>) -> Self {
+        let pending: BTreeSet<_> = priorities
+            .iter()
+            .enumerate()
+            .filter(|(_, &priority)| priority != FilePriority::Skip)
+            .map(|(piece_index, &priority)| (Reverse(priority), piece_index))
+            .collect();
+        Self {
+            state: Mutex::new(PieceQueueState {
+                outstanding: pending.len(),
+                pending,
+                priorities,
+            }),
+            notify: Notify::new(),
+            streaming,
+        }
+    }
+
+    /// Waits for a piece to download, or returns `None` once every piece is done.
+    async fn next(&self) -> Option<usize> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().expect("piece queue lock is not poisoned");
+                let wanted = self.streaming.iter().flat_map(StreamControl::wanted_pieces);
+                for piece_index in wanted {
+                    let Some(&priority) = state.priorities.get(piece_index) else {
+                        continue;
+                    };
+                    if state.pending.remove(&(Reverse(priority), piece_index)) {
+                        return Some(piece_index);
+                    }
+                }
+                if let Some((_, piece_index)) = state.pending.pop_first() {
+                    return Some(piece_index);
+                }
+                if state.outstanding == 0 {
+                    return None;
+                }
+            }
+            // Another connection may still fail and hand its piece back.
+            notified.await;
+        }
+    }
+
+    /// Claims a specific piece if nobody else is downloading or has downloaded it.
+    fn take(&self, piece_index: usize) -> bool {
+        let mut state = self.state.lock().expect("piece queue lock is not poisoned");
+        let Some(&priority) = state.priorities.get(piece_index) else {
+            return false;
+        };
+        state.pending.remove(&(Reverse(priority), piece_index))
+    }
+
+    fn requeue(&self, piece_index: usize) {
+        let mut state = self.state.lock().expect("piece queue lock is not poisoned");
+        let priority = state.priorities[piece_index];
+        state.pending.insert((Reverse(priority), piece_index));
+        self.notify.notify_waiters();
+    }
+
+    fn outstanding(&self) -> usize {
+        let state = self.state.lock().expect("piece queue lock is not poisoned");
+        state.outstanding
+    }
+
+    fn complete(&self) {
+        let mut state = self.state.lock().expect("piece queue lock is not poisoned");
+        state.outstanding -= 1;
+        if state.outstanding == 0 {
+            self.notify.notify_waiters();
+        }
+    }
+}
+
+/// State shared by every connection of a download.
+struct Swarm {
+    torrent: Arc<Torrent>,
+    options: SwarmOptions,
+    queue: PieceQueue,
+    storage: Arc<dyn Storage>,
+    partial: PartialPieces,
+    pieces_tx: mpsc::Sender<Fetched>,
+    // Peers learned from other peers rather than from the caller.
+    discovered_tx: mpsc::Sender<SocketAddr>,
+    // Peers we completed a handshake with, as advertised over PEX.
+    live_peers: Mutex<HashSet<SocketAddr>>,
+    // Peers that sent a piece failing its hash check, to be disconnected.
+    bad_peers: Mutex<HashSet<SocketAddr>>,
+}
+
+/// A piece a worker wrote to storage.
+enum Fetched {
+    /// Checked already, and ready to be counted as done.
+    Verified(usize),
+    /// Still to be checked, having come from `peer`.
+    Unverified {
+        piece_index: usize,
+        peer: SocketAddr,
+    },
+}
+
+impl Swarm {
+    fn live_peers(&self) -> std::sync::MutexGuard<'_, HashSet<SocketAddr>> {
+        self.live_peers
+            .lock()
+            .expect("live peers lock is not poisoned")
+    }
+
+    fn bad_peers(&self) -> std::sync::MutexGuard<'_, HashSet<SocketAddr>> {
+        self.bad_peers
+            .lock()
+            .expect("bad peers lock is not poisoned")
+    }
+
+    /// Counts a verified piece as done, telling whoever follows the download about it.
+    fn piece_done(&self, piece_index: usize) {
+        self.partial.clear(piece_index);
+        self.queue.complete();
+        if let Some(streaming) = &self.options.streaming {
+            streaming.piece_verified(piece_index);
+        }
+        if let Some(resume) = &self.options.resume {
+            resume.piece_verified(piece_index);
+        }
+    }
+}
+
+/// Connects to a peer we heard of, waiting for a free slot under the connection limit first.
+async fn peer_worker(swarm: Arc<Swarm>, addr: SocketAddr) -> anyhow::Result<()> {
+    let permit = match &swarm.options.connections {
+        Some(connections) => Some(
+            connections
+                .clone()
+                .acquire_owned()
+                .await
+                .context("connection limit was closed")?,
+        ),
+        None => None,
+    };
+    let torrent = &swarm.torrent;
+    let options = &swarm.options;
+    let mut stream = transport::connect(
+        addr,
+        &torrent.info_hash(),
+        options.encryption,
+        options.utp.as_ref(),
+        CONNECT_TIMEOUT,
+    )
+    .await?;
+    let handshake = perform_handshake(torrent, &mut stream, options.extensions()).await?;
+    run_peer(&swarm, addr, stream, handshake, permit).await
+}
+
+/// Takes over a connection a peer opened to us, unless the connection limit is reached.
+async fn incoming_worker(swarm: Arc<Swarm>, incoming: IncomingPeer) -> anyhow::Result<()> {
+    let permit = match &swarm.options.connections {
+        Some(connections) => Some(
+            connections
+                .clone()
+                .try_acquire_owned()
+                .context("too many connections to take another")?,
+        ),
+        None => None,
+    };
+    let IncomingPeer {
+        addr,
+        stream,
+        handshake,
+    } = incoming;
+    run_peer(&swarm, addr, stream, handshake, permit).await
+}
+
+/// Fetches pieces from a peer we have exchanged handshakes with until none are left. The
+/// connection holds `_permit` while it lasts.
+async fn run_peer(
+    swarm: &Swarm,
+    addr: SocketAddr,
+    mut stream: PeerStream,
+    handshake: Handshake,
+    _permit: Option<OwnedSemaphorePermit>,
+) -> anyhow::Result<()> {
+    let torrent = &swarm.torrent;
+    let options = &swarm.options;
+    let mut framed = Framed::new(&mut stream, MessageFramer);
+    swarm.live_peers().insert(addr);
+    if let Some(resume) = &options.resume {
+        resume.add_peer(addr);
+    }
+
+    if options.pex && handshake.supports_extension_protocol() {
+        let ext_handshake = ExtensionHandshake::local(options.pex);
+        framed
+            .send(extended_message(extension::HANDSHAKE_ID, &ext_handshake)?)
+            .await
+            .context("send extension handshake")?;
+    }
+    // BEP 5: peers that run a DHT node learn where ours listens, so they can add it.
+    if let (Some(dht), true) = (&options.dht, handshake.supports_dht()) {
+        let port = dht.local_addr().context("DHT socket address")?.port();
+        let msg = Message {
+            tag: MessageTag::Port,
+            payload: port.to_be_bytes().to_vec(),
+        };
+        framed.send(msg).await.context("send port message")?;
+    }
+    let mut peer = PeerState {
+        fast: options.extensions().fast && handshake.supports_fast(),
+        ..Default::default()
+    };
+    init_download(&mut framed, &mut peer).await?;
+
+    loop {
+        anyhow::ensure!(
+            !swarm.bad_peers().contains(&addr),
+            "peer sent a piece that failed its hash check"
+        );
+        let piece_index = if peer.unchoked {
+            match swarm.queue.next().await {
+                Some(piece_index) => piece_index,
+                None => break,
+            }
+        } else {
+            // While choked, only pieces the peer allows us to fetch fast can be requested.
+            let allowed = peer
+                .allowed_fast
+                .iter()
+                .copied()
+                .find(|&piece_index| swarm.queue.take(piece_index));
+            match allowed {
+                Some(piece_index) => piece_index,
+                None => {
+                    recv_message(&mut framed, &mut peer).await?;
+                    continue;
+                }
+            }
+        };
+        if let (Some(port), Some(dht)) = (peer.dht_port.take(), &options.dht) {
+            dht.add_contact(SocketAddr::new(addr.ip(), port));
+        }
+        for discovered in peer.pex_added.drain(..) {
+            let _ = swarm.discovered_tx.try_send(discovered);
+        }
+        let peer_pex_id = peer
+            .extensions
+            .as_ref()
+            .and_then(|extensions| extensions.extension_id("ut_pex"));
+        if let (true, Some(peer_pex_id)) = (options.pex, peer_pex_id) {
+            let msg = peer.pex.next_message(&swarm.live_peers(), addr);
+            if let Some(msg) = msg {
+                framed
+                    .send(extended_message(peer_pex_id, &msg)?)
+                    .await
+                    .context("send ut_pex message")?;
+            }
+        }
+
+        let fetched = _download_piece(
+            torrent,
+            &mut framed,
+            &mut peer,
+            piece_index,
+            &swarm.storage,
+            &swarm.partial,
+            Some(options),
+        )
+        .await;
+        match fetched {
+            // Hashing happens elsewhere, so we can move on to the next piece right away.
+            Ok(true) => {
+                swarm
+                    .pieces_tx
+                    .send(Fetched::Unverified {
+                        piece_index,
+                        peer: addr,
+                    })
+                    .await
+                    .context("download was abandoned")?;
+            }
+            // Let another peer have a go while this one makes up its mind.
+            Ok(false) => {
+                peer.allowed_fast.remove(&piece_index);
+                swarm.queue.requeue(piece_index);
+                // A peer turning down requests is asked less and less often, so that one which
+                // unchokes us and then refuses everything cannot keep us busy.
+                if peer.rejections > 0 {
+                    anyhow::ensure!(
+                        peer.rejections < MAX_REJECTIONS,
+                        "peer rejected {MAX_REJECTIONS} requests in a row"
+                    );
+                    tokio::time::sleep(REJECT_BACKOFF * 2u32.pow(peer.rejections - 1)).await;
+                }
+            }
+            Err(err) => {
+                swarm.queue.requeue(piece_index);
+                return Err(err);
+            }
+        }
     }
     Ok(())
 }
+
+/// Fetches pieces over HTTP for as long as there are any left, giving up on the seed after it
+/// fails repeatedly or sends corrupt data.
+async fn web_seed_worker(swarm: Arc<Swarm>, seed: WebSeed) -> anyhow::Result<()> {
+    let torrent = &swarm.torrent;
+    let mut failures = 0;
+    while let Some(piece_index) = swarm.queue.next().await {
+        if let Some(download_limit) = &swarm.options.download_limit {
+            download_limit
+                .acquire(torrent.info.piece_size(piece_index))
+                .await;
+        }
+        let piece_bytes = match seed.fetch_piece(torrent, piece_index).await {
+            Ok(piece_bytes) => piece_bytes,
+            Err(err) => {
+                swarm.queue.requeue(piece_index);
+                failures += 1;
+                if failures == WEB_SEED_ATTEMPTS {
+                    return Err(err);
+                }
+                tokio::time::sleep(WEB_SEED_RETRY_DELAY * failures).await;
+                continue;
+            }
+        };
+        let torrent = torrent.clone();
+        let (verified, piece_bytes) = swarm
+            .options
+            .hash_pool
+            .run(move || (torrent.verify_piece(piece_index, &piece_bytes), piece_bytes))
+            .await?;
+        if !verified {
+            swarm.queue.requeue(piece_index);
+            anyhow::bail!("piece {piece_index} failed hash check");
+        }
+        failures = 0;
+        let write = with_storage(&swarm.storage, move |storage| {
+            storage.write_block(piece_index, 0, &piece_bytes)
+        });
+        if let Err(err) = write.await {
+            swarm.queue.requeue(piece_index);
+            return Err(err.context(format!("write piece {piece_index}")));
+        }
+        swarm
+            .pieces_tx
+            .send(Fetched::Verified(piece_index))
+            .await
+            .context("download was abandoned")?;
+    }
+    Ok(())
+}
+
+/// Optional machinery shared by every connection of a download.
+#[derive(Clone, Default)]
+pub struct SwarmOptions {
+    /// Our DHT node, advertised to peers and told about the DHT ports they send us.
+    pub dht: Option<Dht>,
+    /// Exchange peer lists with peers that support `ut_pex`.
+    pub pex: bool,
+    /// Whether connections to peers are encrypted.
+    pub encryption: EncryptionPolicy,
+    /// Socket to reach peers over uTP with, falling back to TCP for peers that do not answer.
+    pub utp: Option<UtpSocket>,
+    /// Fetch pieces from the torrent's web seeds too.
+    pub web_seeds: bool,
+    /// Threads downloaded pieces are verified on.
+    pub hash_pool: HashPool,
+    /// Counts pieces from peers as done without reading them back to hash, for storage that
+    /// keeps nothing to check, such as [`NullStorage`](crate::storage::NullStorage).
+    pub skip_hash_check: bool,
+    /// Which files to download, and in what order; every file if unset.
+    pub selection: Option<FileSelection>,
+    /// Readers to fetch pieces for first, and to tell about verified pieces.
+    pub streaming: Option<StreamControl>,
+    /// Pieces and blocks already on disk, and where to record progress for the next run.
+    pub resume: Option<ResumeState>,
+    /// Peer connections open at once, shared with other downloads holding the same semaphore.
+    pub connections: Option<Arc<Semaphore>>,
+    /// Caps the bytes fetched per second, shared with other downloads holding a clone.
+    pub download_limit: Option<RateLimiter>,
+    /// Give up once nothing has been fetching for this long, even though more peers may still
+    /// arrive; wait for them forever if unset.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl SwarmOptions {
+    pub(crate) fn extensions(&self) -> Extensions {
+        Extensions {
+            dht: self.dht.is_some(),
+            extension_protocol: self.pex,
+            fast: true,
+        }
+    }
+}
+
+/// A peer that connected to us and sent a handshake for the torrent, already answered.
+pub struct IncomingPeer {
+    pub addr: SocketAddr,
+    pub stream: PeerStream,
+    pub handshake: Handshake,
+}
+
+/// Downloads the wanted pieces of `torrent` into `storage`, spreading pieces over all peers
+/// received on `peers` while the download is running, and over the torrent's web seeds if
+/// enabled.
+pub async fn download_swarm(
+    torrent: Arc<Torrent>,
+    peers: mpsc::Receiver<SocketAddr>,
+    storage: Arc<dyn Storage>,
+    stats: Arc<TransferStats>,
+    options: SwarmOptions,
+) -> anyhow::Result<()> {
+    // Nobody connects to us without a listener.
+    let (_, incoming) = mpsc::channel(1);
+    download_swarm_accepting(torrent, peers, incoming, storage, stats, options).await
+}
+
+/// Like [`download_swarm`], also fetching from the peers that connect to us and are handed over
+/// on `incoming`.
+pub async fn download_swarm_accepting(
+    torrent: Arc<Torrent>,
+    peers: mpsc::Receiver<SocketAddr>,
+    incoming: mpsc::Receiver<IncomingPeer>,
+    storage: Arc<dyn Storage>,
+    stats: Arc<TransferStats>,
+    options: SwarmOptions,
+) -> anyhow::Result<()> {
+    let streaming = options.streaming.clone();
+    let result = run_swarm(torrent, peers, incoming, storage, stats, options).await;
+    if let Some(streaming) = streaming {
+        streaming.finish(&result);
+    }
+    result
+}
+
+async fn run_swarm(
+    torrent: Arc<Torrent>,
+    mut peers: mpsc::Receiver<SocketAddr>,
+    mut incoming: mpsc::Receiver<IncomingPeer>,
+    storage: Arc<dyn Storage>,
+    stats: Arc<TransferStats>,
+    options: SwarmOptions,
+) -> anyhow::Result<()> {
+    torrent.check_piece_layers()?;
+    let mut priorities = match &options.selection {
+        Some(selection) => selection.piece_priorities().to_vec(),
+        None => vec![FilePriority::Normal; torrent.info.piece_count()],
+    };
+    if let Some(resume) = &options.resume {
+        for (piece_index, priority) in priorities.iter_mut().enumerate() {
+            if resume.has_piece(piece_index) {
+                *priority = FilePriority::Skip;
+                if let Some(streaming) = &options.streaming {
+                    streaming.piece_verified(piece_index);
+                }
+            }
+        }
+    }
+    let queue = PieceQueue::new(priorities, options.streaming.clone());
+    let mut remaining = queue.outstanding();
+    let (pieces_tx, mut pieces_rx) = mpsc::channel(MAX_PEERS);
+    let (discovered_tx, mut discovered_rx) = mpsc::channel(MAX_PEERS);
+    let swarm = Arc::new(Swarm {
+        torrent: torrent.clone(),
+        options,
+        queue,
+        storage: storage.clone(),
+        partial: PartialPieces::default(),
+        pieces_tx,
+        discovered_tx,
+        live_peers: Mutex::new(HashSet::new()),
+        bad_peers: Mutex::new(HashSet::new()),
+    });
+    let mut workers = JoinSet::new();
+    let mut web_seeds = JoinSet::new();
+    let mut hashing = JoinSet::new();
+    if swarm.options.web_seeds {
+        for url in torrent.url_list.iter().flatten() {
+            let worker = web_seed_worker(swarm.clone(), WebSeed::new(url));
+            let url = url.clone();
+            web_seeds.spawn(async move { (url, worker.await) });
+        }
+    }
+    let mut connected: HashSet<SocketAddr> = HashSet::new();
+    let mut waiting: VecDeque<SocketAddr> = VecDeque::new();
+    let mut peers_open = true;
+    let mut incoming_open = true;
+
+    // When the last peer, web seed or check in flight went away, while more peers may come.
+    let mut idle_since = None;
+
+    while remaining > 0 {
+        let fetching = !workers.is_empty() || !web_seeds.is_empty() || !hashing.is_empty();
+        idle_since = match idle_since {
+            _ if fetching => None,
+            None => Some(tokio::time::Instant::now()),
+            idle_since => idle_since,
+        };
+        let idle_deadline = idle_since.zip(swarm.options.idle_timeout);
+        let addr = tokio::select! {
+            _ = async {
+                let (since, timeout) = idle_deadline.expect("only polled when idle");
+                tokio::time::sleep_until(since + timeout).await
+            }, if idle_deadline.is_some() => {
+                anyhow::bail!("ran out of peers with {remaining} pieces left");
+            }
+            addr = peers.recv(), if peers_open => {
+                let Some(addr) = addr else {
+                    peers_open = false;
+                    let fetching =
+                        !workers.is_empty() || !web_seeds.is_empty() || !hashing.is_empty();
+                    anyhow::ensure!(fetching, "ran out of peers with {remaining} pieces left");
+                    continue;
+                };
+                addr
+            }
+            Some(addr) = discovered_rx.recv() => addr,
+            peer = incoming.recv(), if incoming_open => {
+                let Some(peer) = peer else {
+                    incoming_open = false;
+                    continue;
+                };
+                let addr = peer.addr;
+                if connected.len() >= MAX_PEERS
+                    || swarm.bad_peers().contains(&addr)
+                    || !connected.insert(addr)
+                {
+                    continue;
+                }
+                let worker = incoming_worker(swarm.clone(), peer);
+                workers.spawn(async move { (addr, worker.await) });
+                continue;
+            }
+            Some(fetched) = pieces_rx.recv() => {
+                let piece_index = match fetched {
+                    Fetched::Verified(piece_index) => piece_index,
+                    Fetched::Unverified { piece_index, .. } if swarm.options.skip_hash_check => {
+                        piece_index
+                    }
+                    Fetched::Unverified { piece_index, peer } => {
+                        let hash_pool = swarm.options.hash_pool.clone();
+                        let storage = storage.clone();
+                        hashing.spawn(async move {
+                            (piece_index, peer, hash_pool.verify(storage, piece_index).await)
+                        });
+                        continue;
+                    }
+                };
+                let piece_size = torrent.info.piece_size(piece_index);
+                swarm.piece_done(piece_index);
+                remaining -= 1;
+                stats.downloaded.fetch_add(piece_size, Ordering::Relaxed);
+                stats.left.fetch_sub(piece_size, Ordering::Relaxed);
+                continue;
+            }
+            Some(joined) = hashing.join_next() => {
+                let (piece_index, peer, verified) = joined.context("hashing panicked")?;
+                if verified? {
+                    let piece_size = torrent.info.piece_size(piece_index);
+                    swarm.piece_done(piece_index);
+                    remaining -= 1;
+                    stats.downloaded.fetch_add(piece_size, Ordering::Relaxed);
+                    stats.left.fetch_sub(piece_size, Ordering::Relaxed);
+                } else {
+                    // Only the peer that sent the last block is banned. Other peers, or an
+                    // earlier run, may have sent the rest of the piece, and the bad block could
+                    // be theirs; the piece is fetched again whole either way.
+                    swarm.bad_peers().insert(peer);
+                    swarm.partial.clear(piece_index);
+                    if let Some(resume) = &swarm.options.resume {
+                        resume.piece_failed(piece_index);
+                    }
+                    swarm.queue.requeue(piece_index);
+                }
+                // The peer may have gone while its piece was checked.
+                let fetching = !workers.is_empty() || !web_seeds.is_empty() || !hashing.is_empty();
+                anyhow::ensure!(
+                    remaining == 0 || peers_open || fetching,
+                    "ran out of peers with {remaining} pieces left"
+                );
+                continue;
+            }
+            Some(joined) = workers.join_next() => {
+                let (addr, result) = joined.context("peer connection panicked")?;
+                if let Err(err) = result {
+                    eprintln!("Peer {addr} dropped: {err:#}");
+                }
+                connected.remove(&addr);
+                swarm.live_peers().remove(&addr);
+                while connected.len() < MAX_PEERS {
+                    let Some(addr) = waiting.pop_front() else {
+                        break;
+                    };
+                    if swarm.bad_peers().contains(&addr) || !connected.insert(addr) {
+                        continue;
+                    }
+                    let worker = peer_worker(swarm.clone(), addr);
+                    workers.spawn(async move { (addr, worker.await) });
+                }
+                let fetching = !workers.is_empty() || !web_seeds.is_empty() || !hashing.is_empty();
+                anyhow::ensure!(
+                    peers_open || fetching,
+                    "ran out of peers with {remaining} pieces left"
+                );
+                continue;
+            }
+            Some(joined) = web_seeds.join_next() => {
+                let (url, result) = joined.context("web seed panicked")?;
+                if let Err(err) = result {
+                    eprintln!("Web seed {url} dropped: {err:#}");
+                }
+                let fetching = !workers.is_empty() || !web_seeds.is_empty() || !hashing.is_empty();
+                anyhow::ensure!(
+                    peers_open || fetching,
+                    "ran out of peers with {remaining} pieces left"
+                );
+                continue;
+            }
+        };
+        if swarm.bad_peers().contains(&addr) || connected.contains(&addr) {
+            continue;
+        }
+        if connected.len() >= MAX_PEERS {
+            if waiting.len() < MAX_WAITING_PEERS && !waiting.contains(&addr) {
+                waiting.push_back(addr);
+            }
+            continue;
+        }
+        connected.insert(addr);
+        let worker = peer_worker(swarm.clone(), addr);
+        workers.spawn(async move { (addr, worker.await) });
+    }
+    flush_storage(&storage).await
+}
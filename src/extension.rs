@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::message::{Message, MessageTag};
+
+/// Extended message id reserved for the extension handshake itself (BEP 10).
+pub const HANDSHAKE_ID: u8 = 0;
+/// Id we ask peers to use when sending us `ut_pex` messages.
+pub const UT_PEX_ID: u8 = 1;
+
+/// The bencoded dictionary exchanged right after the BitTorrent handshake, mapping extension
+/// names to the message ids the sender wants to receive them under.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    #[serde(default)]
+    pub m: HashMap<String, i64>,
+    pub p: Option<i64>,    // TCP listen port of the sender
+    pub v: Option<String>, // client name and version
+}
+
+impl ExtensionHandshake {
+    /// The handshake we send, listing the extensions we understand.
+    pub fn local(pex: bool) -> Self {
+        let mut m = HashMap::new();
+        if pex {
+            m.insert(String::from("ut_pex"), UT_PEX_ID as i64);
+        }
+        Self {
+            m,
+            p: None,
+            v: Some(format!(
+                "bittorrent-starter-rust {}",
+                env!("CARGO_PKG_VERSION")
+            )),
+        }
+    }
+
+    /// Message id the peer expects for `name`, if it supports that extension at all.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m
+            .get(name)
+            .and_then(|&id| u8::try_from(id).ok())
+            .filter(|&id| id != HANDSHAKE_ID)
+    }
+}
+
+pub fn extended_message(id: u8, payload: &impl Serialize) -> anyhow::Result<Message> {
+    let mut bytes = vec![id];
+    bytes.extend(serde_bencode::to_bytes(payload).context("serialize extended message")?);
+    Ok(Message {
+        tag: MessageTag::Extended,
+        payload: bytes,
+    })
+}
+
+/// Splits an extended message into its extension id and bencoded payload.
+pub fn parse_extended(msg: &Message) -> anyhow::Result<(u8, &[u8])> {
+    let (&id, payload) = msg
+        .payload
+        .split_first()
+        .context("extended message has no id")?;
+    Ok((id, payload))
+}
@@ -1,9 +1,7 @@
 use anyhow::Context;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::peer_id::local_peer_id;
 use crate::torrent::Torrent;
 
 #[derive(Debug)]
@@ -16,6 +14,30 @@ pub struct Handshake {
     pub peer_id: [u8; 20],
 }
 
+/// Protocol extensions advertised through the reserved bytes of the handshake.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Extensions {
+    pub dht: bool,                // BEP 5: bit 0x01 of the last reserved byte
+    pub extension_protocol: bool, // BEP 10: bit 0x10 of the sixth reserved byte
+    pub fast: bool,               // BEP 6: bit 0x04 of the last reserved byte
+}
+
+impl Extensions {
+    pub fn reserved(&self) -> [u8; 8] {
+        let mut reserved = [0; 8];
+        if self.dht {
+            reserved[7] |= 0x01;
+        }
+        if self.extension_protocol {
+            reserved[5] |= 0x10;
+        }
+        if self.fast {
+            reserved[7] |= 0x04;
+        }
+        reserved
+    }
+}
+
 impl Handshake {
     pub fn new(info_hash: &[u8; 20], peer_id: &[u8; 20]) -> Self {
         Self {
@@ -26,26 +48,62 @@ impl Handshake {
             peer_id: *peer_id,
         }
     }
+
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & 0x01 != 0
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
 }
 
-pub async fn perform_handshake(
+pub async fn perform_handshake<S: AsyncRead + AsyncWrite + Unpin>(
     torrent: &Torrent,
-    tcp_stream: &mut TcpStream,
+    stream: &mut S,
+    extensions: Extensions,
 ) -> anyhow::Result<Handshake> {
     let info_hash = torrent.info_hash();
-    let mut handshake = Handshake::new(&info_hash, &b"00112233445566778899");
+    let mut handshake = Handshake::new(&info_hash, local_peer_id());
+    handshake.reserved = extensions.reserved();
 
     let bytes = &mut handshake as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()];
     let bytes: &mut [u8; std::mem::size_of::<Handshake>()] = unsafe { &mut *bytes };
 
-    tcp_stream
-        .write_all(bytes)
-        .await
-        .context("Write handshake")?;
-    tcp_stream
-        .read_exact(bytes)
-        .await
-        .context("Read handshake")?;
+    stream.write_all(bytes).await.context("Write handshake")?;
+    stream.read_exact(bytes).await.context("Read handshake")?;
 
     Ok(handshake)
 }
+
+/// Reads the handshake of a peer that connected to us, before answering with
+/// [`send_handshake`] once we know which torrent it is after.
+pub async fn read_handshake<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<Handshake> {
+    let mut handshake = Handshake::new(&[0; 20], &[0; 20]);
+    let bytes = &mut handshake as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()];
+    let bytes: &mut [u8; std::mem::size_of::<Handshake>()] = unsafe { &mut *bytes };
+    stream.read_exact(bytes).await.context("Read handshake")?;
+    anyhow::ensure!(
+        handshake.length == 19 && &handshake.protocol == b"BitTorrent protocol",
+        "peer does not speak the BitTorrent protocol"
+    );
+    Ok(handshake)
+}
+
+/// Answers a peer's handshake for `info_hash`.
+pub async fn send_handshake<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    info_hash: &[u8; 20],
+    extensions: Extensions,
+) -> anyhow::Result<()> {
+    let mut handshake = Handshake::new(info_hash, local_peer_id());
+    handshake.reserved = extensions.reserved();
+    let bytes = &mut handshake as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()];
+    let bytes: &mut [u8; std::mem::size_of::<Handshake>()] = unsafe { &mut *bytes };
+    stream.write_all(bytes).await.context("Write handshake")?;
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::Context;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::storage::Storage;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of threads that pieces are verified on, so hashing never holds up a network task
+/// and no more pieces are hashed at once than there are threads. The threads start on first use;
+/// clones share them, and they exit once every clone is dropped.
+#[derive(Debug, Clone)]
+pub struct HashPool {
+    threads: usize,
+    jobs: Arc<OnceLock<mpsc::Sender<Job>>>,
+}
+
+impl HashPool {
+    /// A pool of `threads` hashing threads. Submitting work waits while every thread is busy and
+    /// as many jobs again are queued.
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+            jobs: Arc::default(),
+        }
+    }
+
+    fn jobs(&self) -> &mpsc::Sender<Job> {
+        self.jobs.get_or_init(|| {
+            let (jobs_tx, jobs_rx) = mpsc::channel::<Job>(self.threads);
+            let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+            for i in 0..self.threads {
+                let jobs_rx = jobs_rx.clone();
+                std::thread::Builder::new()
+                    .name(format!("hasher-{i}"))
+                    .spawn(move || loop {
+                        let job = jobs_rx
+                            .lock()
+                            .expect("hashing queue lock is not poisoned")
+                            .blocking_recv();
+                        let Some(job) = job else {
+                            return;
+                        };
+                        // A panicking job only fails its own caller.
+                        let _ = std::panic::catch_unwind(AssertUnwindSafe(job));
+                    })
+                    .expect("spawn hashing thread");
+            }
+            jobs_tx
+        })
+    }
+
+    /// Runs `f` on one of the pool's threads.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let job = Box::new(move || {
+            let _ = result_tx.send(f());
+        });
+        self.jobs()
+            .send(job)
+            .await
+            .map_err(|_| anyhow::anyhow!("hashing threads are gone"))?;
+        result_rx.await.context("hashing job panicked")
+    }
+
+    /// Checks piece `piece_index` in `storage` against the torrent.
+    pub async fn verify(
+        &self,
+        storage: Arc<dyn Storage>,
+        piece_index: usize,
+    ) -> anyhow::Result<bool> {
+        self.run(move || storage.verify(piece_index))
+            .await?
+            .with_context(|| format!("read piece {piece_index} back"))
+    }
+}
+
+impl Default for HashPool {
+    /// A thread per core.
+    fn default() -> Self {
+        let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+        Self::new(threads)
+    }
+}
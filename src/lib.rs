@@ -1,7 +1,29 @@
+pub mod cache;
+pub mod create;
 pub mod decode;
+pub mod dht;
 pub mod download;
+pub mod extension;
 pub mod handshake;
+pub mod hash_pool;
+pub mod lsd;
+pub mod merkle;
 pub mod message;
+pub mod mock_peer;
+pub mod mse;
+pub mod peer_id;
+pub mod pex;
+pub mod rate_limit;
+pub mod resume;
+pub mod scrape;
+pub mod selection;
+pub mod session;
+pub mod storage;
+pub mod stream;
 pub mod torrent;
 pub mod tracker;
+pub mod tracker_server;
+pub mod transport;
 pub(crate) mod utils;
+pub mod utp;
+pub mod webseed;
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+pub const LSD_MULTICAST_ADDR: SocketAddrV4 =
+    SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+/// BEP 14 asks for no more than one announce per torrent every few minutes.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub struct LsdConfig {
+    pub multicast_addr: SocketAddrV4,
+    /// Interface to join the multicast group on; unspecified lets the OS pick.
+    pub interface: Ipv4Addr,
+    /// Port we accept peer connections on, as advertised in announces. Without one, nothing is
+    /// announced and we only pick up the announces of other peers.
+    pub port: Option<u16>,
+    pub announce_interval: Duration,
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        Self {
+            multicast_addr: LSD_MULTICAST_ADDR,
+            interface: Ipv4Addr::UNSPECIFIED,
+            port: None,
+            announce_interval: ANNOUNCE_INTERVAL,
+        }
+    }
+}
+
+/// A parsed `BT-SEARCH` announce.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LsdAnnounce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    pub fn to_bytes(&self, multicast_addr: &SocketAddrV4) -> Vec<u8> {
+        let mut msg = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {multicast_addr}\r\nPort: {}\r\n",
+            self.port
+        );
+        for info_hash in &self.info_hashes {
+            msg.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            msg.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        msg.push_str("\r\n\r\n");
+        msg.into_bytes()
+    }
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(bytes).ok()?;
+        let mut lines = text.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    let mut info_hash = [0u8; 20];
+                    if hex::decode_to_slice(value, &mut info_hash).is_ok() {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(Self {
+            port: port?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+struct LsdInner {
+    socket: UdpSocket,
+    config: LsdConfig,
+    // Lets us recognise our own announces when they are looped back.
+    cookie: String,
+    torrents: Mutex<HashMap<[u8; 20], mpsc::Sender<SocketAddr>>>,
+}
+
+/// Local Service Discovery (BEP 14): finds peers on the local network by multicasting the info
+/// hashes of active torrents.
+#[derive(Clone)]
+pub struct Lsd {
+    inner: Arc<LsdInner>,
+}
+
+impl Lsd {
+    pub fn bind(config: LsdConfig) -> anyhow::Result<Self> {
+        // Other clients on this machine listen on the same port, so the address must be shared.
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.multicast_addr.port());
+        socket.bind(&bind_addr.into()).context("bind LSD socket")?;
+        socket
+            .join_multicast_v4(config.multicast_addr.ip(), &config.interface)
+            .context("join LSD multicast group")?;
+        socket.set_multicast_if_v4(&config.interface)?;
+        socket.set_multicast_loop_v4(true)?;
+
+        let socket = UdpSocket::from_std(socket.into())?;
+        let cookie = hex::encode(rand::random::<[u8; 8]>());
+        Ok(Self {
+            inner: Arc::new(LsdInner {
+                socket,
+                config,
+                cookie,
+                torrents: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    fn torrents(&self) -> std::sync::MutexGuard<'_, HashMap<[u8; 20], mpsc::Sender<SocketAddr>>> {
+        self.inner
+            .torrents
+            .lock()
+            .expect("LSD torrents lock is not poisoned")
+    }
+
+    /// Starts announcing `info_hash` and forwarding peers found for it to `peers_tx`.
+    pub fn add_torrent(&self, info_hash: [u8; 20], peers_tx: mpsc::Sender<SocketAddr>) {
+        self.torrents().insert(info_hash, peers_tx);
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        self.torrents().remove(info_hash);
+    }
+
+    /// Multicasts one announce per active torrent, if we accept connections.
+    pub async fn announce(&self) -> anyhow::Result<()> {
+        let Some(port) = self.inner.config.port else {
+            return Ok(());
+        };
+        let info_hashes: Vec<[u8; 20]> = self.torrents().keys().copied().collect();
+        for info_hash in info_hashes {
+            let announce = LsdAnnounce {
+                port,
+                info_hashes: vec![info_hash],
+                cookie: Some(self.inner.cookie.clone()),
+            };
+            let bytes = announce.to_bytes(&self.inner.config.multicast_addr);
+            self.inner
+                .socket
+                .send_to(&bytes, self.inner.config.multicast_addr)
+                .await
+                .context("send LSD announce")?;
+        }
+        Ok(())
+    }
+
+    fn handle_announce(&self, bytes: &[u8], from: SocketAddr) {
+        let Some(announce) = LsdAnnounce::parse(bytes) else {
+            return;
+        };
+        if announce.cookie.as_deref() == Some(self.inner.cookie.as_str()) {
+            return;
+        }
+        let peer = SocketAddr::new(from.ip(), announce.port);
+        let torrents = self.torrents();
+        for info_hash in &announce.info_hashes {
+            if let Some(peers_tx) = torrents.get(info_hash) {
+                let _ = peers_tx.try_send(peer);
+            }
+        }
+    }
+
+    /// Announces active torrents periodically and listens for announces from other peers until
+    /// `shutdown` fires.
+    pub async fn run(self, shutdown: CancellationToken) {
+        let mut announce_timer = tokio::time::interval(self.inner.config.announce_interval);
+        let mut buf = vec![0u8; 1500];
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = announce_timer.tick() => {
+                    if let Err(err) = self.announce().await {
+                        eprintln!("LSD announce failed: {err:#}");
+                    }
+                }
+                received = self.inner.socket.recv_from(&mut buf) => {
+                    if let Ok((n, from)) = received {
+                        self.handle_announce(&buf[..n], from);
+                    }
+                }
+            }
+        }
+    }
+}
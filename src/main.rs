@@ -1,16 +1,38 @@
 use anyhow::Context;
-use clap::{arg, Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 use bittorrent_starter_rust::{
+    cache::CachedStorage,
+    create::{MetaVersion, TorrentBuilder},
     decode::decode_bencoded_value,
-    download::{download_file, download_piece},
-    handshake::perform_handshake,
+    dht::{Dht, DhtConfig},
+    download::{download_piece, download_swarm, SwarmOptions, TransferStats, DEFAULT_IDLE_TIMEOUT},
+    handshake::{perform_handshake, Extensions},
+    hash_pool::HashPool,
+    lsd::{Lsd, LsdConfig},
     message::MessageFramer,
+    mse::EncryptionPolicy,
+    resume::{self, ResumeState},
+    scrape::scrape,
+    selection::{FilePriority, FileSelection},
+    session::{Session, SessionConfig, TorrentState},
+    storage::{self, FsStorage, MemoryStorage, MmapStorage, Storage},
+    stream::StreamControl,
     torrent::Torrent,
-    tracker::request_tracker,
+    tracker::{request_tracker, TrackerRequest, TrackerSession, TrackerTiers},
+    tracker_server::{TrackerServer, TrackerServerConfig},
+    utp::UtpSocket,
 };
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -31,6 +53,10 @@ enum Command {
     Peers {
         filepath: PathBuf,
     },
+    Scrape {
+        #[arg(required = true)]
+        filepaths: Vec<PathBuf>,
+    },
     Handshake {
         filepath: PathBuf,
         peer_addr: String,
@@ -45,7 +71,298 @@ enum Command {
         #[arg(short)]
         outpath: PathBuf,
         filepath: PathBuf,
+        /// Write to the downloaded files through memory mappings.
+        #[arg(long)]
+        mmap: bool,
+        /// MiB of blocks to hold before writing them out, and of pieces to keep for reading;
+        /// 0 turns caching off.
+        #[arg(long, default_value_t = 64)]
+        cache: usize,
+        /// Threads to verify pieces on; one per core by default.
+        #[arg(long = "hashing-threads")]
+        hashing_threads: Option<usize>,
+        /// Only download files matching this glob; repeat to match more files.
+        #[arg(long, value_name = "GLOB")]
+        only: Vec<String>,
+        /// Do not download files matching this glob; repeat to match more files.
+        #[arg(long, value_name = "GLOB")]
+        skip: Vec<String>,
+        /// Download files matching a glob at a priority (skip, low, normal or high), given as
+        /// GLOB=PRIORITY; repeat to set more, later ones winning.
+        #[arg(long = "priority", value_name = "GLOB=PRIORITY", value_parser = parse_priority)]
+        priorities: Vec<(String, FilePriority)>,
+        /// File progress is kept in between runs; next to the download by default.
+        #[arg(long = "resume-file")]
+        resume_file: Option<PathBuf>,
+        /// Neither read nor save progress, fetching every piece again.
+        #[arg(long = "no-resume")]
+        no_resume: bool,
+        #[command(flatten)]
+        network: NetworkArgs,
+    },
+    /// Download a single file, writing it to stdout in order as its pieces arrive.
+    Stream {
+        #[arg(short)]
+        outpath: PathBuf,
+        filepath: PathBuf,
+        /// Path of the file within the torrent; may be left out if there is only one.
+        #[arg(long)]
+        file: Option<String>,
+        /// MiB past the read position to fetch before anything else.
+        #[arg(long, default_value_t = 4)]
+        readahead: usize,
+        #[command(flatten)]
+        network: NetworkArgs,
+    },
+    /// Download several torrents side by side, sharing a listening port, the DHT node, and
+    /// limits on connections and download rate. Nothing is uploaded, and local discovery is
+    /// not used.
+    Session {
+        /// Directory each torrent is downloaded into, under its own name.
+        #[arg(short)]
+        outdir: PathBuf,
+        #[arg(required = true)]
+        filepaths: Vec<PathBuf>,
+        /// Port peers connect to us on, to be downloaded from.
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+        /// Peer connections open at once, over every torrent.
+        #[arg(long = "max-connections", default_value_t = 200)]
+        max_connections: usize,
+        /// KiB per second downloaded at most, over every torrent.
+        #[arg(long = "download-limit")]
+        download_limit: Option<usize>,
+        #[command(flatten)]
+        network: NetworkArgs,
+    },
+    /// Create a torrent from a file or directory.
+    Create {
+        #[arg(short)]
+        outpath: PathBuf,
+        path: PathBuf,
+        /// Tracker announce URL; repeat to add backup trackers, each in a tier of its own.
+        #[arg(long = "tracker", required = true)]
+        trackers: Vec<String>,
+        /// Piece length in bytes, a power of two; chosen from the content size by default.
+        #[arg(long = "piece-length")]
+        piece_length: Option<usize>,
+        #[arg(long)]
+        comment: Option<String>,
+        /// Only find peers through the trackers.
+        #[arg(long)]
+        private: bool,
+        /// URL serving the content over HTTP.
+        #[arg(long = "web-seed")]
+        web_seeds: Vec<String>,
+        /// Metadata format: v1 for the widest support, v2 or hybrid for per-file merkle trees.
+        #[arg(long = "meta-version", value_enum, default_value_t = MetaVersion::V1)]
+        meta_version: MetaVersion,
+        /// Leave out the creation date, so the same content always gives the same file.
+        #[arg(long = "no-date")]
+        no_date: bool,
     },
+    Tracker {
+        #[command(subcommand)]
+        command: TrackerCommand,
+    },
+}
+
+/// How the commands that download find and talk to peers.
+#[derive(clap::Args, Debug)]
+struct NetworkArgs {
+    /// Do not look for peers on the mainline DHT.
+    #[arg(long = "no-dht")]
+    no_dht: bool,
+    /// Do not exchange peer lists with connected peers.
+    #[arg(long = "no-pex")]
+    no_pex: bool,
+    /// Do not look for peers on the local network.
+    #[arg(long = "no-lsd")]
+    no_lsd: bool,
+    /// Only connect to peers over TCP.
+    #[arg(long = "no-utp")]
+    no_utp: bool,
+    /// Do not fetch pieces from the torrent's HTTP mirrors.
+    #[arg(long = "no-web-seeds")]
+    no_web_seeds: bool,
+    /// DHT node to bootstrap from instead of the well-known routers.
+    #[arg(long = "dht-node")]
+    dht_nodes: Vec<String>,
+    /// File the DHT routing table is kept in between runs.
+    #[arg(long = "dht-state")]
+    dht_state: Option<PathBuf>,
+    /// Whether to encrypt connections to peers.
+    #[arg(long, value_enum, default_value_t = EncryptionPolicy::Prefer)]
+    encryption: EncryptionPolicy,
+}
+
+/// The trackers, DHT searches and local discovery feeding peers to a download, stopped together
+/// once it ends.
+struct PeerDiscovery {
+    completed_tx: watch::Sender<bool>,
+    shutdown: CancellationToken,
+    tracker_tasks: Vec<JoinHandle<anyhow::Result<()>>>,
+    dht_tasks: Vec<JoinHandle<()>>,
+    lsd_task: Option<JoinHandle<()>>,
+}
+
+impl PeerDiscovery {
+    /// Starts looking for peers of `torrent`, returning where they arrive, after `known_peers`,
+    /// and the swarm options matching `network`.
+    async fn start(
+        torrent: &Arc<Torrent>,
+        stats: &Arc<TransferStats>,
+        network: NetworkArgs,
+        known_peers: Vec<SocketAddr>,
+    ) -> anyhow::Result<(Self, mpsc::Receiver<SocketAddr>, SwarmOptions)> {
+        // Private torrents only get their peers from the trackers.
+        let private = torrent.info.is_private();
+        let (no_dht, no_pex, no_lsd) = (
+            network.no_dht || private,
+            network.no_pex || private,
+            network.no_lsd || private,
+        );
+
+        let (peers_tx, peers_rx) = mpsc::channel(64);
+        if !known_peers.is_empty() {
+            let peers_tx = peers_tx.clone();
+            tokio::spawn(async move {
+                for addr in known_peers {
+                    if peers_tx.send(addr).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        let (completed_tx, completed_rx) = watch::channel(false);
+        let shutdown = CancellationToken::new();
+        // Hybrid torrents are shared in a v1 and a v2 swarm; peers in either have the data.
+        let swarm_hashes = torrent.swarm_hashes();
+        let tracker_tasks: Vec<_> = swarm_hashes
+            .iter()
+            .map(|&info_hash| {
+                let session = TrackerSession::new(torrent, stats.clone(), peers_tx.clone())
+                    .with_info_hash(info_hash);
+                tokio::spawn(session.run(completed_rx.clone(), shutdown.clone()))
+            })
+            .collect();
+
+        let dht = if no_dht {
+            None
+        } else {
+            let mut config = DhtConfig {
+                state_path: network.dht_state,
+                ..Default::default()
+            };
+            if !network.dht_nodes.is_empty() {
+                config.bootstrap_nodes = network.dht_nodes;
+            }
+            Some(Dht::bind(config).await?)
+        };
+        let utp = if network.no_utp {
+            None
+        } else {
+            Some(UtpSocket::bind("0.0.0.0:0").await?)
+        };
+        let lsd_task = if no_lsd {
+            None
+        } else {
+            // Nothing here accepts connections, so there is no port to announce; we only
+            // pick up the peers that announce themselves.
+            match Lsd::bind(LsdConfig::default()) {
+                Ok(lsd) => {
+                    for &info_hash in &swarm_hashes {
+                        lsd.add_torrent(info_hash, peers_tx.clone());
+                    }
+                    Some(tokio::spawn(lsd.run(shutdown.clone())))
+                }
+                Err(err) => {
+                    eprintln!("Local service discovery unavailable: {err:#}");
+                    None
+                }
+            }
+        };
+        let dht_tasks: Vec<_> = dht
+            .iter()
+            .flat_map(|dht| {
+                swarm_hashes.iter().map(|&info_hash| {
+                    let search = dht.clone().search_peers(
+                        info_hash,
+                        None,
+                        peers_tx.clone(),
+                        shutdown.clone(),
+                    );
+                    tokio::spawn(search)
+                })
+            })
+            .collect();
+
+        let discovery = Self {
+            completed_tx,
+            shutdown,
+            tracker_tasks,
+            dht_tasks,
+            lsd_task,
+        };
+        let options = SwarmOptions {
+            dht,
+            pex: !no_pex,
+            encryption: network.encryption,
+            utp,
+            web_seeds: !network.no_web_seeds,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            ..Default::default()
+        };
+        Ok((discovery, peers_rx, options))
+    }
+
+    /// Stops every peer source, telling the trackers whether the download completed.
+    async fn stop(self, completed: bool) -> anyhow::Result<()> {
+        if completed {
+            self.completed_tx.send_replace(true);
+        }
+        self.shutdown.cancel();
+        for tracker_task in self.tracker_tasks {
+            if let Err(err) = tracker_task.await? {
+                eprintln!("Tracker shutdown failed: {err:#}");
+            }
+        }
+        for dht_task in self.dht_tasks {
+            dht_task.await?;
+        }
+        if let Some(lsd_task) = self.lsd_task {
+            lsd_task.await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Subcommand, Debug)]
+#[clap(rename_all = "snake_case")]
+enum TrackerCommand {
+    /// Run a tracker for the local network.
+    Serve {
+        #[arg(long, default_value = "0.0.0.0:6969")]
+        addr: SocketAddr,
+        /// Seconds clients should wait between announces.
+        #[arg(long, default_value_t = 1800)]
+        interval: u64,
+    },
+}
+
+fn parse_priority(arg: &str) -> Result<(String, FilePriority), String> {
+    let (pattern, priority) = arg
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected GLOB=PRIORITY, got {arg:?}"))?;
+    let priority = FilePriority::from_str(priority, true)?;
+    Ok((pattern.to_string(), priority))
+}
+
+fn print_tracker_tiers(tiers: &[Vec<String>]) {
+    println!("Tracker Tiers:");
+    for (tier_index, tier) in tiers.iter().enumerate() {
+        println!("  Tier {}: {}", tier_index + 1, tier.join(", "));
+    }
 }
 
 #[tokio::main]
@@ -55,16 +372,22 @@ async fn main() -> anyhow::Result<()> {
     match args.command {
         Command::Decode { value } => {
             let decoded_value = decode_bencoded_value(&value);
-            println!("{}", decoded_value.to_string());
+            println!("{decoded_value}");
         }
         Command::Info { filepath } => {
             let content = std::fs::read(filepath)?;
             let torrent = serde_bencode::from_bytes::<Torrent>(&content)?;
             println!("Tracker URL: {}", torrent.announce);
-            println!("Length: {}", torrent.info.length);
+            if torrent.announce_list.is_some() {
+                print_tracker_tiers(&torrent.announce_tiers());
+            }
+            println!("Length: {}", torrent.info.length());
 
             let info_hash = torrent.info_hash();
-            println!("Info Hash: {}", hex::encode(&info_hash));
+            println!("Info Hash: {}", hex::encode(info_hash));
+            if let Some(info_hash_v2) = torrent.info_hash_v2() {
+                println!("Info Hash v2: {}", hex::encode(info_hash_v2));
+            }
 
             println!("Piece Length: {}", torrent.info.piece_length);
             println!("Piece Hashes:");
@@ -77,11 +400,64 @@ async fn main() -> anyhow::Result<()> {
             let torrent = serde_bencode::from_bytes::<Torrent>(&content)
                 .context("Deserialize torrent file")?;
 
-            let tracker_res = request_tracker(&torrent).await?;
+            let tracker_req = TrackerRequest::new(torrent.info.length());
+            let mut trackers = TrackerTiers::new(&torrent);
+            let (tracker_url, tracker_res) = trackers
+                .announce(&torrent.info_hash(), &tracker_req)
+                .await?;
+            if let Some(warning) = &tracker_res.warning_message {
+                eprintln!("Tracker warning: {warning}");
+            }
+            if torrent.announce_list.is_some() {
+                print_tracker_tiers(trackers.tiers());
+                println!("Peers from {tracker_url}:");
+            }
             tracker_res.get_peers().iter().for_each(|ip_addr| {
                 println!("{}", ip_addr);
             })
         }
+        Command::Scrape { filepaths } => {
+            let mut torrents = Vec::with_capacity(filepaths.len());
+            for filepath in &filepaths {
+                let content = std::fs::read(filepath)?;
+                let torrent = serde_bencode::from_bytes::<Torrent>(&content)
+                    .with_context(|| format!("Deserialize {}", filepath.display()))?;
+                torrents.push(torrent);
+            }
+
+            // Batch the info hashes of every torrent sharing a tracker into the same scrape.
+            let mut batches: BTreeMap<String, Vec<[u8; 20]>> = BTreeMap::new();
+            for torrent in &torrents {
+                for url in torrent.announce_tiers().into_iter().flatten() {
+                    batches.entry(url).or_default().push(torrent.info_hash());
+                }
+            }
+            let mut results = HashMap::new();
+            for (url, info_hashes) in &batches {
+                match scrape(url, info_hashes).await {
+                    Ok(stats) => {
+                        for (info_hash, stats) in stats {
+                            results.insert((url.as_str(), info_hash), stats);
+                        }
+                    }
+                    Err(err) => eprintln!("Scrape of {url} failed: {err:#}"),
+                }
+            }
+
+            for torrent in &torrents {
+                let info_hash = torrent.info_hash();
+                println!("{} ({})", torrent.info.name, hex::encode(info_hash));
+                for url in torrent.announce_tiers().iter().flatten() {
+                    match results.get(&(url.as_str(), info_hash)) {
+                        Some(stats) => println!(
+                            "  {url}: seeders {}, leechers {}, completed {}",
+                            stats.complete, stats.incomplete, stats.downloaded
+                        ),
+                        None => println!("  {url}: unavailable"),
+                    }
+                }
+            }
+        }
         Command::Handshake {
             filepath,
             peer_addr,
@@ -91,8 +467,9 @@ async fn main() -> anyhow::Result<()> {
                 .context("Deserialize torrent file")?;
 
             let mut tcp_stream = TcpStream::connect(&peer_addr).await?;
-            let peer_msg = perform_handshake(&torrent, &mut tcp_stream).await?;
-            println!("Peer ID: {}", hex::encode(&peer_msg.peer_id));
+            let peer_msg =
+                perform_handshake(&torrent, &mut tcp_stream, Extensions::default()).await?;
+            println!("Peer ID: {}", hex::encode(peer_msg.peer_id));
         }
         Command::DownloadPiece {
             outpath,
@@ -105,41 +482,332 @@ async fn main() -> anyhow::Result<()> {
 
             let tracker_res = request_tracker(&torrent).await?;
             let peers = tracker_res.get_peers();
-            let peer_addr = &peers.iter().nth(0).context("Get peer addr")?;
+            let peer_addr = &peers.first().context("Get peer addr")?;
             let mut tcp_stream = TcpStream::connect(&peer_addr).await?;
 
-            perform_handshake(&torrent, &mut tcp_stream).await?;
+            perform_handshake(&torrent, &mut tcp_stream, Extensions::default()).await?;
 
-            let mut output_file = tokio::fs::File::create(&outpath)
-                .await
-                .expect("create output file");
+            let torrent = Arc::new(torrent);
+            let memory = Arc::new(MemoryStorage::new(torrent.clone()));
+            let storage: Arc<dyn Storage> = memory.clone();
             let mut framed = tokio_util::codec::Framed::new(&mut tcp_stream, MessageFramer);
-            download_piece(&torrent, &mut framed, piece_index, &mut output_file).await?;
+            download_piece(&torrent, &mut framed, piece_index, &storage).await?;
+
+            let mut piece = vec![0; torrent.info.piece_size(piece_index)];
+            memory.read_block(piece_index, 0, &mut piece)?;
+            std::fs::write(&outpath, piece)
+                .with_context(|| format!("write {}", outpath.display()))?;
 
             println!("Piece {piece_index} downloaded to {}.", outpath.display());
         }
-        Command::Download { outpath, filepath } => {
+        Command::Download {
+            outpath,
+            filepath,
+            mmap,
+            cache,
+            hashing_threads,
+            only,
+            skip,
+            priorities,
+            resume_file,
+            no_resume,
+            network,
+        } => {
             let content = std::fs::read(&filepath)?;
             let torrent = serde_bencode::from_bytes::<Torrent>(&content)
                 .context("Deserialize torrent file")?;
+            let torrent = Arc::new(torrent);
 
-            let tracker_res = request_tracker(&torrent).await?;
-            let peers = tracker_res.get_peers();
-            let peer_addr = &peers.iter().nth(0).context("Get peer addr")?;
-            let mut tcp_stream = TcpStream::connect(&peer_addr).await?;
+            let mut selection = FileSelection::new(&torrent.info);
+            for (pattern, priority) in &priorities {
+                selection = selection.prioritize(pattern, *priority)?;
+            }
+            if !only.is_empty() {
+                selection = selection.only(&only)?;
+            }
+            let selection = selection.skip(&skip)?;
 
-            perform_handshake(&torrent, &mut tcp_stream).await?;
-            let mut output_file = tokio::fs::File::create(&outpath)
-                .await
-                .expect("create output file");
-            let mut framed = tokio_util::codec::Framed::new(&mut tcp_stream, MessageFramer);
-            download_file(&torrent, &mut framed, &mut output_file).await?;
+            // The files have to be looked at before opening storage resizes them.
+            let resume = if no_resume {
+                None
+            } else {
+                let path = resume_file.unwrap_or_else(|| resume::default_path(&outpath));
+                Some(ResumeState::load(
+                    torrent.clone(),
+                    path,
+                    &outpath,
+                    &selection,
+                )?)
+            };
+            let open_error = || format!("open {}", outpath.display());
+            let mut storage: Arc<dyn Storage> = if mmap {
+                let storage = MmapStorage::with_selection(torrent.clone(), &outpath, &selection);
+                Arc::new(storage.with_context(open_error)?)
+            } else {
+                let storage = FsStorage::with_selection(torrent.clone(), &outpath, &selection);
+                Arc::new(storage.with_context(open_error)?)
+            };
+            if cache > 0 {
+                storage = Arc::new(CachedStorage::new(storage, cache << 20));
+            }
+            let hash_pool = hashing_threads.map(HashPool::new).unwrap_or_default();
+            let (left, known_peers) = match &resume {
+                Some(resume) => {
+                    let rechecked = resume.recheck_pieces().len();
+                    if rechecked > 0 {
+                        eprintln!("Checking {rechecked} pieces already on disk.");
+                        resume.recheck(&storage, &hash_pool).await?;
+                    }
+                    let left = resume.missing_length(&selection);
+                    (left, resume.peers())
+                }
+                None => (selection.wanted_length(), Vec::new()),
+            };
+
+            let stats = Arc::new(TransferStats::new(left));
+            let (discovery, peers_rx, options) =
+                PeerDiscovery::start(&torrent, &stats, network, known_peers).await?;
+            let saving = CancellationToken::new();
+            let saver = resume.clone().map(|resume| {
+                tokio::spawn(resume.run(storage.clone(), stats.clone(), saving.clone()))
+            });
+            let download = download_swarm(
+                torrent,
+                peers_rx,
+                storage,
+                stats,
+                SwarmOptions {
+                    hash_pool,
+                    selection: Some(selection),
+                    resume,
+                    ..options
+                },
+            );
+            let download_res = tokio::select! {
+                download_res = download => download_res,
+                _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("interrupted")),
+            };
+            saving.cancel();
+            if let Some(saver) = saver {
+                saver.await?;
+            }
+            discovery.stop(download_res.is_ok()).await?;
+            download_res?;
             println!(
                 "Downloaded {} to {}.",
                 filepath.display(),
                 outpath.display()
             );
         }
+        Command::Stream {
+            outpath,
+            filepath,
+            file,
+            readahead,
+            network,
+        } => {
+            let content = std::fs::read(&filepath)?;
+            let torrent = serde_bencode::from_bytes::<Torrent>(&content)
+                .context("Deserialize torrent file")?;
+            let torrent = Arc::new(torrent);
+
+            let selection = FileSelection::new(&torrent.info);
+            let spans = torrent.info.file_spans();
+            let mut files = (0..spans.len()).filter(|&i| !spans[i].padding);
+            let span_index = match &file {
+                Some(file) => files
+                    .find(|&i| selection.file_path(i) == *file)
+                    .with_context(|| format!("torrent has no file {file:?}"))?,
+                None => match (files.next(), files.next()) {
+                    (Some(span_index), None) => span_index,
+                    _ => anyhow::bail!("torrent has several files; pick one with --file"),
+                },
+            };
+            let path = selection.file_path(span_index);
+            let selection = selection.only(&[globset::escape(&path)])?;
+
+            let stats = Arc::new(TransferStats::new(selection.wanted_length()));
+            let (discovery, peers_rx, options) =
+                PeerDiscovery::start(&torrent, &stats, network, Vec::new()).await?;
+            let storage = FsStorage::with_selection(torrent.clone(), &outpath, &selection)
+                .with_context(|| format!("open {}", outpath.display()))?;
+            let storage: Arc<dyn Storage> = Arc::new(storage);
+
+            let streaming = StreamControl::new();
+            let mut reader = streaming
+                .open_file(storage.clone(), span_index)?
+                .readahead(readahead << 20);
+            let download = tokio::spawn(download_swarm(
+                torrent,
+                peers_rx,
+                storage,
+                stats,
+                SwarmOptions {
+                    selection: Some(selection),
+                    streaming: Some(streaming),
+                    ..options
+                },
+            ));
+            let copy_res = async {
+                let mut stdout = tokio::io::stdout();
+                tokio::io::copy(&mut reader, &mut stdout).await?;
+                stdout.flush().await
+            }
+            .await;
+            // Nobody is left to read what the download would fetch.
+            if copy_res.is_err() {
+                download.abort();
+            }
+            let download_res = match download.await {
+                Ok(download_res) => download_res,
+                Err(err) if err.is_cancelled() => Ok(()),
+                Err(err) => Err(err.into()),
+            };
+            discovery
+                .stop(copy_res.is_ok() && download_res.is_ok())
+                .await?;
+            copy_res.with_context(|| format!("stream {path}"))?;
+            download_res?;
+            eprintln!("Streamed {path} from {}.", filepath.display());
+        }
+        Command::Session {
+            outdir,
+            filepaths,
+            port,
+            max_connections,
+            download_limit,
+            network,
+        } => {
+            let dht = if network.no_dht {
+                None
+            } else {
+                let mut config = DhtConfig {
+                    state_path: network.dht_state,
+                    ..Default::default()
+                };
+                if !network.dht_nodes.is_empty() {
+                    config.bootstrap_nodes = network.dht_nodes;
+                }
+                Some(config)
+            };
+            let session = Session::start(SessionConfig {
+                listen_addr: SocketAddr::from(([0, 0, 0, 0], port)),
+                dht,
+                pex: !network.no_pex,
+                utp: !network.no_utp,
+                web_seeds: !network.no_web_seeds,
+                encryption: network.encryption,
+                max_connections,
+                download_limit: download_limit.map(|kib| kib << 10),
+                hashing_threads: None,
+            })
+            .await?;
+            for filepath in &filepaths {
+                let content = std::fs::read(filepath)?;
+                let torrent = serde_bencode::from_bytes::<Torrent>(&content)
+                    .with_context(|| format!("Deserialize {}", filepath.display()))?;
+                storage::check_plain_name(&torrent.info.name)
+                    .with_context(|| format!("name of {}", filepath.display()))?;
+                let outpath = outdir.join(&torrent.info.name);
+                session.add(torrent, outpath)?;
+            }
+            eprintln!("Listening for peers on {}.", session.local_addr());
+
+            let mut report = tokio::time::interval(Duration::from_secs(5));
+            let interrupted = loop {
+                tokio::select! {
+                    _ = report.tick() => {}
+                    _ = tokio::signal::ctrl_c() => break true,
+                }
+                let statuses = session.torrents();
+                for status in &statuses {
+                    eprintln!(
+                        "{}: {}, {} of {} bytes left",
+                        status.name, status.state, status.left, status.length
+                    );
+                }
+                let done = statuses.iter().all(|status| {
+                    matches!(
+                        status.state,
+                        TorrentState::Finished | TorrentState::Failed(_)
+                    )
+                });
+                if done {
+                    break false;
+                }
+            };
+            session.shutdown().await?;
+            anyhow::ensure!(!interrupted, "interrupted");
+            let failed = session
+                .torrents()
+                .iter()
+                .filter(|status| matches!(status.state, TorrentState::Failed(_)))
+                .count();
+            anyhow::ensure!(
+                failed == 0,
+                "{failed} of {} torrents failed",
+                filepaths.len()
+            );
+            println!(
+                "Downloaded {} torrents to {}.",
+                filepaths.len(),
+                outdir.display()
+            );
+        }
+        Command::Create {
+            outpath,
+            path,
+            trackers,
+            piece_length,
+            comment,
+            private,
+            web_seeds,
+            meta_version,
+            no_date,
+        } => {
+            let mut builder = TorrentBuilder::new(&path)
+                .private(private)
+                .meta_version(meta_version);
+            for tracker in trackers {
+                builder = builder.tracker(tracker);
+            }
+            for web_seed in web_seeds {
+                builder = builder.web_seed(web_seed);
+            }
+            if let Some(piece_length) = piece_length {
+                builder = builder.piece_length(piece_length);
+            }
+            if let Some(comment) = comment {
+                builder = builder.comment(comment);
+            }
+            if no_date {
+                builder = builder.creation_date(None);
+            }
+            let torrent = builder.build().await?;
+            let content = serde_bencode::to_bytes(&torrent).context("Serialize torrent file")?;
+            std::fs::write(&outpath, content)
+                .with_context(|| format!("write {}", outpath.display()))?;
+            println!("Created {}.", outpath.display());
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()));
+        }
+        Command::Tracker {
+            command: TrackerCommand::Serve { addr, interval },
+        } => {
+            let interval = Duration::from_secs(interval);
+            let server = TrackerServer::bind(TrackerServerConfig {
+                bind_addr: addr,
+                interval,
+                peer_ttl: interval * 2 + Duration::from_secs(60),
+            })
+            .await?;
+            println!("Tracker listening on {}", server.announce_url()?);
+
+            let shutdown = CancellationToken::new();
+            let server_task = tokio::spawn(server.run(shutdown.clone()));
+            tokio::signal::ctrl_c().await?;
+            shutdown.cancel();
+            server_task.await?;
+        }
     }
     Ok(())
 }
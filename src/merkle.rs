@@ -0,0 +1,75 @@
+use sha2::{Digest, Sha256};
+
+/// In BitTorrent v2 (BEP 52) every file has a SHA-256 merkle tree whose leaves are the hashes
+/// of its blocks of this size, padded with zero hashes up to a power of two. The layer whose
+/// nodes each cover one piece is the file's piece layer, and the root is its `pieces root`.
+pub const BLOCK_SIZE: usize = 1 << 14;
+
+pub type Hash = [u8; 32];
+
+pub fn sha256(bytes: &[u8]) -> Hash {
+    Sha256::digest(bytes).into()
+}
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of a tree with `width` leaves, the power of two the given `layer` is padded to.
+/// `pad` is the hash standing in for missing nodes at the layer's height.
+pub fn root_of_layer(layer: &[Hash], width: usize, mut pad: Hash) -> Hash {
+    debug_assert!(width.is_power_of_two() && layer.len() <= width);
+    let mut layer = layer.to_vec();
+    let mut width = width;
+    while width > 1 {
+        layer = (0..width / 2)
+            .map(|i| {
+                let left = layer.get(2 * i).unwrap_or(&pad);
+                let right = layer.get(2 * i + 1).unwrap_or(&pad);
+                hash_pair(left, right)
+            })
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+/// Hash of a subtree of `blocks` zero leaves.
+fn pad_hash(blocks: usize) -> Hash {
+    root_of_layer(&[], blocks, [0; 32])
+}
+
+/// Root of the tree over the blocks of `data`, padded to `width_blocks` leaves.
+pub fn subtree_root(data: &[u8], width_blocks: usize) -> Hash {
+    let leaves: Vec<Hash> = data.chunks(BLOCK_SIZE).map(sha256).collect();
+    root_of_layer(&leaves, width_blocks, [0; 32])
+}
+
+/// How many leaves the piece hash of a piece in a file of `file_length` bytes covers. Files no
+/// larger than a piece have no piece layer, their single piece hashes straight to the root.
+pub fn piece_width(file_length: usize, piece_length: usize) -> usize {
+    if file_length <= piece_length {
+        file_length.div_ceil(BLOCK_SIZE).next_power_of_two()
+    } else {
+        piece_length / BLOCK_SIZE
+    }
+}
+
+/// Hash of piece `data`, the last one possibly short, of a file of `file_length` bytes.
+pub fn piece_hash(data: &[u8], file_length: usize, piece_length: usize) -> Hash {
+    subtree_root(data, piece_width(file_length, piece_length))
+}
+
+/// The file's `pieces root` given its piece layer.
+pub fn root_from_piece_layer(piece_layer: &[Hash], piece_length: usize) -> Hash {
+    let blocks_per_piece = piece_length / BLOCK_SIZE;
+    root_of_layer(
+        piece_layer,
+        piece_layer.len().next_power_of_two(),
+        pad_hash(blocks_per_piece),
+    )
+}
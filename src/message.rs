@@ -88,6 +88,13 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Port = 9,
+    Suggest = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
 }
 
 #[derive(Debug, Clone)]
@@ -160,6 +167,13 @@ impl Decoder for MessageFramer {
             6 => MessageTag::Request,
             7 => MessageTag::Piece,
             8 => MessageTag::Cancel,
+            9 => MessageTag::Port,
+            13 => MessageTag::Suggest,
+            14 => MessageTag::HaveAll,
+            15 => MessageTag::HaveNone,
+            16 => MessageTag::RejectRequest,
+            17 => MessageTag::AllowedFast,
+            20 => MessageTag::Extended,
             tag => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
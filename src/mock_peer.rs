@@ -0,0 +1,393 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use serde_bytes::ByteBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+use tokio_util::codec::Framed;
+
+use crate::handshake::{perform_handshake, Extensions};
+use crate::message::{Message, MessageFramer, MessageTag, RequestMessagePayload};
+use crate::storage::{MemoryStorage, Storage};
+use crate::torrent::{Info, Torrent};
+use crate::utils::compute_hash;
+
+/// Test content of `len` bytes, different for every `seed`, that does not repeat from piece to
+/// piece.
+pub fn test_data(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + seed + i / 300) as u8).collect()
+}
+
+/// Builds a single-file torrent describing `data`, for serving with a [`MockPeer`].
+pub fn torrent_for(name: &str, data: &[u8], piece_length: usize) -> Torrent {
+    let pieces = data
+        .chunks(piece_length)
+        .flat_map(|piece| compute_hash(&piece.to_vec()))
+        .collect::<Vec<u8>>();
+    Torrent {
+        announce: String::from("http://127.0.0.1/announce"),
+        info: Info {
+            length: Some(data.len()),
+            name: name.to_string(),
+            piece_length,
+            pieces: ByteBuf::from(pieces),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// Memory storage recording the blocks read from and written to it, as piece index, offset and
+/// length.
+pub struct RecordingStorage {
+    memory: MemoryStorage,
+    reads: Mutex<Vec<(usize, usize, usize)>>,
+    writes: Mutex<Vec<(usize, usize, usize)>>,
+}
+
+impl RecordingStorage {
+    pub fn new(torrent: Arc<Torrent>) -> Self {
+        Self {
+            memory: MemoryStorage::new(torrent),
+            reads: Mutex::new(Vec::new()),
+            writes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Everything written so far, with gaps read as zeros.
+    pub fn contents(&self) -> Vec<u8> {
+        self.memory.contents()
+    }
+
+    /// The blocks read since the last call.
+    pub fn take_reads(&self) -> Vec<(usize, usize, usize)> {
+        std::mem::take(&mut self.reads.lock().expect("reads lock is not poisoned"))
+    }
+
+    /// The blocks written since the last call.
+    pub fn take_writes(&self) -> Vec<(usize, usize, usize)> {
+        std::mem::take(&mut self.writes.lock().expect("writes lock is not poisoned"))
+    }
+
+    /// The pieces whose first block has been written, in order.
+    pub fn pieces_started(&self) -> Vec<usize> {
+        let writes = self.writes.lock().expect("writes lock is not poisoned");
+        writes
+            .iter()
+            .filter(|&&(_, begin, _)| begin == 0)
+            .map(|&(piece_index, _, _)| piece_index)
+            .collect()
+    }
+}
+
+impl Storage for RecordingStorage {
+    fn torrent(&self) -> &Torrent {
+        self.memory.torrent()
+    }
+
+    fn read_block(&self, piece_index: usize, begin: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        self.reads
+            .lock()
+            .expect("reads lock is not poisoned")
+            .push((piece_index, begin, buf.len()));
+        self.memory.read_block(piece_index, begin, buf)
+    }
+
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> std::io::Result<()> {
+        self.writes
+            .lock()
+            .expect("writes lock is not poisoned")
+            .push((piece_index, begin, data.len()));
+        self.memory.write_block(piece_index, begin, data)
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Something a [`MockPeer`] does once it has served a given number of blocks on a connection.
+#[derive(Debug, Clone)]
+pub enum MockAction {
+    /// Chokes the downloader, unchoking it again after the given time.
+    Choke(Duration),
+    /// Announces a piece that was withheld from the bitfield.
+    Have(usize),
+    /// Rejects the next request, which takes the Fast extension.
+    RejectNext,
+    /// Hangs up.
+    Drop,
+}
+
+/// A seeder for tests, serving a torrent's data from storage and misbehaving on cue.
+#[derive(Clone)]
+pub struct MockPeer {
+    torrent: Arc<Torrent>,
+    storage: Arc<dyn Storage>,
+    fast: bool,
+    block_delay: Duration,
+    corrupt_pieces: HashSet<usize>,
+    withheld_pieces: HashSet<usize>,
+    allowed_fast: HashSet<usize>,
+    stay_choked: bool,
+    reject_requests: bool,
+    script: Vec<(usize, MockAction)>,
+    // Shared by clones, so it counts across every connection.
+    blocks_served: Arc<AtomicUsize>,
+}
+
+impl MockPeer {
+    /// Serves `data`, the torrent's content laid out as its pieces cover it.
+    pub fn new(torrent: Arc<Torrent>, data: impl AsRef<[u8]>) -> Self {
+        let data = data.as_ref();
+        let storage = MemoryStorage::new(torrent.clone());
+        for piece_index in 0..torrent.info.piece_count() {
+            let start = piece_index * torrent.info.piece_length;
+            let piece = &data[start..start + torrent.info.piece_size(piece_index)];
+            storage
+                .write_block(piece_index, 0, piece)
+                .expect("data covers the torrent");
+        }
+        Self::seeding(Arc::new(storage))
+    }
+
+    /// Serves whatever `storage` holds for its torrent.
+    pub fn seeding(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            torrent: Arc::new(storage.torrent().clone()),
+            storage,
+            fast: false,
+            block_delay: Duration::ZERO,
+            corrupt_pieces: HashSet::new(),
+            withheld_pieces: HashSet::new(),
+            allowed_fast: HashSet::new(),
+            stay_choked: false,
+            reject_requests: false,
+            script: Vec::new(),
+            blocks_served: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Advertises the Fast extension, which makes the peer reject requests while choking.
+    pub fn fast(mut self) -> Self {
+        self.fast = true;
+        self
+    }
+
+    /// Waits this long before sending each block.
+    pub fn delay_blocks(mut self, delay: Duration) -> Self {
+        self.block_delay = delay;
+        self
+    }
+
+    /// Flips bits in every block of `piece_index`, so the piece fails its hash check.
+    pub fn corrupt_piece(mut self, piece_index: usize) -> Self {
+        self.corrupt_pieces.insert(piece_index);
+        self
+    }
+
+    /// Leaves `piece_index` out of the bitfield; requests for it go unanswered until a
+    /// [`MockAction::Have`] announces it.
+    pub fn withhold_piece(mut self, piece_index: usize) -> Self {
+        self.withheld_pieces.insert(piece_index);
+        self
+    }
+
+    /// Lets the downloader fetch `piece_index` while choked, which takes the Fast extension.
+    pub fn allow_fast(mut self, piece_index: usize) -> Self {
+        self.allowed_fast.insert(piece_index);
+        self
+    }
+
+    /// Never unchokes the downloader, leaving it only the allowed fast pieces.
+    pub fn stay_choked(mut self) -> Self {
+        self.stay_choked = true;
+        self
+    }
+
+    /// Unchokes the downloader but rejects every request, which takes the Fast extension.
+    pub fn reject_requests(mut self) -> Self {
+        self.reject_requests = true;
+        self
+    }
+
+    /// Blocks sent so far, over every connection of this peer and its clones.
+    pub fn blocks_served(&self) -> usize {
+        self.blocks_served.load(Ordering::Relaxed)
+    }
+
+    /// Performs `action` once `blocks` blocks have been served on a connection.
+    pub fn after_blocks(mut self, blocks: usize, action: MockAction) -> Self {
+        self.script.push((blocks, action));
+        self
+    }
+
+    /// Accepts connections on `addr` in the background, serving each of them. Returns the
+    /// address actually bound.
+    pub async fn listen(self, addr: SocketAddr) -> anyhow::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).await.context("bind mock peer")?;
+        let local_addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let peer = self.clone();
+                tokio::spawn(async move { peer.serve(stream).await });
+            }
+        });
+        Ok(local_addr)
+    }
+
+    /// Connects to a downloader accepting peers on `addr` and serves it over that one connection.
+    pub async fn connect(self, addr: SocketAddr) -> anyhow::Result<()> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .context("connect to downloader")?;
+        self.serve(stream).await
+    }
+
+    /// Serves a single connection until the downloader hangs up or the script drops it.
+    pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: S,
+    ) -> anyhow::Result<()> {
+        let extensions = Extensions {
+            fast: self.fast,
+            ..Default::default()
+        };
+        let handshake = perform_handshake(&self.torrent, &mut stream, extensions).await?;
+        anyhow::ensure!(
+            handshake.info_hash == self.torrent.info_hash(),
+            "downloader asked for another torrent"
+        );
+        let fast = self.fast && handshake.supports_fast();
+        let mut framed = Framed::new(stream, MessageFramer);
+
+        let mut withheld = self.withheld_pieces.clone();
+        let piece_count = self.torrent.info.piece_count();
+        if fast && withheld.is_empty() {
+            send(&mut framed, MessageTag::HaveAll, Vec::new()).await?;
+        } else {
+            let mut bitfield = vec![0u8; piece_count.div_ceil(8)];
+            for piece_index in (0..piece_count).filter(|i| !withheld.contains(i)) {
+                bitfield[piece_index / 8] |= 0x80 >> (piece_index % 8);
+            }
+            send(&mut framed, MessageTag::Bitfield, bitfield).await?;
+        }
+        if fast {
+            for &piece_index in &self.allowed_fast {
+                let payload = (piece_index as u32).to_be_bytes().to_vec();
+                send(&mut framed, MessageTag::AllowedFast, payload).await?;
+            }
+        }
+
+        let mut interested = false;
+        let mut unchoke_at: Option<Instant> = None;
+        let mut served = 0;
+        let mut reject_next = false;
+        loop {
+            let msg = tokio::select! {
+                msg = framed.next() => match msg {
+                    Some(msg) => msg.context("invalid message from downloader")?,
+                    None => return Ok(()),
+                },
+                _ = sleep_until(unchoke_at) => {
+                    unchoke_at = None;
+                    if interested {
+                        send(&mut framed, MessageTag::Unchoke, Vec::new()).await?;
+                    }
+                    continue;
+                }
+            };
+            match msg.tag {
+                MessageTag::Interested => {
+                    interested = true;
+                    if unchoke_at.is_none() && !self.stay_choked {
+                        send(&mut framed, MessageTag::Unchoke, Vec::new()).await?;
+                    }
+                }
+                MessageTag::Request => {
+                    let payload: [u8; 12] = msg.payload[..]
+                        .try_into()
+                        .context("request must carry 12 bytes")?;
+                    let (piece_index, begin, length) = read_request(&payload);
+                    let choked = self.stay_choked || unchoke_at.is_some();
+                    if (choked && !self.allowed_fast.contains(&piece_index))
+                        || withheld.contains(&piece_index)
+                        || self.reject_requests
+                        || std::mem::take(&mut reject_next)
+                    {
+                        if fast {
+                            send(&mut framed, MessageTag::RejectRequest, payload.to_vec()).await?;
+                        }
+                        continue;
+                    }
+                    let mut block = vec![0; length];
+                    self.storage
+                        .read_block(piece_index, begin, &mut block)
+                        .context("read requested block")?;
+                    if self.corrupt_pieces.contains(&piece_index) {
+                        block.iter_mut().for_each(|byte| *byte ^= 0xff);
+                    }
+                    tokio::time::sleep(self.block_delay).await;
+                    let mut piece = Vec::with_capacity(8 + block.len());
+                    piece.extend((piece_index as u32).to_be_bytes());
+                    piece.extend((begin as u32).to_be_bytes());
+                    piece.extend(block);
+                    send(&mut framed, MessageTag::Piece, piece).await?;
+                    served += 1;
+                    self.blocks_served.fetch_add(1, Ordering::Relaxed);
+
+                    for (_, action) in self.script.iter().filter(|(after, _)| *after == served) {
+                        match action {
+                            MockAction::Choke(duration) => {
+                                send(&mut framed, MessageTag::Choke, Vec::new()).await?;
+                                unchoke_at = Some(Instant::now() + *duration);
+                            }
+                            MockAction::Have(piece_index) => {
+                                withheld.remove(piece_index);
+                                let payload = (*piece_index as u32).to_be_bytes().to_vec();
+                                send(&mut framed, MessageTag::Have, payload).await?;
+                            }
+                            MockAction::RejectNext => reject_next = true,
+                            MockAction::Drop => return Ok(()),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn read_request(payload: &[u8; 12]) -> (usize, usize, usize) {
+    let mut request = RequestMessagePayload::new(0, 0, 0);
+    request.as_bytes_mut().copy_from_slice(payload);
+    (
+        request.index() as usize,
+        request.begin() as usize,
+        request.length() as usize,
+    )
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn send<S: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<S, MessageFramer>,
+    tag: MessageTag,
+    payload: Vec<u8>,
+) -> anyhow::Result<()> {
+    framed
+        .send(Message { tag, payload })
+        .await
+        .with_context(|| format!("send {tag:?} message"))
+}
@@ -0,0 +1,449 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+
+use anyhow::Context;
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The 768-bit safe prime every MSE implementation shares.
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+const KEY_LEN: usize = 96;
+const MAX_PAD_LEN: usize = 512;
+/// Verification constant; its encrypted form marks where the encrypted headers start.
+const VC: [u8; 8] = [0; 8];
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// Whether connections use Message Stream Encryption.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EncryptionPolicy {
+    /// Never encrypt, and turn encrypted incoming connections away.
+    Plaintext,
+    /// Encrypt when the other side supports it, and fall back to plaintext otherwise.
+    #[default]
+    Prefer,
+    /// Only talk to peers that encrypt.
+    Require,
+}
+
+/// RC4 with the first kilobyte of keystream discarded, as MSE requires.
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        let mut rc4 = Self { state, i: 0, j: 0 };
+        rc4.apply(&mut [0u8; 1024]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// One side of the Diffie-Hellman exchange.
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let prime = prime();
+        let private = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+        let public = BigUint::from(GENERATOR).modpow(&private, &prime);
+        Self {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    fn shared_secret(&self, remote_public: &[u8]) -> [u8; KEY_LEN] {
+        let remote = BigUint::from_bytes_be(remote_public);
+        to_key_bytes(&remote.modpow(&self.private, &prime()))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).expect("MSE prime is valid hex")
+}
+
+fn to_key_bytes(value: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD_LEN);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+/// Reads from a stream during the handshake, keeping whatever arrived past the part being parsed.
+struct HandshakeReader<'a, S> {
+    stream: &'a mut S,
+    buf: Vec<u8>,
+}
+
+impl<'a, S: AsyncRead + Unpin> HandshakeReader<'a, S> {
+    async fn fill(&mut self, len: usize) -> anyhow::Result<()> {
+        let mut chunk = [0u8; 1024];
+        while self.buf.len() < len {
+            let n = self.stream.read(&mut chunk).await?;
+            anyhow::ensure!(n > 0, "peer closed the connection during the MSE handshake");
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok(())
+    }
+
+    async fn take(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+        self.fill(len).await?;
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    /// Discards everything up to and including `pattern`, which must show up within `max_skip`
+    /// bytes.
+    async fn skip_past(&mut self, pattern: &[u8], max_skip: usize) -> anyhow::Result<()> {
+        let mut chunk = [0u8; 1024];
+        loop {
+            if let Some(pos) = self
+                .buf
+                .windows(pattern.len())
+                .position(|window| window == pattern)
+            {
+                self.buf.drain(..pos + pattern.len());
+                return Ok(());
+            }
+            anyhow::ensure!(
+                self.buf.len() < max_skip + pattern.len(),
+                "MSE synchronisation marker not found"
+            );
+            let n = self.stream.read(&mut chunk).await?;
+            anyhow::ensure!(n > 0, "peer closed the connection during the MSE handshake");
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+/// A connection wrapped in Message Stream Encryption (MSE/PE). Once the handshake is done it is
+/// a plain byte stream, so the BitTorrent handshake and message framing run on top unchanged.
+pub struct MseStream<S> {
+    inner: S,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    // Payload that arrived along with the handshake, already decrypted.
+    read_buf: Vec<u8>,
+    // Encrypted bytes accepted by `poll_write` but not yet written to `inner`.
+    write_buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> MseStream<S> {
+    /// Performs the initiating side of the handshake. With [`EncryptionPolicy::Plaintext`] no
+    /// handshake takes place and the stream is passed through as is.
+    pub async fn connect(
+        mut stream: S,
+        info_hash: &[u8; 20],
+        policy: EncryptionPolicy,
+    ) -> anyhow::Result<Self> {
+        let crypto_provide = match policy {
+            EncryptionPolicy::Plaintext => return Ok(Self::plaintext(stream, Vec::new())),
+            EncryptionPolicy::Prefer => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Require => CRYPTO_RC4,
+        };
+
+        let keys = KeyPair::generate();
+        let mut msg = keys.public.to_vec();
+        msg.extend(random_pad());
+        stream
+            .write_all(&msg)
+            .await
+            .context("send MSE public key")?;
+
+        let mut reader = HandshakeReader {
+            stream: &mut stream,
+            buf: Vec::new(),
+        };
+        let remote_public = reader.take(KEY_LEN).await.context("read MSE public key")?;
+        let secret = keys.shared_secret(&remote_public);
+        let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+        let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+        let mut msg = Vec::new();
+        msg.extend(hash(&[b"req1", &secret]));
+        msg.extend(xor(
+            &hash(&[b"req2", info_hash]),
+            &hash(&[b"req3", &secret]),
+        ));
+        let pad = random_pad();
+        let mut headers = VC.to_vec();
+        headers.extend(crypto_provide.to_be_bytes());
+        headers.extend((pad.len() as u16).to_be_bytes());
+        headers.extend(pad);
+        // No initial payload: the BitTorrent handshake follows through the stream.
+        headers.extend(0u16.to_be_bytes());
+        encrypt.apply(&mut headers);
+        msg.extend(headers);
+        reader
+            .stream
+            .write_all(&msg)
+            .await
+            .context("send MSE crypto offer")?;
+
+        // The responder's padding hides where its answer starts, so look for the encrypted VC.
+        let mut vc = VC;
+        decrypt.apply(&mut vc);
+        reader
+            .skip_past(&vc, MAX_PAD_LEN)
+            .await
+            .context("read MSE crypto selection")?;
+        let mut headers = reader.take(6).await?;
+        decrypt.apply(&mut headers);
+        let crypto_select = u32::from_be_bytes(headers[..4].try_into().expect("4 bytes"));
+        let pad_len = u16::from_be_bytes([headers[4], headers[5]]) as usize;
+        anyhow::ensure!(pad_len <= MAX_PAD_LEN, "MSE padding is too long");
+        let mut pad = reader.take(pad_len).await?;
+        decrypt.apply(&mut pad);
+        let mut rest = std::mem::take(&mut reader.buf);
+
+        match crypto_select {
+            CRYPTO_RC4 => {
+                decrypt.apply(&mut rest);
+                Ok(Self {
+                    inner: stream,
+                    read_cipher: Some(decrypt),
+                    write_cipher: Some(encrypt),
+                    read_buf: rest,
+                    write_buf: Vec::new(),
+                })
+            }
+            CRYPTO_PLAINTEXT if crypto_provide & CRYPTO_PLAINTEXT != 0 => {
+                Ok(Self::plaintext(stream, rest))
+            }
+            other => anyhow::bail!("peer selected an unsupported MSE method {other:#x}"),
+        }
+    }
+
+    /// Performs the receiving side of the handshake for a connection that may be for any of
+    /// `info_hashes`. Returns the info hash the peer asked for, or `None` if it connected in
+    /// plaintext and the BitTorrent handshake has yet to tell.
+    pub async fn accept(
+        mut stream: S,
+        info_hashes: &[[u8; 20]],
+        policy: EncryptionPolicy,
+    ) -> anyhow::Result<(Self, Option<[u8; 20]>)> {
+        let mut reader = HandshakeReader {
+            stream: &mut stream,
+            buf: Vec::new(),
+        };
+        reader.fill(20).await?;
+        if reader.buf.starts_with(b"\x13BitTorrent protocol") {
+            anyhow::ensure!(
+                policy != EncryptionPolicy::Require,
+                "peer did not encrypt the connection"
+            );
+            let received = std::mem::take(&mut reader.buf);
+            return Ok((Self::plaintext(stream, received), None));
+        }
+        anyhow::ensure!(
+            policy != EncryptionPolicy::Plaintext,
+            "peer tried to encrypt the connection"
+        );
+
+        let remote_public = reader.take(KEY_LEN).await.context("read MSE public key")?;
+        let keys = KeyPair::generate();
+        let mut msg = keys.public.to_vec();
+        msg.extend(random_pad());
+        reader
+            .stream
+            .write_all(&msg)
+            .await
+            .context("send MSE public key")?;
+        let secret = keys.shared_secret(&remote_public);
+
+        reader
+            .skip_past(&hash(&[b"req1", &secret]), MAX_PAD_LEN)
+            .await
+            .context("read MSE crypto offer")?;
+        let skey_hash: [u8; 20] = reader.take(20).await?.try_into().expect("20 bytes");
+        let req3 = hash(&[b"req3", &secret]);
+        let info_hash = *info_hashes
+            .iter()
+            .find(|info_hash| xor(&hash(&[b"req2", &info_hash[..]]), &req3) == skey_hash)
+            .context("peer asked for a torrent we do not have")?;
+        let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+        let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+        let mut headers = reader.take(14).await?;
+        decrypt.apply(&mut headers);
+        anyhow::ensure!(headers[..8] == VC, "MSE verification constant mismatch");
+        let crypto_provide = u32::from_be_bytes(headers[8..12].try_into().expect("4 bytes"));
+        let pad_len = u16::from_be_bytes([headers[12], headers[13]]) as usize;
+        anyhow::ensure!(pad_len <= MAX_PAD_LEN, "MSE padding is too long");
+        let mut pad = reader.take(pad_len + 2).await?;
+        decrypt.apply(&mut pad);
+        let payload_len = u16::from_be_bytes([pad[pad_len], pad[pad_len + 1]]) as usize;
+        let mut payload = reader.take(payload_len).await?;
+        decrypt.apply(&mut payload);
+        let mut rest = std::mem::take(&mut reader.buf);
+
+        let crypto_select = if crypto_provide & CRYPTO_RC4 != 0 {
+            CRYPTO_RC4
+        } else if crypto_provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Require {
+            CRYPTO_PLAINTEXT
+        } else {
+            anyhow::bail!("peer offered no acceptable MSE method ({crypto_provide:#x})");
+        };
+        let mut msg = VC.to_vec();
+        msg.extend(crypto_select.to_be_bytes());
+        msg.extend(0u16.to_be_bytes());
+        encrypt.apply(&mut msg);
+        stream
+            .write_all(&msg)
+            .await
+            .context("send MSE crypto selection")?;
+
+        // The initial payload was encrypted either way; only what follows depends on the choice.
+        let stream = if crypto_select == CRYPTO_RC4 {
+            decrypt.apply(&mut rest);
+            payload.extend(rest);
+            Self {
+                inner: stream,
+                read_cipher: Some(decrypt),
+                write_cipher: Some(encrypt),
+                read_buf: payload,
+                write_buf: Vec::new(),
+            }
+        } else {
+            payload.extend(rest);
+            Self::plaintext(stream, payload)
+        };
+        Ok((stream, Some(info_hash)))
+    }
+}
+
+impl<S> MseStream<S> {
+    fn plaintext(inner: S, read_buf: Vec<u8>) -> Self {
+        Self {
+            inner,
+            read_cipher: None,
+            write_cipher: None,
+            read_buf,
+            write_buf: Vec::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_write_buf(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.read_buf.is_empty() {
+            let n = this.read_buf.len().min(buf.remaining());
+            buf.put_slice(&this.read_buf[..n]);
+            this.read_buf.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.read_cipher {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // Bytes are encrypted as soon as they are accepted, so anything left over from the
+        // previous write has to go out first.
+        ready!(this.poll_write_buf(cx))?;
+        let mut encrypted = buf.to_vec();
+        if let Some(cipher) = &mut this.write_cipher {
+            cipher.apply(&mut encrypted);
+        }
+        this.write_buf = encrypted;
+        if let Poll::Ready(Err(err)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
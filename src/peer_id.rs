@@ -0,0 +1,40 @@
+use std::sync::OnceLock;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+/// Two-letter client code used in the Azureus-style peer id prefix, e.g. `-BR0100-`.
+const CLIENT_CODE: &str = "BR";
+
+static PEER_ID: OnceLock<[u8; 20]> = OnceLock::new();
+static TRACKER_KEY: OnceLock<u32> = OnceLock::new();
+
+/// Builds an Azureus-style peer id: `-`, the client code, four version digits, `-`, followed by
+/// twelve random alphanumeric characters.
+pub fn generate_peer_id() -> [u8; 20] {
+    let version: String = env!("CARGO_PKG_VERSION")
+        .split('.')
+        .map(|part| part.parse::<u32>().unwrap_or(0).min(9).to_string())
+        .chain(std::iter::repeat(String::from("0")))
+        .take(4)
+        .collect();
+    let prefix = format!("-{CLIENT_CODE}{version}-");
+
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(prefix.as_bytes());
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .zip(&mut peer_id[8..])
+        .for_each(|(byte, slot)| *slot = byte);
+    peer_id
+}
+
+/// The peer id of this process, shared by tracker announces and peer handshakes.
+pub fn local_peer_id() -> &'static [u8; 20] {
+    PEER_ID.get_or_init(generate_peer_id)
+}
+
+/// Random `key` sent on announces so trackers can recognise us across IP changes.
+pub fn tracker_key() -> u32 {
+    *TRACKER_KEY.get_or_init(rand::random)
+}
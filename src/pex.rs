@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+/// Peers must not send `ut_pex` messages more often than this.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Most peers added or dropped in a single message.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// A `ut_pex` message. Peers are compact addresses, with one flag byte per added peer.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    pub added_flags: ByteBuf,
+    #[serde(default)]
+    pub dropped: ByteBuf,
+    #[serde(default)]
+    pub added6: ByteBuf,
+    #[serde(rename = "added6.f", default)]
+    pub added6_flags: ByteBuf,
+    #[serde(default)]
+    pub dropped6: ByteBuf,
+}
+
+pub(crate) fn encode_addrs<'a>(
+    addrs: impl Iterator<Item = &'a SocketAddr>,
+    v4: &mut Vec<u8>,
+    v6: &mut Vec<u8>,
+) {
+    for addr in addrs {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                v4.extend(ip.octets());
+                v4.extend(addr.port().to_be_bytes());
+            }
+            IpAddr::V6(ip) => {
+                v6.extend(ip.octets());
+                v6.extend(addr.port().to_be_bytes());
+            }
+        }
+    }
+}
+
+pub(crate) fn decode_addrs(v4: &[u8], v6: &[u8]) -> Vec<SocketAddr> {
+    let v4 = v4.chunks_exact(6).map(|chunk| {
+        let ip: [u8; 4] = chunk[..4].try_into().expect("chunk has 6 bytes");
+        let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+        SocketAddr::new(Ipv4Addr::from(ip).into(), port)
+    });
+    let v6 = v6.chunks_exact(18).map(|chunk| {
+        let ip: [u8; 16] = chunk[..16].try_into().expect("chunk has 18 bytes");
+        let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+        SocketAddr::new(Ipv6Addr::from(ip).into(), port)
+    });
+    v4.chain(v6).collect()
+}
+
+impl PexMessage {
+    pub fn new(added: &[SocketAddr], dropped: &[SocketAddr]) -> Self {
+        let mut msg = Self::default();
+        encode_addrs(added.iter(), &mut msg.added, &mut msg.added6);
+        encode_addrs(dropped.iter(), &mut msg.dropped, &mut msg.dropped6);
+        // We know nothing about the peers' capabilities, so no flags are set.
+        msg.added_flags = ByteBuf::from(vec![0; msg.added.len() / 6]);
+        msg.added6_flags = ByteBuf::from(vec![0; msg.added6.len() / 18]);
+        msg
+    }
+
+    pub fn added_peers(&self) -> Vec<SocketAddr> {
+        decode_addrs(&self.added, &self.added6)
+    }
+
+    pub fn dropped_peers(&self) -> Vec<SocketAddr> {
+        decode_addrs(&self.dropped, &self.dropped6)
+    }
+}
+
+/// Tracks what we already told one peer, so each message only carries the difference, and
+/// when it last sent us a message.
+#[derive(Debug, Default)]
+pub struct PexState {
+    advertised: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexState {
+    /// Whether a message that just arrived from the peer should be looked at. Peers sending
+    /// more often than `PEX_INTERVAL` are ignored until the interval has passed.
+    pub fn accept_incoming(&mut self) -> bool {
+        if self
+            .last_received
+            .is_some_and(|last_received| last_received.elapsed() < PEX_INTERVAL)
+        {
+            return false;
+        }
+        self.last_received = Some(Instant::now());
+        true
+    }
+
+    /// The message to send to `peer` given the peers we are currently connected to, or `None`
+    /// if it is too early or nothing changed.
+    pub fn next_message(
+        &mut self,
+        live_peers: &HashSet<SocketAddr>,
+        peer: SocketAddr,
+    ) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last_sent| last_sent.elapsed() < PEX_INTERVAL)
+        {
+            return None;
+        }
+        let added: Vec<SocketAddr> = live_peers
+            .iter()
+            .filter(|addr| **addr != peer && !self.advertised.contains(addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .advertised
+            .iter()
+            .filter(|addr| !live_peers.contains(addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        self.advertised.extend(&added);
+        dropped.iter().for_each(|addr| {
+            self.advertised.remove(addr);
+        });
+        self.last_sent = Some(Instant::now());
+        Some(PexMessage::new(&added, &dropped))
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+/// Caps the bytes per second transferred by everything holding a clone, as a token bucket
+/// holding up to a second's worth of bytes.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bytes_per_second: usize,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // Negative while callers are being held back for bytes they already took.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: usize) -> Self {
+        let bytes_per_second = bytes_per_second.max(1);
+        Self {
+            bytes_per_second,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_second as f64,
+                refilled: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> usize {
+        self.bytes_per_second
+    }
+
+    /// Waits until `bytes` more fit within the rate. Callers are let through in the order they
+    /// asked.
+    pub async fn acquire(&self, bytes: usize) {
+        let rate = self.bytes_per_second as f64;
+        // Held while waiting, so that later callers queue up behind this one.
+        let mut bucket = self.bucket.lock().await;
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate);
+        bucket.refilled = now;
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-bucket.tokens / rate)).await;
+        }
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio_util::sync::CancellationToken;
+
+use crate::download::TransferStats;
+use crate::hash_pool::HashPool;
+use crate::pex::{decode_addrs, encode_addrs};
+use crate::selection::FileSelection;
+use crate::storage::{file_path, PartFile, Storage};
+use crate::torrent::{FileSpan, Torrent};
+
+/// How often resume data is saved while downloading.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Most peers remembered for the next run.
+const MAX_SAVED_PEERS: usize = 100;
+/// The size blocks are requested in, which partial pieces are tracked by.
+const BLOCK_LENGTH: usize = 1 << 14;
+
+/// The bencoded contents of a resume file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(rename = "info-hash")]
+    pub info_hash: ByteBuf,
+    /// Verified pieces, laid out as in a bitfield message.
+    pub pieces: ByteBuf,
+    /// Size and modification time of each span's own file when the data was saved, in the order
+    /// of [`Info::file_spans`].
+    pub files: Vec<FileStamp>,
+    /// Spans whose data was kept in the part file.
+    #[serde(default)]
+    pub skipped: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub part: Option<FileStamp>,
+    /// Pieces some blocks of which were written but that were not yet verified.
+    #[serde(default)]
+    pub partial: Vec<PartialPiece>,
+    /// Peers we were connected to, as compact addresses.
+    #[serde(default)]
+    pub peers: ByteBuf,
+    #[serde(default)]
+    pub peers6: ByteBuf,
+    /// Bytes transferred over every run so far. Nothing is uploaded yet, but the total is kept
+    /// for when it is.
+    #[serde(default)]
+    pub uploaded: u64,
+    #[serde(default)]
+    pub downloaded: u64,
+}
+
+/// What tells us a file was left alone since resume data was saved. Missing files have a zero
+/// stamp.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub mtime: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PartialPiece {
+    pub piece: usize,
+    /// The 16 KiB blocks written, laid out as a bitfield.
+    pub blocks: ByteBuf,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> io::Result<Self> {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err),
+        };
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Self {
+            size: metadata.len(),
+            mtime: mtime.as_nanos() as u64,
+        })
+    }
+}
+
+fn to_bitfield(bits: &[bool]) -> Vec<u8> {
+    let mut bitfield = vec![0; bits.len().div_ceil(8)];
+    for (i, _) in bits.iter().enumerate().filter(|(_, &bit)| bit) {
+        bitfield[i / 8] |= 0x80 >> (i % 8);
+    }
+    bitfield
+}
+
+fn from_bitfield(bitfield: &[u8], len: usize) -> Vec<bool> {
+    (0..len)
+        .map(|i| {
+            bitfield
+                .get(i / 8)
+                .is_some_and(|byte| byte & (0x80 >> (i % 8)) != 0)
+        })
+        .collect()
+}
+
+/// Progress of a download that outlives it: which pieces are on disk, which blocks of the rest
+/// were written, which peers we knew and how much was transferred. Saved to a resume file and
+/// checked against the files on start-up, so only files changed in between are hashed again.
+///
+/// Clones share the same state; hand one to the download through
+/// [`SwarmOptions::resume`](crate::download::SwarmOptions::resume).
+#[derive(Clone)]
+pub struct ResumeState {
+    shared: Arc<ResumeShared>,
+}
+
+struct ResumeShared {
+    torrent: Arc<Torrent>,
+    path: PathBuf,
+    root: PathBuf,
+    spans: Vec<FileSpan>,
+    skipped: Vec<usize>,
+    progress: Mutex<Progress>,
+}
+
+struct Progress {
+    have: Vec<bool>,
+    partial: HashMap<usize, Vec<bool>>,
+    peers: Vec<SocketAddr>,
+    // Totals of earlier runs.
+    uploaded: u64,
+    downloaded: u64,
+    // What the transfer stats had counted up and down before this run, which the earlier runs
+    // cover.
+    stats_baseline: (usize, usize),
+    // Pieces of changed files still to be hashed.
+    recheck: Vec<usize>,
+}
+
+impl ResumeState {
+    /// Reads the resume file at `path`, if there is one for `torrent`, and compares it with the
+    /// files downloaded to `root`. Must be called before storage is opened on `root`, since that
+    /// touches the files. Pieces of files that changed, or of every file on disk if there is no
+    /// usable resume file, are left for [`ResumeState::recheck`].
+    pub fn load(
+        torrent: Arc<Torrent>,
+        path: impl Into<PathBuf>,
+        root: impl Into<PathBuf>,
+        selection: &FileSelection,
+    ) -> anyhow::Result<Self> {
+        let (path, root) = (path.into(), root.into());
+        let saved = match std::fs::read(&path) {
+            Ok(bytes) => match serde_bencode::from_bytes::<ResumeData>(&bytes) {
+                Ok(saved) => Some(saved),
+                Err(err) => {
+                    eprintln!("Ignoring resume file {}: {err}", path.display());
+                    None
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => {
+                return Err(err).with_context(|| format!("read {}", path.display()));
+            }
+        };
+        let spans = torrent.info.file_spans();
+        let saved = saved.filter(|saved| {
+            saved.info_hash[..] == torrent.info_hash() && saved.files.len() == spans.len()
+        });
+        let skipped: Vec<usize> = (0..spans.len())
+            .filter(|&i| selection.is_skipped(i))
+            .collect();
+        let (files, part) = file_stamps(&torrent, &root, &spans, &skipped)
+            .with_context(|| format!("look at files in {}", root.display()))?;
+
+        let unchanged: Vec<bool> = (0..spans.len())
+            .map(|i| {
+                let Some(saved) = &saved else {
+                    return spans[i].padding;
+                };
+                let is_skipped = skipped.contains(&i);
+                spans[i].padding
+                    || (saved.files[i] == files[i]
+                        && saved.skipped.contains(&i) == is_skipped
+                        && (!is_skipped || saved.part == part))
+            })
+            .collect();
+        // Where each span's data is read from in this run, and whether anything is there.
+        let on_disk: Vec<bool> = (0..spans.len())
+            .map(|i| match (spans[i].padding, skipped.contains(&i)) {
+                (true, _) => false,
+                (false, true) => part.is_some_and(|part| part.size > 0),
+                (false, false) => files[i].size > 0,
+            })
+            .collect();
+
+        let info = &torrent.info;
+        let piece_count = info.piece_count();
+        let saved_have = saved
+            .as_ref()
+            .map(|saved| from_bitfield(&saved.pieces, piece_count))
+            .unwrap_or_else(|| vec![false; piece_count]);
+        let mut have = vec![false; piece_count];
+        // Pieces lying entirely in files that were left alone.
+        let mut trusted = vec![false; piece_count];
+        let mut recheck = Vec::new();
+        for piece_index in 0..piece_count {
+            let start = piece_index * info.piece_length;
+            let end = start + info.piece_size(piece_index);
+            let touched: Vec<usize> = (0..spans.len())
+                .filter(|&i| spans[i].offset < end && start < spans[i].offset + spans[i].length)
+                .collect();
+            if touched.iter().all(|&i| unchanged[i]) {
+                trusted[piece_index] = true;
+                have[piece_index] = saved_have[piece_index];
+            } else if touched.iter().any(|&i| !unchanged[i] && on_disk[i]) {
+                recheck.push(piece_index);
+            }
+        }
+
+        let mut progress = Progress {
+            have,
+            partial: HashMap::new(),
+            peers: Vec::new(),
+            uploaded: 0,
+            downloaded: 0,
+            stats_baseline: (0, 0),
+            recheck,
+        };
+        if let Some(saved) = saved {
+            for partial in saved.partial {
+                let piece_index = partial.piece;
+                // Blocks in changed files may have been overwritten.
+                if trusted.get(piece_index) == Some(&true) && !progress.have[piece_index] {
+                    let blocks = info.piece_size(piece_index).div_ceil(BLOCK_LENGTH);
+                    let blocks = from_bitfield(&partial.blocks, blocks);
+                    progress.partial.insert(piece_index, blocks);
+                }
+            }
+            progress.peers = decode_addrs(&saved.peers, &saved.peers6);
+            progress.peers.truncate(MAX_SAVED_PEERS);
+            progress.uploaded = saved.uploaded;
+            progress.downloaded = saved.downloaded;
+        }
+        Ok(Self {
+            shared: Arc::new(ResumeShared {
+                torrent,
+                path,
+                root,
+                spans,
+                skipped,
+                progress: Mutex::new(progress),
+            }),
+        })
+    }
+
+    fn progress(&self) -> std::sync::MutexGuard<'_, Progress> {
+        self.shared
+            .progress
+            .lock()
+            .expect("resume lock is not poisoned")
+    }
+
+    /// Pieces left to hash before they can be trusted.
+    pub fn recheck_pieces(&self) -> Vec<usize> {
+        self.progress().recheck.clone()
+    }
+
+    /// Hashes the pieces of files that changed since the resume data was saved, keeping the ones
+    /// that pass. Returns how many passed.
+    pub async fn recheck(
+        &self,
+        storage: &Arc<dyn Storage>,
+        hash_pool: &HashPool,
+    ) -> anyhow::Result<usize> {
+        let pieces = std::mem::take(&mut self.progress().recheck);
+        let checks = pieces
+            .iter()
+            .map(|&piece_index| hash_pool.verify(storage.clone(), piece_index));
+        let checks = futures_util::future::join_all(checks).await;
+        let mut progress = self.progress();
+        let mut passed = 0;
+        for (&piece_index, verified) in pieces.iter().zip(checks) {
+            if verified? {
+                progress.have[piece_index] = true;
+                passed += 1;
+            }
+        }
+        Ok(passed)
+    }
+
+    /// Whether piece `piece_index` is on disk and verified.
+    pub fn has_piece(&self, piece_index: usize) -> bool {
+        self.progress().have.get(piece_index) == Some(&true)
+    }
+
+    /// Bytes in the pieces `selection` wants that are not on disk yet.
+    pub fn missing_length(&self, selection: &FileSelection) -> usize {
+        let progress = self.progress();
+        selection
+            .wanted_pieces()
+            .filter(|&(piece_index, _)| !progress.have[piece_index])
+            .map(|(_, size)| size)
+            .sum()
+    }
+
+    /// Peers we were connected to in earlier runs.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.progress().peers.clone()
+    }
+
+    /// Whether the block of piece `piece_index` at `begin` was written in an earlier run.
+    pub(crate) fn has_block(&self, piece_index: usize, begin: usize) -> bool {
+        let progress = self.progress();
+        let blocks = progress.partial.get(&piece_index);
+        blocks.and_then(|blocks| blocks.get(begin / BLOCK_LENGTH)) == Some(&true)
+    }
+
+    pub(crate) fn block_written(&self, piece_index: usize, begin: usize) {
+        let blocks = self
+            .shared
+            .torrent
+            .info
+            .piece_size(piece_index)
+            .div_ceil(BLOCK_LENGTH);
+        let mut progress = self.progress();
+        let written = progress
+            .partial
+            .entry(piece_index)
+            .or_insert_with(|| vec![false; blocks]);
+        if let Some(written) = written.get_mut(begin / BLOCK_LENGTH) {
+            *written = true;
+        }
+    }
+
+    pub(crate) fn piece_verified(&self, piece_index: usize) {
+        let mut progress = self.progress();
+        progress.have[piece_index] = true;
+        progress.partial.remove(&piece_index);
+    }
+
+    /// Forgets the blocks of a piece that failed its hash check, so it is fetched whole again.
+    pub(crate) fn piece_failed(&self, piece_index: usize) {
+        self.progress().partial.remove(&piece_index);
+    }
+
+    pub(crate) fn add_peer(&self, addr: SocketAddr) {
+        let mut progress = self.progress();
+        if !progress.peers.contains(&addr) && progress.peers.len() < MAX_SAVED_PEERS {
+            progress.peers.push(addr);
+        }
+    }
+
+    /// Counts only what `stats` transfers from now on towards the saved totals, for stats that
+    /// outlive a single run and so already include the earlier ones.
+    pub fn count_from(&self, stats: &TransferStats) {
+        self.progress().stats_baseline = (
+            stats.uploaded.load(Ordering::Relaxed),
+            stats.downloaded.load(Ordering::Relaxed),
+        );
+    }
+
+    /// Writes the progress so far to the resume file, after flushing `storage` so that the
+    /// file stamps taken cover everything recorded.
+    pub async fn save(
+        &self,
+        storage: &Arc<dyn Storage>,
+        stats: &TransferStats,
+    ) -> anyhow::Result<()> {
+        // Recorded before flushing: anything written later is at worst left out.
+        let mut data = {
+            let progress = self.progress();
+            let mut partial: Vec<_> = progress
+                .partial
+                .iter()
+                .map(|(&piece, blocks)| PartialPiece {
+                    piece,
+                    blocks: ByteBuf::from(to_bitfield(blocks)),
+                })
+                .collect();
+            partial.sort_by_key(|partial| partial.piece);
+            let mut data = ResumeData {
+                info_hash: ByteBuf::from(self.shared.torrent.info_hash().to_vec()),
+                pieces: ByteBuf::from(to_bitfield(&progress.have)),
+                skipped: self.shared.skipped.clone(),
+                partial,
+                uploaded: progress.uploaded
+                    + stats
+                        .uploaded
+                        .load(Ordering::Relaxed)
+                        .saturating_sub(progress.stats_baseline.0) as u64,
+                downloaded: progress.downloaded
+                    + stats
+                        .downloaded
+                        .load(Ordering::Relaxed)
+                        .saturating_sub(progress.stats_baseline.1) as u64,
+                ..Default::default()
+            };
+            encode_addrs(progress.peers.iter(), &mut data.peers, &mut data.peers6);
+            data
+        };
+        let (this, storage) = (self.clone(), storage.clone());
+        tokio::task::spawn_blocking(move || {
+            storage.flush().context("flush storage")?;
+            let shared = &this.shared;
+            let (files, part) = file_stamps(
+                &shared.torrent,
+                &shared.root,
+                &shared.spans,
+                &shared.skipped,
+            )
+            .context("look at downloaded files")?;
+            data.files = files;
+            data.part = part;
+            let bytes = serde_bencode::to_bytes(&data).context("serialize resume data")?;
+            // Replace the old file in one go, so a crash never leaves half of one behind.
+            let mut tmp_path = shared.path.clone().into_os_string();
+            tmp_path.push(".tmp");
+            std::fs::write(&tmp_path, bytes)
+                .and_then(|()| std::fs::rename(&tmp_path, &shared.path))
+                .with_context(|| format!("write {}", shared.path.display()))
+        })
+        .await
+        .context("saving resume data panicked")?
+    }
+
+    /// Saves every [`SAVE_INTERVAL`] until `shutdown` is cancelled, and once more then.
+    pub async fn run(
+        self,
+        storage: Arc<dyn Storage>,
+        stats: Arc<TransferStats>,
+        shutdown: CancellationToken,
+    ) {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        // The first tick completes right away, with nothing to save yet.
+        interval.tick().await;
+        loop {
+            let stopping = tokio::select! {
+                _ = shutdown.cancelled() => true,
+                _ = interval.tick() => false,
+            };
+            if let Err(err) = self.save(&storage, &stats).await {
+                eprintln!("Saving resume data failed: {err:#}");
+            }
+            if stopping {
+                return;
+            }
+        }
+    }
+}
+
+/// Stamps of each span's own file, padding aside, and of the part file if any span is skipped.
+fn file_stamps(
+    torrent: &Torrent,
+    root: &Path,
+    spans: &[FileSpan],
+    skipped: &[usize],
+) -> io::Result<(Vec<FileStamp>, Option<FileStamp>)> {
+    let files = spans
+        .iter()
+        .map(|span| {
+            if span.padding {
+                Ok(FileStamp::default())
+            } else {
+                FileStamp::of(&file_path(torrent, root, span)?)
+            }
+        })
+        .collect::<io::Result<_>>()?;
+    let part = if skipped.is_empty() || torrent.info.is_single_file() {
+        None
+    } else {
+        Some(FileStamp::of(&PartFile::path(root))?)
+    };
+    Ok((files, part))
+}
+
+/// Where the resume file of a download to `outpath` goes by default: next to it.
+pub fn default_path(outpath: &Path) -> PathBuf {
+    let mut path = outpath.as_os_str().to_owned();
+    path.push(".resume");
+    PathBuf::from(path)
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::net::UdpSocket;
+
+use crate::tracker::{urlencode, TrackerError};
+
+/// Info hashes sent in a single HTTP scrape, to keep the query string reasonably short.
+const HTTP_BATCH_SIZE: usize = 50;
+/// Info hashes that fit in a single UDP scrape packet (BEP 15).
+const UDP_BATCH_SIZE: usize = 74;
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+const UDP_TIMEOUT: Duration = Duration::from_secs(5);
+const UDP_ATTEMPTS: usize = 3;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct ScrapeStats {
+    pub complete: i64, // number of seeders
+    #[serde(default)]
+    pub downloaded: i64, // number of times the torrent was completed, if the tracker counts
+    pub incomplete: i64, // number of leechers
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+/// Derives the scrape URL from an HTTP announce URL. Following the convention, this only works
+/// if the last path component starts with `announce`, which is then replaced with `scrape`.
+pub fn scrape_url(announce_url: &str) -> Option<String> {
+    let slash = announce_url.rfind('/')?;
+    let (base, last) = announce_url.split_at(slash + 1);
+    let rest = last.strip_prefix("announce")?;
+    Some(format!("{base}scrape{rest}"))
+}
+
+/// Asks `tracker_url` for swarm statistics of every info hash, batching as many of them per
+/// request as the protocol allows. Torrents unknown to the tracker are missing from the result.
+pub async fn scrape(
+    tracker_url: &str,
+    info_hashes: &[[u8; 20]],
+) -> anyhow::Result<HashMap<[u8; 20], ScrapeStats>> {
+    let mut stats = HashMap::new();
+    if let Some(addr) = tracker_url.strip_prefix("udp://") {
+        let addr = addr
+            .split('/')
+            .next()
+            .expect("split yields at least one item");
+        for batch in info_hashes.chunks(UDP_BATCH_SIZE) {
+            stats.extend(udp_scrape(addr, batch).await?);
+        }
+    } else {
+        let url = scrape_url(tracker_url)
+            .with_context(|| format!("{tracker_url} does not support scraping"))?;
+        for batch in info_hashes.chunks(HTTP_BATCH_SIZE) {
+            stats.extend(http_scrape(&url, batch).await?);
+        }
+    }
+    Ok(stats)
+}
+
+async fn http_scrape(
+    url: &str,
+    info_hashes: &[[u8; 20]],
+) -> anyhow::Result<HashMap<[u8; 20], ScrapeStats>> {
+    let query = info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={}", urlencode(info_hash)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let res = reqwest::get(format!("{url}{separator}{query}"))
+        .await
+        .map_err(TrackerError::from)?
+        .bytes()
+        .await
+        .map_err(TrackerError::from)?;
+
+    let res = serde_bencode::from_bytes::<ScrapeResponse>(&res).map_err(TrackerError::from)?;
+    if let Some(reason) = res.failure_reason {
+        return Err(TrackerError::Failure(reason).into());
+    }
+    Ok(res
+        .files
+        .into_iter()
+        .filter_map(|(info_hash, stats)| Some((info_hash[..].try_into().ok()?, stats)))
+        .collect())
+}
+
+/// Sends `packet` and waits for a reply carrying the same transaction id, retrying a few times
+/// since UDP gives no delivery guarantees. The reply must be for `action`, or an error.
+async fn udp_transact(
+    socket: &UdpSocket,
+    packet: &[u8],
+    transaction_id: u32,
+    action: u32,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0u8; 8 + 12 * UDP_BATCH_SIZE + 512];
+    for _ in 0..UDP_ATTEMPTS {
+        socket
+            .send(packet)
+            .await
+            .context("send UDP tracker packet")?;
+        let Ok(n) = tokio::time::timeout(UDP_TIMEOUT, socket.recv(&mut buf)).await else {
+            continue;
+        };
+        let n = n.context("receive UDP tracker packet")?;
+        if n < 8 || buf[4..8] != transaction_id.to_be_bytes() {
+            continue;
+        }
+        let answered = u32::from_be_bytes(buf[0..4].try_into().expect("slice has 4 bytes"));
+        if answered == ACTION_ERROR {
+            let reason = String::from_utf8_lossy(&buf[8..n]).into_owned();
+            return Err(TrackerError::Failure(reason).into());
+        }
+        anyhow::ensure!(
+            answered == action,
+            "UDP tracker answered action {action} with action {answered}"
+        );
+        return Ok(buf[..n].to_vec());
+    }
+    anyhow::bail!("UDP tracker did not answer after {UDP_ATTEMPTS} attempts")
+}
+
+async fn udp_scrape(
+    addr: &str,
+    info_hashes: &[[u8; 20]],
+) -> anyhow::Result<HashMap<[u8; 20], ScrapeStats>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket
+        .connect(addr)
+        .await
+        .with_context(|| format!("resolve UDP tracker {addr}"))?;
+
+    let transaction_id = rand::random::<u32>();
+    let mut connect = Vec::with_capacity(16);
+    connect.extend(UDP_PROTOCOL_ID.to_be_bytes());
+    connect.extend(ACTION_CONNECT.to_be_bytes());
+    connect.extend(transaction_id.to_be_bytes());
+    let res = udp_transact(&socket, &connect, transaction_id, ACTION_CONNECT).await?;
+    anyhow::ensure!(res.len() >= 16, "UDP connect response is truncated");
+    let connection_id = &res[8..16];
+
+    let transaction_id = rand::random::<u32>();
+    let mut scrape = Vec::with_capacity(16 + 20 * info_hashes.len());
+    scrape.extend(connection_id);
+    scrape.extend(ACTION_SCRAPE.to_be_bytes());
+    scrape.extend(transaction_id.to_be_bytes());
+    info_hashes
+        .iter()
+        .for_each(|info_hash| scrape.extend(info_hash));
+    let res = udp_transact(&socket, &scrape, transaction_id, ACTION_SCRAPE).await?;
+
+    let read_i32 = |bytes: &[u8]| i32::from_be_bytes(bytes.try_into().expect("slice has 4 bytes"));
+    Ok(info_hashes
+        .iter()
+        .zip(res[8..].chunks_exact(12))
+        .map(|(info_hash, chunk)| {
+            let stats = ScrapeStats {
+                complete: read_i32(&chunk[0..4]) as i64,
+                downloaded: read_i32(&chunk[4..8]) as i64,
+                incomplete: read_i32(&chunk[8..12]) as i64,
+            };
+            (*info_hash, stats)
+        })
+        .collect())
+}
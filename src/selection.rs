@@ -0,0 +1,154 @@
+use anyhow::Context;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::torrent::{FileSpan, Info};
+
+/// How eagerly a file is downloaded. Higher priorities are fetched first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, clap::ValueEnum)]
+pub enum FilePriority {
+    /// Not downloaded at all.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Which of a torrent's files to download, and in what order, as a priority for each of its
+/// [`FileSpan`]s. Padding is never wanted for its own sake.
+#[derive(Debug, Clone)]
+pub struct FileSelection {
+    spans: Vec<FileSpan>,
+    single_file: bool,
+    priorities: Vec<FilePriority>,
+    piece_length: usize,
+    piece_priorities: Vec<FilePriority>,
+    piece_sizes: Vec<usize>,
+}
+
+fn glob_set(patterns: &[impl AsRef<str>]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.as_ref();
+        builder.add(Glob::new(pattern).with_context(|| format!("parse glob {pattern:?}"))?);
+    }
+    Ok(builder.build()?)
+}
+
+impl FileSelection {
+    /// Every file at normal priority.
+    pub fn new(info: &Info) -> Self {
+        let spans = info.file_spans();
+        let priorities = spans
+            .iter()
+            .map(|span| {
+                if span.padding {
+                    FilePriority::Skip
+                } else {
+                    FilePriority::Normal
+                }
+            })
+            .collect();
+        let mut selection = Self {
+            spans,
+            single_file: info.is_single_file(),
+            priorities,
+            piece_length: info.piece_length,
+            piece_priorities: vec![FilePriority::Skip; info.piece_count()],
+            piece_sizes: (0..info.piece_count())
+                .map(|piece_index| info.piece_size(piece_index))
+                .collect(),
+        };
+        selection.update_piece_priorities();
+        selection
+    }
+
+    /// Gives each piece the highest priority of the files it holds part of, walking the spans
+    /// and the pieces they cover together.
+    fn update_piece_priorities(&mut self) {
+        self.piece_priorities.fill(FilePriority::Skip);
+        for (span, &priority) in self.spans.iter().zip(&self.priorities) {
+            if span.length == 0 {
+                continue;
+            }
+            let first = span.offset / self.piece_length;
+            let last = (span.offset + span.length - 1) / self.piece_length;
+            for piece_priority in &mut self.piece_priorities[first..=last] {
+                *piece_priority = (*piece_priority).max(priority);
+            }
+        }
+    }
+
+    /// The path globs are matched against: the file's path within the torrent's directory, or
+    /// the torrent's name for single-file torrents.
+    pub fn file_path(&self, span_index: usize) -> String {
+        let path = &self.spans[span_index].path;
+        if self.single_file {
+            path.join("/")
+        } else {
+            path[1..].join("/")
+        }
+    }
+
+    fn set_matching(
+        mut self,
+        patterns: &[impl AsRef<str>],
+        matching: bool,
+        priority: FilePriority,
+    ) -> anyhow::Result<Self> {
+        let globs = glob_set(patterns)?;
+        for i in 0..self.spans.len() {
+            if !self.spans[i].padding && globs.is_match(self.file_path(i)) == matching {
+                self.priorities[i] = priority;
+            }
+        }
+        self.update_piece_priorities();
+        Ok(self)
+    }
+
+    /// Gives files matching `pattern` the given priority.
+    pub fn prioritize(self, pattern: &str, priority: FilePriority) -> anyhow::Result<Self> {
+        self.set_matching(&[pattern], true, priority)
+    }
+
+    /// Skips every file matching none of `patterns`.
+    pub fn only(self, patterns: &[impl AsRef<str>]) -> anyhow::Result<Self> {
+        self.set_matching(patterns, false, FilePriority::Skip)
+    }
+
+    /// Skips every file matching any of `patterns`.
+    pub fn skip(self, patterns: &[impl AsRef<str>]) -> anyhow::Result<Self> {
+        self.set_matching(patterns, true, FilePriority::Skip)
+    }
+
+    /// The priority of each span, in the order of [`Info::file_spans`].
+    pub fn priorities(&self) -> &[FilePriority] {
+        &self.priorities
+    }
+
+    /// Whether the content of span `span_index` is to be kept out of its file.
+    pub fn is_skipped(&self, span_index: usize) -> bool {
+        !self.spans[span_index].padding && self.priorities[span_index] == FilePriority::Skip
+    }
+
+    /// The priority of each piece: the highest of the files it holds part of, so pieces on the
+    /// boundary of a wanted file are fetched even when the other files in them are skipped.
+    pub fn piece_priorities(&self) -> &[FilePriority] {
+        &self.piece_priorities
+    }
+
+    /// Each piece to download, with its size.
+    pub fn wanted_pieces(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.piece_priorities
+            .iter()
+            .zip(&self.piece_sizes)
+            .enumerate()
+            .filter(|(_, (&priority, _))| priority != FilePriority::Skip)
+            .map(|(piece_index, (_, &size))| (piece_index, size))
+    }
+
+    /// Bytes in the pieces to download.
+    pub fn wanted_length(&self) -> usize {
+        self.wanted_pieces().map(|(_, size)| size).sum()
+    }
+}
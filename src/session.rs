@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::dht::{Dht, DhtConfig};
+use crate::download::{download_swarm_accepting, IncomingPeer, SwarmOptions, TransferStats};
+use crate::handshake::{read_handshake, send_handshake};
+use crate::hash_pool::HashPool;
+use crate::mse::{EncryptionPolicy, MseStream};
+use crate::rate_limit::RateLimiter;
+use crate::resume::{self, ResumeState};
+use crate::selection::FileSelection;
+use crate::storage::{FsStorage, Storage};
+use crate::torrent::Torrent;
+use crate::tracker::TrackerSession;
+use crate::transport::PeerStream;
+use crate::utp::UtpSocket;
+
+/// How long a peer connecting to us gets to say which torrent it wants.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Peers queued for a torrent before more are dropped; enough for every saved one.
+const PEER_QUEUE: usize = 128;
+
+pub struct SessionConfig {
+    /// Where peers connect to us, to be downloaded from like any other; nothing is uploaded to
+    /// them. Port 0 picks a free one.
+    pub listen_addr: SocketAddr,
+    /// The DHT node shared by every torrent; no DHT if unset.
+    pub dht: Option<DhtConfig>,
+    /// Exchange peer lists with peers that support `ut_pex`.
+    pub pex: bool,
+    /// Reach peers over uTP too, from a socket shared by every torrent.
+    pub utp: bool,
+    /// Fetch pieces from the torrents' web seeds too.
+    pub web_seeds: bool,
+    pub encryption: EncryptionPolicy,
+    /// Peer connections open at once, over every torrent.
+    pub max_connections: usize,
+    /// Bytes fetched per second, over every torrent; unlimited if unset.
+    pub download_limit: Option<usize>,
+    /// Threads pieces are verified on, for every torrent; a thread per core if unset.
+    pub hashing_threads: Option<usize>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            dht: Some(DhtConfig::default()),
+            pex: true,
+            utp: true,
+            web_seeds: true,
+            encryption: EncryptionPolicy::default(),
+            max_connections: 200,
+            download_limit: None,
+            hashing_threads: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Looking at what is already on disk.
+    Checking,
+    Downloading,
+    Paused,
+    /// Every wanted piece is on disk.
+    Finished,
+    /// Stopped by an error, which resuming may get past.
+    Failed(String),
+}
+
+impl std::fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Checking => f.write_str("checking"),
+            Self::Downloading => f.write_str("downloading"),
+            Self::Paused => f.write_str("paused"),
+            Self::Finished => f.write_str("finished"),
+            Self::Failed(err) => write!(f, "failed: {err}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    pub state: TorrentState,
+    /// Bytes in the torrent.
+    pub length: usize,
+    /// Bytes wanted that are not on disk yet.
+    pub left: usize,
+    /// Bytes fetched since the torrent was added.
+    pub downloaded: usize,
+}
+
+/// Many torrents downloading side by side, sharing one listening port, one DHT node, and limits
+/// on connections and download rate. Clones are handles to the same session.
+///
+/// Torrents keep running until paused, removed, or [`Session::shutdown`] is called.
+#[derive(Clone)]
+pub struct Session {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    local_addr: SocketAddr,
+    // The options every torrent starts from.
+    options: SwarmOptions,
+    torrents: Mutex<HashMap<[u8; 20], TorrentEntry>>,
+    shutdown: CancellationToken,
+}
+
+struct TorrentEntry {
+    torrent: Arc<Torrent>,
+    outpath: PathBuf,
+    stats: Arc<TransferStats>,
+    state: Arc<Mutex<TorrentState>>,
+    running: Option<Running>,
+}
+
+/// A torrent's download task, and the ways into it.
+struct Running {
+    stop: CancellationToken,
+    peers_tx: mpsc::Sender<SocketAddr>,
+    incoming_tx: mpsc::Sender<IncomingPeer>,
+    options: SwarmOptions,
+    task: JoinHandle<()>,
+}
+
+impl Shared {
+    fn torrents(&self) -> std::sync::MutexGuard<'_, HashMap<[u8; 20], TorrentEntry>> {
+        self.torrents.lock().expect("torrents lock is not poisoned")
+    }
+}
+
+impl Session {
+    /// Starts listening for peers and joins the DHT, with no torrents yet.
+    pub async fn start(config: SessionConfig) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(config.listen_addr)
+            .await
+            .with_context(|| format!("listen on {}", config.listen_addr))?;
+        let local_addr = listener.local_addr()?;
+        let dht = match config.dht {
+            Some(dht) => Some(Dht::bind(dht).await?),
+            None => None,
+        };
+        // The DHT may hold the UDP side of our port, so uTP goes out from a port of its own.
+        let utp = if config.utp {
+            Some(UtpSocket::bind("0.0.0.0:0").await?)
+        } else {
+            None
+        };
+        let options = SwarmOptions {
+            dht,
+            pex: config.pex,
+            encryption: config.encryption,
+            utp,
+            web_seeds: config.web_seeds,
+            hash_pool: config
+                .hashing_threads
+                .map(HashPool::new)
+                .unwrap_or_default(),
+            connections: Some(Arc::new(Semaphore::new(config.max_connections))),
+            download_limit: config.download_limit.map(RateLimiter::new),
+            ..Default::default()
+        };
+        let shared = Arc::new(Shared {
+            local_addr,
+            options,
+            torrents: Mutex::new(HashMap::new()),
+            shutdown: CancellationToken::new(),
+        });
+        tokio::spawn(accept_loop(
+            Arc::downgrade(&shared),
+            listener,
+            shared.shutdown.clone(),
+        ));
+        Ok(Self { shared })
+    }
+
+    /// Where peers connect to us.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.local_addr
+    }
+
+    /// Starts downloading `torrent` to `outpath`, picking up where an earlier run left off.
+    /// `outpath` may not hold, or lie within, that of another torrent in the session.
+    /// Returns the info hash the torrent goes by in the session.
+    pub fn add(&self, torrent: Torrent, outpath: impl Into<PathBuf>) -> anyhow::Result<[u8; 20]> {
+        torrent.check_piece_layers()?;
+        let info_hash = torrent.info_hash();
+        let mut torrents = self.shared.torrents();
+        anyhow::ensure!(
+            !torrents.contains_key(&info_hash),
+            "torrent {} is already in the session",
+            hex::encode(info_hash)
+        );
+        let outpath = outpath.into();
+        // Torrents sharing files would write over each other's data and resume files.
+        if let Some(other) = torrents.values().find(|other| {
+            other.outpath.starts_with(&outpath) || outpath.starts_with(&other.outpath)
+        }) {
+            anyhow::bail!(
+                "{} is already used by {}",
+                outpath.display(),
+                other.outpath.display()
+            );
+        }
+        let length = torrent.info.length();
+        let mut entry = TorrentEntry {
+            torrent: Arc::new(torrent),
+            outpath,
+            stats: Arc::new(TransferStats::new(length)),
+            state: Arc::new(Mutex::new(TorrentState::Checking)),
+            running: None,
+        };
+        self.start_torrent(&mut entry);
+        torrents.insert(info_hash, entry);
+        Ok(info_hash)
+    }
+
+    fn start_torrent(&self, entry: &mut TorrentEntry) {
+        let private = entry.torrent.info.is_private();
+        let options = SwarmOptions {
+            // Private torrents only get their peers from the trackers.
+            dht: self.shared.options.dht.clone().filter(|_| !private),
+            pex: self.shared.options.pex && !private,
+            ..self.shared.options.clone()
+        };
+        let stop = self.shared.shutdown.child_token();
+        let (peers_tx, peers_rx) = mpsc::channel(PEER_QUEUE);
+        let (incoming_tx, incoming_rx) = mpsc::channel(8);
+        *lock(&entry.state) = TorrentState::Checking;
+        let run = TorrentRun {
+            torrent: entry.torrent.clone(),
+            outpath: entry.outpath.clone(),
+            stats: entry.stats.clone(),
+            state: entry.state.clone(),
+            options: options.clone(),
+            port: self.shared.local_addr.port(),
+            peers_tx: peers_tx.clone(),
+            stop: stop.clone(),
+        };
+        let task = tokio::spawn(run.run(peers_rx, incoming_rx));
+        entry.running = Some(Running {
+            stop,
+            peers_tx,
+            incoming_tx,
+            options,
+            task,
+        });
+    }
+
+    /// Stops downloading a torrent, saving its progress. Does nothing to a torrent that is not
+    /// running.
+    pub async fn pause(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        let running = self
+            .shared
+            .torrents()
+            .get_mut(info_hash)
+            .with_context(|| format!("no torrent {} in the session", hex::encode(info_hash)))?
+            .running
+            .take();
+        if let Some(running) = running {
+            running.stop.cancel();
+            running.task.await.context("torrent task panicked")?;
+        }
+        Ok(())
+    }
+
+    /// Starts a torrent that is paused, or that stopped on its own, again.
+    pub fn resume(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        let mut torrents = self.shared.torrents();
+        let entry = torrents
+            .get_mut(info_hash)
+            .with_context(|| format!("no torrent {} in the session", hex::encode(info_hash)))?;
+        let running = entry
+            .running
+            .as_ref()
+            .is_some_and(|running| !running.task.is_finished());
+        if !running {
+            self.start_torrent(entry);
+        }
+        Ok(())
+    }
+
+    /// Stops a torrent and forgets it. The files downloaded so far stay on disk.
+    pub async fn remove(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        self.pause(info_hash).await?;
+        self.shared.torrents().remove(info_hash);
+        Ok(())
+    }
+
+    /// Hands a peer of a torrent to its download, for instance one saved by the caller.
+    pub fn add_peer(&self, info_hash: &[u8; 20], addr: SocketAddr) -> anyhow::Result<()> {
+        let torrents = self.shared.torrents();
+        let entry = torrents
+            .get(info_hash)
+            .with_context(|| format!("no torrent {} in the session", hex::encode(info_hash)))?;
+        let running = entry.running.as_ref().context("torrent is not running")?;
+        running
+            .peers_tx
+            .try_send(addr)
+            .context("torrent is not taking peers")
+    }
+
+    pub fn status(&self, info_hash: &[u8; 20]) -> Option<TorrentStatus> {
+        let torrents = self.shared.torrents();
+        torrents
+            .get(info_hash)
+            .map(|entry| entry.status(*info_hash))
+    }
+
+    /// The status of every torrent in the session, in no particular order.
+    pub fn torrents(&self) -> Vec<TorrentStatus> {
+        self.shared
+            .torrents()
+            .iter()
+            .map(|(&info_hash, entry)| entry.status(info_hash))
+            .collect()
+    }
+
+    /// Pauses every torrent and stops listening for peers.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        self.shared.shutdown.cancel();
+        let running: Vec<_> = self
+            .shared
+            .torrents()
+            .values_mut()
+            .filter_map(|entry| entry.running.take())
+            .collect();
+        for running in running {
+            running.task.await.context("torrent task panicked")?;
+        }
+        if let Some(dht) = &self.shared.options.dht {
+            dht.save_state()?;
+        }
+        Ok(())
+    }
+}
+
+impl TorrentEntry {
+    fn status(&self, info_hash: [u8; 20]) -> TorrentStatus {
+        TorrentStatus {
+            info_hash,
+            name: self.torrent.info.name.clone(),
+            state: lock(&self.state).clone(),
+            length: self.torrent.info.length(),
+            left: self.stats.left.load(Ordering::Relaxed),
+            downloaded: self.stats.downloaded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn lock(state: &Mutex<TorrentState>) -> std::sync::MutexGuard<'_, TorrentState> {
+    state.lock().expect("torrent state lock is not poisoned")
+}
+
+/// Everything one run of a torrent, from start to pause, needs.
+struct TorrentRun {
+    torrent: Arc<Torrent>,
+    outpath: PathBuf,
+    stats: Arc<TransferStats>,
+    state: Arc<Mutex<TorrentState>>,
+    options: SwarmOptions,
+    port: u16,
+    peers_tx: mpsc::Sender<SocketAddr>,
+    stop: CancellationToken,
+}
+
+impl TorrentRun {
+    async fn run(
+        self,
+        peers_rx: mpsc::Receiver<SocketAddr>,
+        incoming_rx: mpsc::Receiver<IncomingPeer>,
+    ) {
+        let state = self.state.clone();
+        let result = self.download(peers_rx, incoming_rx).await;
+        *lock(&state) = match result {
+            Ok(true) => TorrentState::Finished,
+            Ok(false) => TorrentState::Paused,
+            Err(err) => TorrentState::Failed(format!("{err:#}")),
+        };
+    }
+
+    /// Downloads until every wanted piece is on disk, returning `false` if stopped before that.
+    async fn download(
+        self,
+        peers_rx: mpsc::Receiver<SocketAddr>,
+        incoming_rx: mpsc::Receiver<IncomingPeer>,
+    ) -> anyhow::Result<bool> {
+        let torrent = &self.torrent;
+        let selection = FileSelection::new(&torrent.info);
+        // The files have to be looked at before opening storage resizes them.
+        let resume = ResumeState::load(
+            torrent.clone(),
+            resume::default_path(&self.outpath),
+            &self.outpath,
+            &selection,
+        )?;
+        // The stats carry over from earlier runs, which the resume file already counts.
+        resume.count_from(&self.stats);
+        let storage = FsStorage::with_selection(torrent.clone(), &self.outpath, &selection)
+            .with_context(|| format!("open {}", self.outpath.display()))?;
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        tokio::select! {
+            checked = resume.recheck(&storage, &self.options.hash_pool) => checked?,
+            _ = self.stop.cancelled() => return Ok(false),
+        };
+        self.stats
+            .left
+            .store(resume.missing_length(&selection), Ordering::Relaxed);
+        for addr in resume.peers() {
+            let _ = self.peers_tx.try_send(addr);
+        }
+        *lock(&self.state) = TorrentState::Downloading;
+
+        // Peer sources and saving outlive the download by a little, to report how it ended.
+        let discovery = CancellationToken::new();
+        let (completed_tx, completed_rx) = watch::channel(false);
+        let mut tasks = Vec::new();
+        for info_hash in torrent.swarm_hashes() {
+            let session = TrackerSession::new(torrent, self.stats.clone(), self.peers_tx.clone())
+                .with_info_hash(info_hash)
+                .with_port(self.port);
+            let announcing = session.run(completed_rx.clone(), discovery.clone());
+            tasks.push(tokio::spawn(async move {
+                if let Err(err) = announcing.await {
+                    eprintln!("Tracker shutdown failed: {err:#}");
+                }
+            }));
+            if let Some(dht) = &self.options.dht {
+                let search = dht.clone().search_peers(
+                    info_hash,
+                    Some(self.port),
+                    self.peers_tx.clone(),
+                    discovery.clone(),
+                );
+                tasks.push(tokio::spawn(search));
+            }
+        }
+        tasks.push(tokio::spawn(resume.clone().run(
+            storage.clone(),
+            self.stats.clone(),
+            discovery.clone(),
+        )));
+
+        let download = download_swarm_accepting(
+            torrent.clone(),
+            peers_rx,
+            incoming_rx,
+            storage,
+            self.stats.clone(),
+            SwarmOptions {
+                selection: Some(selection),
+                resume: Some(resume),
+                ..self.options.clone()
+            },
+        );
+        let result = tokio::select! {
+            result = download => result.map(|()| true),
+            _ = self.stop.cancelled() => Ok(false),
+        };
+        completed_tx.send_replace(matches!(result, Ok(true)));
+        discovery.cancel();
+        for task in tasks {
+            task.await.context("peer source panicked")?;
+        }
+        result
+    }
+}
+
+/// Accepts peers connecting to us until `shutdown` fires, handing each to the torrent it asks for.
+async fn accept_loop(shared: Weak<Shared>, listener: TcpListener, shutdown: CancellationToken) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => return,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                eprintln!("Accepting peers failed: {err}");
+                continue;
+            }
+        };
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        tokio::spawn(async move {
+            let accepting = tokio::time::timeout(HANDSHAKE_TIMEOUT, accept_peer(&shared, stream));
+            match accepting.await {
+                Ok(Ok(incoming)) => {
+                    // The torrent stopping in the meantime just means it no longer needs the peer.
+                    let _ = incoming.0.send(incoming.1).await;
+                }
+                Ok(Err(err)) => eprintln!("Peer {addr} turned away: {err:#}"),
+                Err(_) => eprintln!("Peer {addr} turned away: handshake timed out"),
+            }
+        });
+    }
+}
+
+/// Exchanges handshakes with a peer that connected to us, returning the running torrent it wants
+/// along with it.
+async fn accept_peer(
+    shared: &Shared,
+    stream: TcpStream,
+) -> anyhow::Result<(mpsc::Sender<IncomingPeer>, IncomingPeer)> {
+    let addr = stream.peer_addr()?;
+    let policy = shared.options.encryption;
+    let mut stream = if policy == EncryptionPolicy::Plaintext {
+        PeerStream::Tcp(stream)
+    } else {
+        let info_hashes: Vec<_> = shared
+            .torrents()
+            .values()
+            .filter(|entry| entry.running.is_some())
+            .flat_map(|entry| entry.torrent.swarm_hashes())
+            .collect();
+        let (stream, _) = MseStream::accept(PeerStream::Tcp(stream), &info_hashes, policy).await?;
+        PeerStream::Encrypted(Box::new(stream))
+    };
+    let handshake = read_handshake(&mut stream).await?;
+    let (incoming_tx, extensions) = {
+        let torrents = shared.torrents();
+        let running = torrents
+            .values()
+            .filter(|entry| entry.torrent.swarm_hashes().contains(&handshake.info_hash))
+            .find_map(|entry| entry.running.as_ref())
+            .context("peer asked for a torrent we are not running")?;
+        (running.incoming_tx.clone(), running.options.extensions())
+    };
+    send_handshake(&mut stream, &handshake.info_hash, extensions).await?;
+    let incoming = IncomingPeer {
+        addr,
+        stream,
+        handshake,
+    };
+    Ok((incoming_tx, incoming))
+}
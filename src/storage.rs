@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use memmap2::MmapMut;
+
+use crate::selection::FileSelection;
+use crate::torrent::{FileSpan, Torrent};
+
+/// Where downloaded piece data goes and is read back from. Blocks are addressed by piece and
+/// offset within the piece, so they can be written in any order.
+///
+/// Methods block; async code calls them on a blocking thread.
+pub trait Storage: Send + Sync {
+    fn torrent(&self) -> &Torrent;
+
+    /// Fills `buf` with the data at offset `begin` of piece `piece_index`.
+    fn read_block(&self, piece_index: usize, begin: usize, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()>;
+
+    /// Makes every write so far durable.
+    fn flush(&self) -> io::Result<()>;
+
+    /// Reads piece `piece_index` back and checks it against the torrent.
+    fn verify(&self, piece_index: usize) -> io::Result<bool> {
+        let torrent = self.torrent();
+        let mut piece = vec![0; torrent.info.piece_size(piece_index)];
+        self.read_block(piece_index, 0, &mut piece)?;
+        Ok(torrent.verify_piece(piece_index, &piece))
+    }
+}
+
+/// Checks that a block lies within its piece, returning its offset in the torrent's content.
+fn block_offset(
+    torrent: &Torrent,
+    piece_index: usize,
+    begin: usize,
+    len: usize,
+) -> io::Result<usize> {
+    let info = &torrent.info;
+    if piece_index >= info.piece_count() || begin + len > info.piece_size(piece_index) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("block {begin}+{len} is outside of piece {piece_index}"),
+        ));
+    }
+    Ok(piece_index * info.piece_length + begin)
+}
+
+/// The parts of `spans` that bytes `offset..offset + len` of the content fall into, as
+/// `(span index, offset in span, offset in block, length)`.
+fn split_over_spans(
+    spans: &[FileSpan],
+    offset: usize,
+    len: usize,
+) -> impl Iterator<Item = (usize, usize, usize, usize)> + '_ {
+    let end = offset + len;
+    spans.iter().enumerate().filter_map(move |(i, span)| {
+        let from = offset.max(span.offset);
+        let to = end.min(span.offset + span.length);
+        (from < to).then(|| (i, from - span.offset, from - offset, to - from))
+    })
+}
+
+/// Keeps the torrent's files on disk, laid out as the torrent describes them.
+pub struct FsStorage {
+    torrent: Arc<Torrent>,
+    spans: Vec<FileSpan>,
+    // `None` for padding, which is never written, and for skipped files.
+    files: Vec<Option<File>>,
+    part: Option<PartFile>,
+}
+
+impl FsStorage {
+    /// Opens or creates the torrent's files under `path`: the file itself for single-file
+    /// torrents, or the directory to put the torrent's files in. Files are preallocated to
+    /// their full, sparse length, and anything past their end is cut off.
+    pub fn new(torrent: Arc<Torrent>, path: impl AsRef<Path>) -> io::Result<Self> {
+        let selection = FileSelection::new(&torrent.info);
+        Self::with_selection(torrent, path, &selection)
+    }
+
+    /// Like [`FsStorage::new`], but leaves the files `selection` skips alone, keeping the parts
+    /// of them sharing a piece with wanted files in a [`PartFile`] instead.
+    pub fn with_selection(
+        torrent: Arc<Torrent>,
+        path: impl AsRef<Path>,
+        selection: &FileSelection,
+    ) -> io::Result<Self> {
+        let spans = torrent.info.file_spans();
+        let (files, part) = open_files(&torrent, &spans, path.as_ref(), selection)?;
+        Ok(Self {
+            torrent,
+            spans,
+            files,
+            part,
+        })
+    }
+}
+
+/// Holds the bytes of skipped files that share a piece with wanted ones, at their offset in the
+/// torrent's content, so the skipped files themselves are never created. The file is sparse, so
+/// only those bytes take up space.
+pub struct PartFile {
+    file: File,
+}
+
+impl PartFile {
+    /// The part file of a multi-file torrent downloaded to the directory `path`.
+    pub fn path(path: &Path) -> PathBuf {
+        path.join(".parts")
+    }
+
+    fn open(path: &Path, length: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() != length as u64 {
+            file.set_len(length as u64)?;
+        }
+        Ok(Self { file })
+    }
+
+    fn read(&self, span: &FileSpan, in_span: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.file.read_exact_at(buf, (span.offset + in_span) as u64)
+    }
+
+    fn write(&self, span: &FileSpan, in_span: usize, data: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(data, (span.offset + in_span) as u64)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// Checks that `part`, a path part taken from a torrent, is a plain file or directory name, and
+/// so cannot lead out of the directory it is joined to.
+pub fn check_plain_name(part: &str) -> io::Result<()> {
+    let mut components = Path::new(part).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) if name == part => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("file path part {part:?} is not a plain name"),
+        )),
+    }
+}
+
+/// Where the file of `span` goes under `path`. Path parts come from the torrent, so anything
+/// but a plain file or directory name is refused.
+pub(crate) fn file_path(torrent: &Torrent, path: &Path, span: &FileSpan) -> io::Result<PathBuf> {
+    if torrent.info.is_single_file() {
+        return Ok(path.to_path_buf());
+    }
+    // Spans start with the torrent's directory, which `path` stands in for.
+    span.path[1..]
+        .iter()
+        .try_fold(path.to_path_buf(), |path, part| {
+            check_plain_name(part)?;
+            Ok(path.join(part))
+        })
+}
+
+/// Opens or creates the file of every span but padding and skipped files, at exactly the
+/// span's length, and the part file if any file is skipped.
+fn open_files(
+    torrent: &Torrent,
+    spans: &[FileSpan],
+    path: &Path,
+    selection: &FileSelection,
+) -> io::Result<(Vec<Option<File>>, Option<PartFile>)> {
+    // Refuse bad paths before anything is created.
+    for span in spans.iter().filter(|span| !span.padding) {
+        file_path(torrent, path, span)?;
+    }
+    let skipping = (0..spans.len()).any(|i| selection.is_skipped(i));
+    // A single file that is skipped leaves nothing to download.
+    let part = if skipping && !torrent.info.is_single_file() {
+        std::fs::create_dir_all(path)?;
+        Some(PartFile::open(
+            &PartFile::path(path),
+            torrent.info.length(),
+        )?)
+    } else {
+        None
+    };
+    let files = spans
+        .iter()
+        .enumerate()
+        .map(|(i, span)| {
+            if span.padding || selection.is_skipped(i) {
+                return Ok(None);
+            }
+            let file_path = file_path(torrent, path, span)?;
+            if let Some(parent) = file_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&file_path)?;
+            if file.metadata()?.len() != span.length as u64 {
+                file.set_len(span.length as u64)?;
+            }
+            Ok(Some(file))
+        })
+        .collect::<io::Result<_>>()?;
+    Ok((files, part))
+}
+
+impl Storage for FsStorage {
+    fn torrent(&self) -> &Torrent {
+        &self.torrent
+    }
+
+    fn read_block(&self, piece_index: usize, begin: usize, buf: &mut [u8]) -> io::Result<()> {
+        let offset = block_offset(&self.torrent, piece_index, begin, buf.len())?;
+        for (i, in_span, in_block, len) in split_over_spans(&self.spans, offset, buf.len()) {
+            let buf = &mut buf[in_block..in_block + len];
+            match (&self.files[i], &self.part) {
+                (Some(file), _) => file.read_exact_at(buf, in_span as u64)?,
+                (None, Some(part)) if !self.spans[i].padding => {
+                    part.read(&self.spans[i], in_span, buf)?
+                }
+                (None, _) => buf.fill(0),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        let offset = block_offset(&self.torrent, piece_index, begin, data.len())?;
+        for (i, in_span, in_block, len) in split_over_spans(&self.spans, offset, data.len()) {
+            let data = &data[in_block..in_block + len];
+            match (&self.files[i], &self.part) {
+                (Some(file), _) => file.write_all_at(data, in_span as u64)?,
+                (None, Some(part)) if !self.spans[i].padding => {
+                    part.write(&self.spans[i], in_span, data)?
+                }
+                (None, _) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.files.iter().flatten().try_for_each(File::sync_data)?;
+        self.part.iter().try_for_each(PartFile::flush)
+    }
+}
+
+/// Keeps the torrent's files on disk like [`FsStorage`], but maps them into memory so blocks are
+/// copied straight to and from the page cache, without a system call each.
+pub struct MmapStorage {
+    torrent: Arc<Torrent>,
+    spans: Vec<FileSpan>,
+    // `None` for padding, skipped files and empty files, which cannot be mapped.
+    maps: Vec<Option<RwLock<MmapMut>>>,
+    // Small and rarely used, so not worth mapping.
+    part: Option<PartFile>,
+}
+
+impl MmapStorage {
+    /// Opens or creates the torrent's files under `path`, the same way as [`FsStorage::new`],
+    /// and maps them.
+    pub fn new(torrent: Arc<Torrent>, path: impl AsRef<Path>) -> io::Result<Self> {
+        let selection = FileSelection::new(&torrent.info);
+        Self::with_selection(torrent, path, &selection)
+    }
+
+    /// Like [`MmapStorage::new`], but leaves the files `selection` skips alone, the same way as
+    /// [`FsStorage::with_selection`].
+    pub fn with_selection(
+        torrent: Arc<Torrent>,
+        path: impl AsRef<Path>,
+        selection: &FileSelection,
+    ) -> io::Result<Self> {
+        let spans = torrent.info.file_spans();
+        let (files, part) = open_files(&torrent, &spans, path.as_ref(), selection)?;
+        let maps = files
+            .into_iter()
+            .zip(&spans)
+            .map(|(file, span)| match file {
+                Some(file) if span.length > 0 => {
+                    // Safety: the mapping is only sound as long as nothing else truncates the
+                    // file, which holds for files we are downloading into.
+                    let map = unsafe { MmapMut::map_mut(&file)? };
+                    Ok(Some(RwLock::new(map)))
+                }
+                _ => Ok(None),
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            torrent,
+            spans,
+            maps,
+            part,
+        })
+    }
+}
+
+fn read_map(map: &RwLock<MmapMut>) -> std::sync::RwLockReadGuard<'_, MmapMut> {
+    map.read().expect("mapping lock is not poisoned")
+}
+
+impl Storage for MmapStorage {
+    fn torrent(&self) -> &Torrent {
+        &self.torrent
+    }
+
+    fn read_block(&self, piece_index: usize, begin: usize, buf: &mut [u8]) -> io::Result<()> {
+        let offset = block_offset(&self.torrent, piece_index, begin, buf.len())?;
+        for (i, in_span, in_block, len) in split_over_spans(&self.spans, offset, buf.len()) {
+            let buf = &mut buf[in_block..in_block + len];
+            let span = &self.spans[i];
+            match (&self.maps[i], &self.part) {
+                (Some(map), _) => buf.copy_from_slice(&read_map(map)[in_span..in_span + len]),
+                (None, Some(part)) if !span.padding => part.read(span, in_span, buf)?,
+                (None, _) => buf.fill(0),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        let offset = block_offset(&self.torrent, piece_index, begin, data.len())?;
+        for (i, in_span, in_block, len) in split_over_spans(&self.spans, offset, data.len()) {
+            let data = &data[in_block..in_block + len];
+            let span = &self.spans[i];
+            match (&self.maps[i], &self.part) {
+                (Some(map), _) => {
+                    let mut map = map.write().expect("mapping lock is not poisoned");
+                    map[in_span..in_span + len].copy_from_slice(data);
+                }
+                (None, Some(part)) if !span.padding => part.write(span, in_span, data)?,
+                (None, _) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.maps
+            .iter()
+            .flatten()
+            .try_for_each(|map| read_map(map).flush())?;
+        self.part.iter().try_for_each(PartFile::flush)
+    }
+
+    fn verify(&self, piece_index: usize) -> io::Result<bool> {
+        let piece_size = self.torrent.info.piece_size(piece_index);
+        let offset = block_offset(&self.torrent, piece_index, 0, piece_size)?;
+        let mut parts = split_over_spans(&self.spans, offset, piece_size);
+        // A piece within a single file is hashed where it lies.
+        if let (Some((i, in_span, _, len)), None) = (parts.next(), parts.next()) {
+            if let Some(map) = &self.maps[i] {
+                let map = read_map(map);
+                return Ok(self
+                    .torrent
+                    .verify_piece(piece_index, &map[in_span..in_span + len]));
+            }
+        }
+        let mut piece = vec![0; piece_size];
+        self.read_block(piece_index, 0, &mut piece)?;
+        Ok(self.torrent.verify_piece(piece_index, &piece))
+    }
+}
+
+/// Keeps pieces in memory, allocating each one as it is first written to.
+pub struct MemoryStorage {
+    torrent: Arc<Torrent>,
+    pieces: Mutex<HashMap<usize, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(torrent: Arc<Torrent>) -> Self {
+        Self {
+            torrent,
+            pieces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The torrent's content with zeros where nothing was written, laid out like on disk
+    /// with padding between v2 files.
+    pub fn contents(&self) -> Vec<u8> {
+        let info = &self.torrent.info;
+        let mut contents = vec![0; info.length()];
+        for (piece_index, piece) in self.pieces().iter() {
+            let offset = piece_index * info.piece_length;
+            contents[offset..offset + piece.len()].copy_from_slice(piece);
+        }
+        contents
+    }
+
+    fn pieces(&self) -> std::sync::MutexGuard<'_, HashMap<usize, Vec<u8>>> {
+        self.pieces.lock().expect("pieces lock is not poisoned")
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn torrent(&self) -> &Torrent {
+        &self.torrent
+    }
+
+    fn read_block(&self, piece_index: usize, begin: usize, buf: &mut [u8]) -> io::Result<()> {
+        block_offset(&self.torrent, piece_index, begin, buf.len())?;
+        match self.pieces().get(&piece_index) {
+            Some(piece) => buf.copy_from_slice(&piece[begin..begin + buf.len()]),
+            None => buf.fill(0),
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        block_offset(&self.torrent, piece_index, begin, data.len())?;
+        let piece_size = self.torrent.info.piece_size(piece_index);
+        self.pieces()
+            .entry(piece_index)
+            .or_insert_with(|| vec![0; piece_size])[begin..begin + data.len()]
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Discards everything written, for measuring transfer speed without touching the disk. With
+/// nothing kept to check, swarms writing to it have to set
+/// [`SwarmOptions::skip_hash_check`](crate::download::SwarmOptions::skip_hash_check).
+pub struct NullStorage {
+    torrent: Arc<Torrent>,
+}
+
+impl NullStorage {
+    pub fn new(torrent: Arc<Torrent>) -> Self {
+        Self { torrent }
+    }
+}
+
+impl Storage for NullStorage {
+    fn torrent(&self) -> &Torrent {
+        &self.torrent
+    }
+
+    fn read_block(&self, _piece_index: usize, _begin: usize, _buf: &mut [u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "nothing is kept to read back",
+        ))
+    }
+
+    fn write_block(&self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        block_offset(&self.torrent, piece_index, begin, data.len()).map(drop)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
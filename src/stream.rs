@@ -0,0 +1,290 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use anyhow::Context as _;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::Notify;
+
+use crate::storage::Storage;
+
+/// Bytes past the read position fetched ahead of everything else.
+pub const DEFAULT_READAHEAD: usize = 4 << 20;
+
+/// Links a swarm download to readers of its content: the download reports the pieces it
+/// verifies, and fetches the pieces just ahead of each reader before any other.
+///
+/// Clones share the same state; hand one to the download through
+/// [`SwarmOptions::streaming`](crate::download::SwarmOptions::streaming).
+#[derive(Clone, Default)]
+pub struct StreamControl {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<StreamState>,
+    // Woken whenever a piece is verified or the download ends.
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct StreamState {
+    verified: HashSet<usize>,
+    // The pieces each open reader wants next.
+    windows: HashMap<u64, Range<usize>>,
+    next_reader: u64,
+    // Set once the download ends, to its error if it failed.
+    finished: Option<Result<(), String>>,
+}
+
+impl StreamControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, StreamState> {
+        self.shared
+            .state
+            .lock()
+            .expect("stream lock is not poisoned")
+    }
+
+    /// Whether piece `piece_index` has been downloaded and checked.
+    pub fn is_verified(&self, piece_index: usize) -> bool {
+        self.state().verified.contains(&piece_index)
+    }
+
+    /// Records a piece as readable, for instance one already on disk before the download.
+    pub fn piece_verified(&self, piece_index: usize) {
+        self.state().verified.insert(piece_index);
+        self.shared.changed.notify_waiters();
+    }
+
+    /// Records the end of the download, so readers stop waiting for pieces that will never come.
+    pub(crate) fn finish(&self, result: &anyhow::Result<()>) {
+        self.state().finished = Some(
+            result
+                .as_ref()
+                .map(|_| ())
+                .map_err(|err| format!("{err:#}")),
+        );
+        self.shared.changed.notify_waiters();
+    }
+
+    /// Unverified pieces within the readers' windows, in order.
+    pub(crate) fn wanted_pieces(&self) -> Vec<usize> {
+        let state = self.state();
+        let mut pieces: Vec<_> = state
+            .windows
+            .values()
+            .flat_map(Range::clone)
+            .filter(|piece_index| !state.verified.contains(piece_index))
+            .collect();
+        pieces.sort_unstable();
+        pieces.dedup();
+        pieces
+    }
+
+    /// Waits for piece `piece_index` to be verified, failing if the download ends without it.
+    async fn wait_for(&self, piece_index: usize) -> io::Result<()> {
+        loop {
+            let changed = self.shared.changed.notified();
+            {
+                let state = self.state();
+                if state.verified.contains(&piece_index) {
+                    return Ok(());
+                }
+                match &state.finished {
+                    Some(Ok(())) => {
+                        return Err(io::Error::other(format!(
+                            "piece {piece_index} is not part of the download"
+                        )))
+                    }
+                    Some(Err(err)) => {
+                        return Err(io::Error::other(format!(
+                            "download failed before piece {piece_index} arrived: {err}"
+                        )))
+                    }
+                    None => {}
+                }
+            }
+            changed.await;
+        }
+    }
+
+    /// Opens span `span_index` of `storage`'s torrent, in the order of
+    /// [`Info::file_spans`](crate::torrent::Info::file_spans), for reading as it downloads.
+    pub fn open_file(
+        &self,
+        storage: Arc<dyn Storage>,
+        span_index: usize,
+    ) -> anyhow::Result<FileStream> {
+        let spans = storage.torrent().info.file_spans();
+        let span = spans
+            .get(span_index)
+            .with_context(|| format!("torrent has no file {span_index}"))?;
+        anyhow::ensure!(!span.padding, "file {span_index} is padding");
+        let id = {
+            let mut state = self.state();
+            state.next_reader += 1;
+            state.next_reader
+        };
+        let stream = FileStream {
+            control: self.clone(),
+            storage,
+            id,
+            offset: span.offset,
+            length: span.length,
+            readahead: DEFAULT_READAHEAD,
+            position: 0,
+            chunk: None,
+            reading: None,
+        };
+        stream.update_window();
+        Ok(stream)
+    }
+}
+
+type PendingRead = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>;
+
+/// A file of a torrent being downloaded, read in order or seeked around in. Reads wait for the
+/// pieces they need to be verified, and the download fetches the pieces at and just after the
+/// read position first.
+pub struct FileStream {
+    control: StreamControl,
+    storage: Arc<dyn Storage>,
+    id: u64,
+    // Where the file lies within the torrent.
+    offset: usize,
+    length: usize,
+    readahead: usize,
+    position: u64,
+    // Bytes read from storage and the file position they start at.
+    chunk: Option<(u64, Vec<u8>)>,
+    reading: Option<(u64, PendingRead)>,
+}
+
+impl FileStream {
+    /// Sets how many bytes past the read position are fetched first.
+    pub fn readahead(mut self, bytes: usize) -> Self {
+        self.readahead = bytes;
+        self.update_window();
+        self
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Asks for the pieces from the read position to the end of the readahead.
+    fn update_window(&self) {
+        let mut state = self.control.state();
+        if self.position >= self.length as u64 {
+            state.windows.remove(&self.id);
+            return;
+        }
+        let piece_length = self.storage.torrent().info.piece_length;
+        let start = self.offset + self.position as usize;
+        let end = (start + self.readahead.max(1)).min(self.offset + self.length);
+        let pieces = start / piece_length..end.div_ceil(piece_length);
+        state.windows.insert(self.id, pieces);
+    }
+
+    /// Reads from the read position to the end of its piece, or of the file if that comes first.
+    fn start_read(&self) -> PendingRead {
+        let info = &self.storage.torrent().info;
+        let start = self.offset + self.position as usize;
+        let piece_index = start / info.piece_length;
+        let begin = start % info.piece_length;
+        let len = (info.piece_size(piece_index) - begin).min(self.offset + self.length - start);
+        let (control, storage) = (self.control.clone(), self.storage.clone());
+        Box::pin(async move {
+            control.wait_for(piece_index).await?;
+            tokio::task::spawn_blocking(move || {
+                let mut buf = vec![0; len];
+                storage.read_block(piece_index, begin, &mut buf)?;
+                Ok(buf)
+            })
+            .await
+            .map_err(io::Error::other)?
+        })
+    }
+}
+
+impl Drop for FileStream {
+    fn drop(&mut self) {
+        self.control.state().windows.remove(&self.id);
+    }
+}
+
+impl AsyncRead for FileStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.position >= this.length as u64 || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some((start, chunk)) = &this.chunk {
+                let available = this
+                    .position
+                    .checked_sub(*start)
+                    .and_then(|skip| chunk.get(skip as usize..))
+                    .filter(|rest| !rest.is_empty());
+                if let Some(rest) = available {
+                    let n = rest.len().min(buf.remaining());
+                    buf.put_slice(&rest[..n]);
+                    this.position += n as u64;
+                    this.update_window();
+                    return Poll::Ready(Ok(()));
+                }
+            }
+            if this.reading.is_none() {
+                this.update_window();
+                this.reading = Some((this.position, this.start_read()));
+            }
+            let (start, reading) = this.reading.as_mut().expect("a read is in progress");
+            let result = ready!(reading.as_mut().poll(cx));
+            let start = *start;
+            this.reading = None;
+            this.chunk = Some((start, result?));
+        }
+    }
+}
+
+impl AsyncSeek for FileStream {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => (this.length as u64).checked_add_signed(delta),
+            SeekFrom::Current(delta) => this.position.checked_add_signed(delta),
+        };
+        this.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+        // A read under way may be for somewhere else.
+        this.reading = None;
+        this.update_window();
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
@@ -1,18 +1,346 @@
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::OnceLock;
+
+use anyhow::Context;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_bytes::ByteBuf;
 
+use crate::merkle::{self, Hash};
 use crate::utils::compute_hash;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Info {
-    pub length: usize, // size of torrent file in bytes
-    pub name: String,
+    /// Size of the file in bytes, for single-file torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    /// The files of a multi-file torrent, laid out one after the other in this order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileEntry>>,
+    pub name: String, // file name, or directory name for multi-file torrents
     #[serde(rename = "piece length")]
     pub piece_length: usize, // number of bytes in each piece
-    pub pieces: ByteBuf, // concatenated SHA-1 hashes of each piece
+    /// Concatenated SHA-1 hashes of each piece; empty for v2-only torrents.
+    #[serde(default, skip_serializing_if = "is_empty")]
+    pub pieces: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>, // 1 keeps peer discovery to the trackers (BEP 27)
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u8>, // 2 for v2 and hybrid torrents (BEP 52)
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<FileTree>,
+    /// Worked out from the fields above when first needed.
+    #[serde(skip)]
+    pub layout: Derived<Layout>,
+}
+
+/// A value worked out from the fields next to it on first use, rather than on every call.
+/// Clones start out without it, so that their fields can still be changed; the fields of the
+/// original must not change once it has been worked out.
+pub struct Derived<T>(OnceLock<T>);
+
+impl<T> Derived<T> {
+    fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        self.0.get_or_init(f)
+    }
+}
+
+impl<T> Default for Derived<T> {
+    fn default() -> Self {
+        Self(OnceLock::new())
+    }
+}
+
+impl<T> Clone for Derived<T> {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl<T> fmt::Debug for Derived<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Derived")
+    }
+}
+
+/// How an [`Info`]'s content is laid out, so that piece lookups need not walk every file.
+pub struct Layout {
+    length: usize,
+    single_file: bool,
+    /// The files of the v2 file tree in order, each with the index of its first piece.
+    v2_files: Vec<(usize, V2File)>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FileEntry {
+    pub length: usize,
+    pub path: Vec<String>, // path components relative to the torrent's directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>, // `p` marks padding files (BEP 47)
+}
+
+impl FileEntry {
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+/// A directory of the v2 file tree, keyed by file or directory name.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct FileTree(pub BTreeMap<String, FileTreeNode>);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileTreeNode {
+    File(FileLeaf),
+    Directory(FileTree),
+}
+
+/// Files are told apart from directories by their single entry under the empty key.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileLeaf {
+    #[serde(rename = "")]
+    pub file: V2File,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct V2File {
+    pub length: usize,
+    /// Root of the file's merkle tree; missing for empty files.
+    #[serde(
+        rename = "pieces root",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<ByteBuf>,
+}
+
+impl FileTree {
+    /// Builds a tree out of `(path, file)` pairs.
+    pub fn from_files(files: impl IntoIterator<Item = (Vec<String>, V2File)>) -> Self {
+        let mut tree = FileTree::default();
+        for (path, file) in files {
+            let (name, dirs) = path.split_last().expect("paths are not empty");
+            let mut dir = &mut tree;
+            for component in dirs {
+                let node = dir
+                    .0
+                    .entry(component.clone())
+                    .or_insert_with(|| FileTreeNode::Directory(FileTree::default()));
+                dir = match node {
+                    FileTreeNode::Directory(subdir) => subdir,
+                    FileTreeNode::File(_) => panic!("{component} is both a file and a directory"),
+                };
+            }
+            dir.0
+                .insert(name.clone(), FileTreeNode::File(FileLeaf { file }));
+        }
+        tree
+    }
+
+    /// Every file in the tree with its path, in the order their pieces are laid out.
+    pub fn files(&self) -> Vec<(Vec<String>, &V2File)> {
+        let mut files = Vec::new();
+        self.collect_files(&mut Vec::new(), &mut files);
+        files
+    }
+
+    fn collect_files<'a>(
+        &'a self,
+        path: &mut Vec<String>,
+        files: &mut Vec<(Vec<String>, &'a V2File)>,
+    ) {
+        for (name, node) in &self.0 {
+            path.push(name.clone());
+            match node {
+                FileTreeNode::File(leaf) => files.push((path.clone(), &leaf.file)),
+                FileTreeNode::Directory(dir) => dir.collect_files(path, files),
+            }
+            path.pop();
+        }
+    }
+}
+
+/// A file's place in the torrent's content, where pieces are laid out back to back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSpan {
+    /// Path components; just the torrent's name for single-file torrents, and otherwise the
+    /// name of the torrent's directory followed by the file's path within it.
+    pub path: Vec<String>,
+    pub offset: usize,
+    pub length: usize,
+    /// Padding exists only to align the next file to a piece and is all zeros.
+    pub padding: bool,
+}
+
+/// Where a piece of a v2 torrent lies within its file.
+struct V2Piece<'a> {
+    file: &'a V2File,
+    index_in_file: usize,
+    size: usize,
 }
 
 impl Info {
+    fn layout(&self) -> &Layout {
+        self.layout.get_or_init(|| {
+            let v2_files = self
+                .file_tree
+                .as_ref()
+                .map_or_else(Vec::new, FileTree::files);
+            let length = match (&self.files, self.length) {
+                (Some(files), _) => files.iter().map(|file| file.length).sum(),
+                (None, Some(length)) => length,
+                // Every file but the last is padded out to a whole piece.
+                (None, None) => {
+                    let last = v2_files.len().saturating_sub(1);
+                    v2_files
+                        .iter()
+                        .enumerate()
+                        .map(|(i, (_, file))| match i == last {
+                            true => file.length,
+                            false => file.length.next_multiple_of(self.piece_length),
+                        })
+                        .sum()
+                }
+            };
+            let single_file = match (&self.files, self.length) {
+                (Some(_), _) => false,
+                (None, Some(_)) => true,
+                (None, None) if self.file_tree.is_some() => {
+                    v2_files.len() == 1 && v2_files[0].0 == [self.name.clone()]
+                }
+                (None, None) => true,
+            };
+            let mut first_piece = 0;
+            let v2_files = v2_files
+                .into_iter()
+                .map(|(_, file)| {
+                    let first = first_piece;
+                    first_piece += file.length.div_ceil(self.piece_length);
+                    (first, file.clone())
+                })
+                .collect();
+            Layout {
+                length,
+                single_file,
+                v2_files,
+            }
+        })
+    }
+
+    /// Size of the torrent's content in bytes. Every file starts on a piece boundary in v2
+    /// torrents, so this includes the padding between files, given explicitly for hybrids.
+    pub fn length(&self) -> usize {
+        self.layout().length
+    }
+
+    pub fn is_v1(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v1() && self.is_v2()
+    }
+
+    pub fn piece_count(&self) -> usize {
+        if self.is_v1() {
+            self.pieces.len() / 20
+        } else {
+            self.length().div_ceil(self.piece_length)
+        }
+    }
+
+    /// Size of piece `piece_index`; the last piece of each file may be short in v2 torrents.
+    pub fn piece_size(&self, piece_index: usize) -> usize {
+        if !self.is_v1() {
+            if let Some(piece) = self.v2_piece(piece_index) {
+                return piece.size;
+            }
+        }
+        let start = piece_index * self.piece_length;
+        self.piece_length.min(self.length().saturating_sub(start))
+    }
+
+    /// Single-file torrents name the file itself rather than a directory.
+    pub fn is_single_file(&self) -> bool {
+        self.layout().single_file
+    }
+
+    /// Every file of the torrent with the range of the content it occupies, in order.
+    pub fn file_spans(&self) -> Vec<FileSpan> {
+        if self.is_single_file() {
+            return vec![FileSpan {
+                path: vec![self.name.clone()],
+                offset: 0,
+                length: self.length(),
+                padding: false,
+            }];
+        }
+        let in_torrent_dir = |path: &[String]| {
+            std::iter::once(self.name.clone())
+                .chain(path.iter().cloned())
+                .collect::<Vec<_>>()
+        };
+        let mut spans = Vec::new();
+        let mut offset = 0;
+        let mut push = |path: Vec<String>, length: usize, padding: bool| {
+            spans.push(FileSpan {
+                path,
+                offset,
+                length,
+                padding,
+            });
+            offset += length;
+        };
+        if let Some(files) = &self.files {
+            for file in files {
+                push(in_torrent_dir(&file.path), file.length, file.is_padding());
+            }
+        } else if let Some(file_tree) = &self.file_tree {
+            let files = file_tree.files();
+            let last = files.len().saturating_sub(1);
+            for (i, (path, file)) in files.iter().enumerate() {
+                push(in_torrent_dir(path), file.length, false);
+                let padding = file.length.next_multiple_of(self.piece_length) - file.length;
+                if i != last && padding > 0 {
+                    let pad_path = [String::from(".pad"), padding.to_string()];
+                    push(in_torrent_dir(&pad_path), padding, true);
+                }
+            }
+        }
+        spans
+    }
+
+    fn v2_piece(&self, piece_index: usize) -> Option<V2Piece<'_>> {
+        let files = &self.layout().v2_files;
+        // Empty files share their first piece with the next file, which is the one holding it.
+        let i = files
+            .partition_point(|&(first_piece, _)| first_piece <= piece_index)
+            .checked_sub(1)?;
+        let (first_piece, file) = &files[i];
+        let index_in_file = piece_index - first_piece;
+        let start = index_in_file * self.piece_length;
+        (start < file.length).then(|| V2Piece {
+            file,
+            index_in_file,
+            size: self.piece_length.min(file.length - start),
+        })
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
     pub fn piece_hashes(&self) -> Vec<[u8; 20]> {
         self.pieces
             .chunks_exact(20)
@@ -21,15 +349,213 @@ impl Info {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Torrent {
+    /// Empty for torrents that only list their trackers in `announce-list`, or have none.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>, // tiers of tracker URLs (BEP 12)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>, // seconds since the Unix epoch
+    #[serde(
+        rename = "url-list",
+        default,
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub url_list: Option<Vec<String>>, // web seeds (BEP 19)
     pub info: Info,
+    /// Piece layers of v2 files larger than a piece, keyed by their `pieces root`.
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+    /// Hashes of the info dictionary, worked out when first needed.
+    #[serde(skip)]
+    pub info_hashes: Derived<InfoHashes>,
+}
+
+/// SHA-1 of the info dictionary, and its SHA-256 for v2 and hybrid torrents.
+pub struct InfoHashes {
+    v1: [u8; 20],
+    v2: Option<Hash>,
+}
+
+/// `url-list` is a single URL or a list of them.
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    }
+    .into())
 }
 
 impl Torrent {
+    /// Tracker tiers in the order they should be tried. `announce-list` supersedes `announce`
+    /// when present.
+    pub fn announce_tiers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .flatten()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        if tiers.is_empty() && self.announce.is_empty() {
+            Vec::new()
+        } else if tiers.is_empty() {
+            vec![vec![self.announce.clone()]]
+        } else {
+            tiers
+        }
+    }
+
+    /// Identifies the torrent's swarm: the SHA-1 of the info dictionary, or for v2-only
+    /// torrents its SHA-256 truncated to 20 bytes.
     pub fn info_hash(&self) -> [u8; 20] {
-        let bencoded_info = serde_bencode::to_bytes(&self.info).expect("info is serializable");
-        compute_hash(&bencoded_info)
+        if !self.info.is_v1() {
+            if let Some(info_hash) = self.info_hash_v2() {
+                return truncate(&info_hash);
+            }
+        }
+        self.info_hashes().v1
+    }
+
+    /// The SHA-256 of the info dictionary, for v2 and hybrid torrents.
+    pub fn info_hash_v2(&self) -> Option<Hash> {
+        self.info_hashes().v2
+    }
+
+    fn info_hashes(&self) -> &InfoHashes {
+        self.info_hashes.get_or_init(|| {
+            let bencoded_info = serde_bencode::to_bytes(&self.info).expect("info is serializable");
+            InfoHashes {
+                v1: compute_hash(&bencoded_info),
+                v2: self.info.is_v2().then(|| merkle::sha256(&bencoded_info)),
+            }
+        })
+    }
+
+    /// Every swarm sharing this torrent's content: hybrid torrents are in both a v1 and a v2
+    /// swarm, the latter under the truncated v2 info hash.
+    pub fn swarm_hashes(&self) -> Vec<[u8; 20]> {
+        let mut hashes = vec![self.info_hash()];
+        if self.info.is_hybrid() {
+            hashes.extend(self.info_hash_v2().map(|info_hash| truncate(&info_hash)));
+        }
+        hashes
     }
+
+    /// Checks downloaded piece data against the torrent: SHA-1 for v1 and hybrid torrents,
+    /// the file's merkle tree for v2-only torrents.
+    pub fn verify_piece(&self, piece_index: usize, data: &[u8]) -> bool {
+        if self.info.is_v1() {
+            let hashes = self.info.piece_hashes();
+            return hashes
+                .get(piece_index)
+                .is_some_and(|hash| *hash == compute_hash(&data.to_vec()));
+        }
+        let Some(piece) = self.info.v2_piece(piece_index) else {
+            return false;
+        };
+        if data.len() != piece.size {
+            return false;
+        }
+        let hash = merkle::piece_hash(data, piece.file.length, self.info.piece_length);
+        if piece.file.length <= self.info.piece_length {
+            return piece
+                .file
+                .pieces_root
+                .as_ref()
+                .is_some_and(|root| root[..] == hash);
+        }
+        self.piece_layer(piece.file)
+            .and_then(|layer| layer.get(piece.index_in_file).copied())
+            .is_some_and(|expected| expected == hash)
+    }
+
+    fn piece_layer(&self, file: &V2File) -> Option<Vec<Hash>> {
+        let layer = self
+            .piece_layers
+            .as_ref()?
+            .get(file.pieces_root.as_ref()?)?;
+        Some(
+            layer
+                .chunks_exact(32)
+                .map(|chunk| chunk.try_into().expect("chunk is 32 bytes"))
+                .collect(),
+        )
+    }
+
+    /// Makes sure every v2 file larger than a piece comes with a piece layer that hashes up to
+    /// its `pieces root`, so that its pieces can be verified.
+    pub fn check_piece_layers(&self) -> anyhow::Result<()> {
+        let Some(file_tree) = self.info.file_tree.as_ref().filter(|_| self.info.is_v2()) else {
+            return Ok(());
+        };
+        let piece_length = self.info.piece_length;
+        anyhow::ensure!(
+            piece_length.is_power_of_two() && piece_length >= merkle::BLOCK_SIZE,
+            "v2 piece length must be a power of two of at least 16 KiB"
+        );
+        for (path, file) in file_tree.files() {
+            let path = path.join("/");
+            if file.length == 0 {
+                continue;
+            }
+            let root = file
+                .pieces_root
+                .as_deref()
+                .with_context(|| format!("{path} has no pieces root"))?;
+            if file.length <= piece_length {
+                continue;
+            }
+            let layer = self
+                .piece_layer(file)
+                .with_context(|| format!("{path} has no piece layer"))?;
+            anyhow::ensure!(
+                layer.len() == file.length.div_ceil(piece_length)
+                    && merkle::root_from_piece_layer(&layer, piece_length)[..] == root[..],
+                "piece layer of {path} does not match its pieces root"
+            );
+        }
+        Ok(())
+    }
+}
+
+fn is_empty(bytes: &ByteBuf) -> bool {
+    bytes.is_empty()
+}
+
+fn truncate(info_hash: &Hash) -> [u8; 20] {
+    info_hash[..20]
+        .try_into()
+        .expect("SHA-256 is longer than 20 bytes")
 }
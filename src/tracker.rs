@@ -1,10 +1,42 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::Context;
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
+use crate::download::TransferStats;
+use crate::peer_id::{local_peer_id, tracker_key};
 use crate::torrent::Torrent;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError {
+    #[error("tracker rejected the request: {0}")]
+    Failure(String),
+    #[error("tracker response is missing the `{0}` field")]
+    MissingField(&'static str),
+    #[error("tracker response is not valid bencode")]
+    Malformed(#[from] serde_bencode::Error),
+    #[error("tracker could not be reached")]
+    Http(#[from] reqwest::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
     pub peer_id: String,
     pub port: u16,
@@ -12,59 +44,361 @@ pub struct TrackerRequest {
     pub downloaded: usize,
     pub left: usize,
     pub compact: u8,
+    pub trackerid: Option<String>,
+    pub event: Option<AnnounceEvent>,
+    pub key: String,
+}
+
+impl TrackerRequest {
+    /// An announce without an event for a download with `left` bytes to go, identifying us with
+    /// the process-wide peer id and tracker key.
+    pub fn new(left: usize) -> Self {
+        let peer_id = local_peer_id();
+        Self {
+            peer_id: String::from_utf8(peer_id.to_vec()).expect("peer id is alphanumeric"),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            compact: 1,
+            trackerid: None,
+            event: None,
+            key: format!("{:08X}", tracker_key()),
+        }
+    }
 }
 
+/// The raw dictionary sent by the tracker. Every field is optional because a rejection only
+/// carries `failure reason`.
 #[derive(Debug, Deserialize)]
+struct RawTrackerResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    interval: Option<i64>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<i64>,
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<String>,
+    complete: Option<i64>,
+    incomplete: Option<i64>,
+    peers: Option<RawPeers>,
+}
+
+/// Trackers send peers as a compact string unless asked not to, or when they ignore `compact`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawPeers {
+    Compact(ByteBuf),
+    List(Vec<RawPeer>),
+}
+
+#[derive(Debug, Deserialize)]
+struct RawPeer {
+    ip: String,
+    port: u16,
+}
+
+impl RawPeers {
+    fn into_addrs(self) -> Vec<SocketAddr> {
+        match self {
+            Self::Compact(peers) => peers
+                .chunks_exact(6)
+                .map(|chunk| {
+                    let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+                    let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+                    SocketAddr::V4(SocketAddrV4::new(ip, port))
+                })
+                .collect(),
+            // Peers given by host name are left out rather than resolved.
+            Self::List(peers) => peers
+                .into_iter()
+                .filter_map(|peer| {
+                    let ip: IpAddr = peer.ip.parse().ok()?;
+                    Some(SocketAddr::new(ip, peer.port))
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct TrackerResponse {
     pub interval: i64,
-    pub peers: ByteBuf,
+    pub min_interval: Option<i64>,
+    pub warning_message: Option<String>,
+    pub tracker_id: Option<String>,
+    pub complete: Option<i64>,   // number of seeders
+    pub incomplete: Option<i64>, // number of leechers
+    pub peers: Vec<SocketAddr>,
 }
 
 impl TrackerResponse {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TrackerError> {
+        let raw = serde_bencode::from_bytes::<RawTrackerResponse>(bytes)?;
+        if let Some(reason) = raw.failure_reason {
+            return Err(TrackerError::Failure(reason));
+        }
+        Ok(Self {
+            interval: raw.interval.ok_or(TrackerError::MissingField("interval"))?,
+            min_interval: raw.min_interval,
+            warning_message: raw.warning_message,
+            tracker_id: raw.tracker_id,
+            complete: raw.complete,
+            incomplete: raw.incomplete,
+            peers: raw
+                .peers
+                .ok_or(TrackerError::MissingField("peers"))?
+                .into_addrs(),
+        })
+    }
+
+    /// How long to wait before announcing again. Trackers may ask for a `min interval` that is
+    /// longer than the advertised `interval`, in which case the former wins.
+    pub fn reannounce_interval(&self) -> Duration {
+        let secs = self.interval.max(self.min_interval.unwrap_or(0)).max(0);
+        Duration::from_secs(secs as u64)
+    }
+
     pub fn get_peers(&self) -> Vec<String> {
-        self.peers
-            .chunks_exact(6)
-            .map(|chunk| {
-                format!(
-                    "{}.{}.{}.{}:{}",
-                    chunk[0],
-                    chunk[1],
-                    chunk[2],
-                    chunk[3],
-                    ((chunk[4] as u16) << 8 | chunk[5] as u16)
-                )
-            })
-            .collect()
-    }
-}
-
-fn urlencode(bytes: &[u8]) -> String {
+        self.peers.iter().map(|addr| addr.to_string()).collect()
+    }
+
+    pub fn peer_addrs(&self) -> Vec<SocketAddr> {
+        self.peers.clone()
+    }
+}
+
+pub(crate) fn urlencode(bytes: &[u8]) -> String {
     let mut encoded = String::with_capacity(3 * bytes.len());
     for &byte in bytes {
         encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+        encoded.push_str(&hex::encode([byte]));
     }
     encoded
 }
 
-pub async fn request_tracker(torrent: &Torrent) -> anyhow::Result<TrackerResponse> {
-    let tracker_req = TrackerRequest {
-        peer_id: String::from("00112233445566778899"),
-        port: 6881,
-        uploaded: 0,
-        downloaded: 0,
-        left: torrent.info.length,
-        compact: 1,
-    };
-    let url_params =
-        serde_urlencoded::to_string(&tracker_req).context("URL-encode TrackRequest")?;
+pub async fn announce(
+    announce_url: &str,
+    info_hash: &[u8; 20],
+    tracker_req: &TrackerRequest,
+) -> anyhow::Result<TrackerResponse> {
+    let url_params = serde_urlencoded::to_string(tracker_req).context("URL-encode TrackRequest")?;
     let url = format!(
         "{}?info_hash={}&{}",
-        &torrent.announce,
-        &urlencode(&torrent.info_hash()),
+        announce_url,
+        &urlencode(info_hash),
         &url_params
     );
 
-    let res = reqwest::get(&url).await?.bytes().await?;
-    serde_bencode::from_bytes::<TrackerResponse>(&res).context("Parse TrackerResponse")
+    let res = reqwest::get(&url)
+        .await
+        .map_err(TrackerError::from)?
+        .bytes()
+        .await
+        .map_err(TrackerError::from)?;
+    Ok(TrackerResponse::from_bytes(&res)?)
+}
+
+/// The trackers of a torrent, grouped in tiers as described by BEP 12. Trackers within a tier
+/// are shuffled once and a tracker that answers is moved to the front of its tier.
+#[derive(Debug, Clone)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+    // The `tracker id` each tracker gave us. It is only meaningful to the tracker that issued it,
+    // so it is sent back to that tracker alone.
+    tracker_ids: HashMap<String, String>,
+}
+
+impl TrackerTiers {
+    pub fn new(torrent: &Torrent) -> Self {
+        let mut tiers = torrent.announce_tiers();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rand::thread_rng());
+        }
+        Self {
+            tiers,
+            tracker_ids: HashMap::new(),
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    fn promote(&mut self, tier: usize, index: usize) {
+        let url = self.tiers[tier].remove(index);
+        self.tiers[tier].insert(0, url);
+    }
+
+    /// Tries every tracker in order until one answers, returning its URL along with the
+    /// response. Each tracker gets back the `tracker id` it handed out, if any.
+    pub async fn announce(
+        &mut self,
+        info_hash: &[u8; 20],
+        tracker_req: &TrackerRequest,
+    ) -> anyhow::Result<(String, TrackerResponse)> {
+        let mut last_err = None;
+        for tier in 0..self.tiers.len() {
+            for index in 0..self.tiers[tier].len() {
+                let url = self.tiers[tier][index].clone();
+                let tracker_req = TrackerRequest {
+                    trackerid: self.tracker_ids.get(&url).cloned(),
+                    ..tracker_req.clone()
+                };
+                match announce(&url, info_hash, &tracker_req).await {
+                    Ok(res) => {
+                        if let Some(id) = &res.tracker_id {
+                            self.tracker_ids.insert(url.clone(), id.clone());
+                        }
+                        self.promote(tier, index);
+                        return Ok((url, res));
+                    }
+                    Err(err) => last_err = Some(err.context(format!("announce to {url}"))),
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("torrent has no trackers")))
+    }
+}
+
+pub async fn request_tracker(torrent: &Torrent) -> anyhow::Result<TrackerResponse> {
+    let tracker_req = TrackerRequest::new(torrent.info.length());
+    let mut tiers = TrackerTiers::new(torrent);
+    let (_, res) = tiers.announce(&torrent.info_hash(), &tracker_req).await?;
+    Ok(res)
+}
+
+/// Retry delay used when an announce fails and the tracker never told us its interval.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keeps the torrent's trackers informed about our progress for the lifetime of a download, and
+/// forwards every peer they hand out to the download engine.
+pub struct TrackerSession {
+    trackers: TrackerTiers,
+    info_hash: [u8; 20],
+    stats: Arc<TransferStats>,
+    peers_tx: mpsc::Sender<SocketAddr>,
+    // The port we accept peers on, if not the default one.
+    port: Option<u16>,
+}
+
+impl TrackerSession {
+    pub fn new(
+        torrent: &Torrent,
+        stats: Arc<TransferStats>,
+        peers_tx: mpsc::Sender<SocketAddr>,
+    ) -> Self {
+        Self {
+            trackers: TrackerTiers::new(torrent),
+            info_hash: torrent.info_hash(),
+            stats,
+            peers_tx,
+            port: None,
+        }
+    }
+
+    /// Announces under `info_hash` instead, such as the v2 swarm of a hybrid torrent.
+    pub fn with_info_hash(mut self, info_hash: [u8; 20]) -> Self {
+        self.info_hash = info_hash;
+        self
+    }
+
+    /// Tells the trackers that peers reach us on `port`.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub async fn announce(
+        &mut self,
+        event: Option<AnnounceEvent>,
+    ) -> anyhow::Result<TrackerResponse> {
+        let mut tracker_req = TrackerRequest {
+            uploaded: self.stats.uploaded.load(Ordering::Relaxed),
+            downloaded: self.stats.downloaded.load(Ordering::Relaxed),
+            event,
+            ..TrackerRequest::new(self.stats.left.load(Ordering::Relaxed))
+        };
+        if let Some(port) = self.port {
+            tracker_req.port = port;
+        }
+        let (_, res) = self
+            .trackers
+            .announce(&self.info_hash, &tracker_req)
+            .await?;
+        if let Some(warning) = &res.warning_message {
+            eprintln!("Tracker warning: {warning}");
+        }
+        for addr in res.peer_addrs() {
+            // The engine going away just means nobody needs peers anymore.
+            let _ = self.peers_tx.send(addr).await;
+        }
+        Ok(res)
+    }
+
+    /// Announces `started`, then re-announces every interval until `shutdown` fires. Flipping
+    /// `completed` to true sends a one-off `completed` event, held back until `started` got
+    /// through; shutting down sends `stopped`.
+    pub async fn run(
+        mut self,
+        mut completed: watch::Receiver<bool>,
+        shutdown: CancellationToken,
+    ) -> anyhow::Result<()> {
+        let mut next_event = Some(AnnounceEvent::Started);
+        let mut next_announce = Instant::now();
+        let mut watching_completion = true;
+        // Completion to report once the tracker has heard of us.
+        let mut completion_pending = false;
+        loop {
+            tokio::select! {
+                // Completion is checked first so that finishing and shutting down right after
+                // still reports `completed` before `stopped`.
+                biased;
+                changed = completed.changed(), if watching_completion => {
+                    if changed.is_err() {
+                        watching_completion = false;
+                        continue;
+                    }
+                    if !*completed.borrow() {
+                        continue;
+                    }
+                    watching_completion = false;
+                    if next_event == Some(AnnounceEvent::Started) {
+                        completion_pending = true;
+                        continue;
+                    }
+                    next_event = Some(AnnounceEvent::Completed);
+                }
+                _ = shutdown.cancelled() => break,
+                _ = tokio::time::sleep_until(next_announce) => {}
+            }
+            let interval = match self.announce(next_event).await {
+                Ok(_) if completion_pending => {
+                    completion_pending = false;
+                    next_event = Some(AnnounceEvent::Completed);
+                    Duration::ZERO
+                }
+                Ok(res) => {
+                    next_event = None;
+                    res.reannounce_interval()
+                }
+                Err(err) => {
+                    eprintln!("Announce failed: {err:#}");
+                    RETRY_INTERVAL
+                }
+            };
+            next_announce = Instant::now() + interval;
+        }
+        // `started` never reached the tracker, so there is nothing to stop.
+        if next_event == Some(AnnounceEvent::Started) {
+            if completion_pending {
+                eprintln!("Tracker never answered, so the completed download went unreported.");
+            }
+            return Ok(());
+        }
+        self.announce(Some(AnnounceEvent::Stopped)).await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,390 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use rand::seq::IteratorRandom;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+use crate::scrape::ScrapeStats;
+
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_NUMWANT: usize = 50;
+const MAX_NUMWANT: usize = 200;
+
+#[derive(Debug, Clone)]
+pub struct TrackerServerConfig {
+    pub bind_addr: SocketAddr,
+    /// Announce interval handed out to clients.
+    pub interval: Duration,
+    /// Peers that have not announced for this long are forgotten.
+    pub peer_ttl: Duration,
+}
+
+impl Default for TrackerServerConfig {
+    fn default() -> Self {
+        let interval = Duration::from_secs(30 * 60);
+        Self {
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 6969)),
+            interval,
+            // Allow for one missed announce before dropping a peer.
+            peer_ttl: interval * 2 + Duration::from_secs(60),
+        }
+    }
+}
+
+struct PeerEntry {
+    addr: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], PeerEntry>,
+    completed: i64,
+}
+
+impl Swarm {
+    fn expire(&mut self, peer_ttl: Duration) {
+        self.peers
+            .retain(|_, peer| peer.last_seen.elapsed() < peer_ttl);
+    }
+
+    fn stats(&self) -> ScrapeStats {
+        let seeders = self.peers.values().filter(|peer| peer.left == 0).count() as i64;
+        ScrapeStats {
+            complete: seeders,
+            downloaded: self.completed,
+            incomplete: self.peers.len() as i64 - seeders,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: String,
+}
+
+#[derive(Serialize)]
+struct PeerDict {
+    #[serde(rename = "peer id")]
+    peer_id: ByteBuf,
+    ip: String,
+    port: u16,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Peers {
+    Compact(ByteBuf),
+    List(Vec<PeerDict>),
+}
+
+#[derive(Serialize)]
+struct AnnounceResponse {
+    interval: u64,
+    complete: i64,
+    incomplete: i64,
+    peers: Peers,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peers6: Option<ByteBuf>,
+}
+
+#[derive(Serialize)]
+struct ScrapeResponse {
+    files: BTreeMap<ByteBuf, ScrapeStats>,
+}
+
+/// Decodes a percent-encoded query string component into raw bytes, as info hashes and peer ids
+/// are not necessarily valid UTF-8.
+fn urldecode(value: &str) -> Option<Vec<u8>> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(decoded)
+}
+
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = String::from_utf8(urldecode(name)?).ok()?;
+            Some((name, urldecode(value)?))
+        })
+        .collect()
+}
+
+/// A minimal HTTP tracker keeping swarms in memory, for local networks and tests.
+pub struct TrackerServer {
+    listener: TcpListener,
+    config: TrackerServerConfig,
+    swarms: Arc<Mutex<HashMap<[u8; 20], Swarm>>>,
+}
+
+impl TrackerServer {
+    pub async fn bind(config: TrackerServerConfig) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(config.bind_addr)
+            .await
+            .context("bind tracker")?;
+        Ok(Self {
+            listener,
+            config,
+            swarms: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The URL to put in the `announce` field of torrents tracked here.
+    pub fn announce_url(&self) -> std::io::Result<String> {
+        Ok(format!("http://{}/announce", self.local_addr()?))
+    }
+
+    /// Answers requests until `shutdown` fires.
+    pub async fn run(self, shutdown: CancellationToken) {
+        let this = Arc::new(self);
+        let mut sweep = tokio::time::interval(this.config.interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = sweep.tick() => this.sweep(),
+                accepted = this.listener.accept() => {
+                    let Ok((stream, from)) = accepted else {
+                        continue;
+                    };
+                    let this = this.clone();
+                    tokio::spawn(async move {
+                        let handled = tokio::time::timeout(
+                            REQUEST_TIMEOUT,
+                            this.handle_connection(stream, from),
+                        );
+                        if let Ok(Err(err)) = handled.await {
+                            eprintln!("Tracker request from {from} failed: {err:#}");
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    fn swarms(&self) -> std::sync::MutexGuard<'_, HashMap<[u8; 20], Swarm>> {
+        self.swarms
+            .lock()
+            .expect("tracker swarms lock is not poisoned")
+    }
+
+    /// Forgets stale peers, and torrents nobody is sharing anymore.
+    fn sweep(&self) {
+        let mut swarms = self.swarms();
+        for swarm in swarms.values_mut() {
+            swarm.expire(self.config.peer_ttl);
+        }
+        swarms.retain(|_, swarm| !swarm.peers.is_empty() || swarm.completed > 0);
+    }
+
+    async fn handle_connection(
+        &self,
+        mut stream: TcpStream,
+        from: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let mut request = Vec::new();
+        let mut chunk = [0u8; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            anyhow::ensure!(request.len() < MAX_REQUEST_LEN, "request is too long");
+            let n = stream.read(&mut chunk).await?;
+            anyhow::ensure!(n > 0, "client closed the connection");
+            request.extend_from_slice(&chunk[..n]);
+        }
+        let request = String::from_utf8_lossy(&request);
+        let target = request
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix("GET "))
+            .and_then(|line| line.split(' ').next())
+            .context("not a GET request")?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let params = parse_query(query);
+
+        let (status, body) = if path.ends_with("/announce") {
+            ("200 OK", self.announce(&params, from.ip()))
+        } else if path.ends_with("/scrape") {
+            ("200 OK", self.scrape(&params))
+        } else {
+            ("404 Not Found", Vec::new())
+        };
+        let mut response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend(body);
+        stream.write_all(&response).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    fn announce(&self, params: &[(String, Vec<u8>)], ip: IpAddr) -> Vec<u8> {
+        match self.try_announce(params, ip) {
+            Ok(body) => body,
+            Err(err) => failure(err),
+        }
+    }
+
+    fn try_announce(&self, params: &[(String, Vec<u8>)], ip: IpAddr) -> Result<Vec<u8>, String> {
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_slice())
+        };
+        let number = |name: &str| -> Result<Option<u64>, String> {
+            param(name)
+                .map(|value| {
+                    std::str::from_utf8(value)
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                        .ok_or_else(|| format!("invalid {name}"))
+                })
+                .transpose()
+        };
+        let info_hash: [u8; 20] = param("info_hash")
+            .and_then(|value| value.try_into().ok())
+            .ok_or("missing or invalid info_hash")?;
+        let peer_id: [u8; 20] = param("peer_id")
+            .and_then(|value| value.try_into().ok())
+            .ok_or("missing or invalid peer_id")?;
+        let port = number("port")?.ok_or("missing port")?;
+        let port = u16::try_from(port).map_err(|_| String::from("invalid port"))?;
+        let left = number("left")?.unwrap_or(0);
+        let compact = number("compact")?.unwrap_or(1) == 1;
+        let numwant = number("numwant")?
+            .map_or(DEFAULT_NUMWANT, |numwant| numwant as usize)
+            .min(MAX_NUMWANT);
+        let event = param("event").unwrap_or_default();
+
+        let mut swarms = self.swarms();
+        let swarm = swarms.entry(info_hash).or_default();
+        swarm.expire(self.config.peer_ttl);
+        match event {
+            b"stopped" => {
+                swarm.peers.remove(&peer_id);
+            }
+            _ => {
+                if event == b"completed" {
+                    swarm.completed += 1;
+                }
+                swarm.peers.insert(
+                    peer_id,
+                    PeerEntry {
+                        addr: SocketAddr::new(ip, port),
+                        left,
+                        last_seen: Instant::now(),
+                    },
+                );
+            }
+        }
+
+        let others = swarm
+            .peers
+            .iter()
+            .filter(|(id, _)| **id != peer_id)
+            .choose_multiple(&mut rand::thread_rng(), numwant);
+        let (peers, peers6) = if compact {
+            let mut peers = Vec::new();
+            let mut peers6 = Vec::new();
+            for (_, peer) in &others {
+                match peer.addr {
+                    SocketAddr::V4(addr) => {
+                        peers.extend(addr.ip().octets());
+                        peers.extend(addr.port().to_be_bytes());
+                    }
+                    SocketAddr::V6(addr) => {
+                        peers6.extend(addr.ip().octets());
+                        peers6.extend(addr.port().to_be_bytes());
+                    }
+                }
+            }
+            let peers6 = (!peers6.is_empty()).then(|| ByteBuf::from(peers6));
+            (Peers::Compact(ByteBuf::from(peers)), peers6)
+        } else {
+            let peers = others
+                .iter()
+                .map(|(id, peer)| PeerDict {
+                    peer_id: ByteBuf::from(id.to_vec()),
+                    ip: peer.addr.ip().to_string(),
+                    port: peer.addr.port(),
+                })
+                .collect();
+            (Peers::List(peers), None)
+        };
+
+        let stats = swarm.stats();
+        let response = AnnounceResponse {
+            interval: self.config.interval.as_secs(),
+            complete: stats.complete,
+            incomplete: stats.incomplete,
+            peers,
+            peers6,
+        };
+        serde_bencode::to_bytes(&response).map_err(|err| err.to_string())
+    }
+
+    /// Reports on the requested torrents, or on every torrent when none is named.
+    fn scrape(&self, params: &[(String, Vec<u8>)]) -> Vec<u8> {
+        let mut swarms = self.swarms();
+        let requested: Vec<[u8; 20]> = params
+            .iter()
+            .filter(|(key, _)| key == "info_hash")
+            .filter_map(|(_, value)| value[..].try_into().ok())
+            .collect();
+        let info_hashes = if requested.is_empty() {
+            swarms.keys().copied().collect()
+        } else {
+            requested
+        };
+        let mut files = BTreeMap::new();
+        for info_hash in info_hashes {
+            if let Some(swarm) = swarms.get_mut(&info_hash) {
+                swarm.expire(self.config.peer_ttl);
+                files.insert(ByteBuf::from(info_hash.to_vec()), swarm.stats());
+            }
+        }
+        serde_bencode::to_bytes(&ScrapeResponse { files })
+            .unwrap_or_else(|err| failure(err.to_string()))
+    }
+}
+
+fn failure(reason: String) -> Vec<u8> {
+    serde_bencode::to_bytes(&FailureResponse {
+        failure_reason: reason,
+    })
+    .expect("failure response is serializable")
+}
@@ -0,0 +1,130 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::mse::{EncryptionPolicy, MseStream};
+use crate::utp::{UtpSocket, UtpStream};
+
+/// How long to wait for a uTP answer before assuming the peer only speaks TCP.
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A connection to a peer, over whichever transport was negotiated.
+pub enum PeerStream {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+    Encrypted(Box<MseStream<PeerStream>>),
+}
+
+impl From<TcpStream> for PeerStream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+impl PeerStream {
+    pub fn is_encrypted(&self) -> bool {
+        match self {
+            Self::Tcp(_) | Self::Utp(_) => false,
+            Self::Encrypted(stream) => stream.is_encrypted(),
+        }
+    }
+}
+
+async fn connect_tcp(addr: SocketAddr, timeout: Duration) -> anyhow::Result<TcpStream> {
+    tokio::time::timeout(timeout, TcpStream::connect(addr))
+        .await
+        .context("connect timed out")?
+        .context("connect")
+}
+
+/// Tries uTP first when a socket is available, falling back to TCP.
+async fn connect_raw(
+    addr: SocketAddr,
+    utp: Option<&UtpSocket>,
+    timeout: Duration,
+) -> anyhow::Result<PeerStream> {
+    if let Some(utp) = utp {
+        let utp_timeout = timeout.min(UTP_CONNECT_TIMEOUT);
+        if let Ok(Ok(stream)) = tokio::time::timeout(utp_timeout, utp.connect(addr)).await {
+            return Ok(PeerStream::Utp(stream));
+        }
+    }
+    Ok(PeerStream::Tcp(connect_tcp(addr, timeout).await?))
+}
+
+/// Connects to `addr` for the torrent `info_hash` over uTP if `utp` is given and the peer
+/// answers, or TCP otherwise, encrypting the connection as `policy` asks. When encryption is
+/// merely preferred, peers that fail the MSE handshake are retried in plaintext.
+pub async fn connect(
+    addr: SocketAddr,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+    utp: Option<&UtpSocket>,
+    timeout: Duration,
+) -> anyhow::Result<PeerStream> {
+    if policy == EncryptionPolicy::Plaintext {
+        return connect_raw(addr, utp, timeout).await;
+    }
+    let stream = connect_raw(addr, utp, timeout).await?;
+    // No need to wait on uTP again when the peer did not answer it the first time.
+    let utp = utp.filter(|_| matches!(stream, PeerStream::Utp(_)));
+    let encrypted = tokio::time::timeout(timeout, MseStream::connect(stream, info_hash, policy))
+        .await
+        .context("MSE handshake timed out")
+        .and_then(|result| result);
+    match encrypted {
+        Ok(stream) => Ok(PeerStream::Encrypted(Box::new(stream))),
+        Err(err) if policy == EncryptionPolicy::Require => Err(err),
+        Err(_) => connect_raw(addr, utp, timeout).await,
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Encrypted(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Encrypted(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Utp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Encrypted(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Encrypted(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,734 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::task::{Context as TaskContext, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+const VERSION: u8 = 1;
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+
+const HEADER_LEN: usize = 20;
+/// Payload per packet, small enough to avoid IP fragmentation on common links.
+const PACKET_SIZE: usize = 1400;
+const MIN_WINDOW: usize = PACKET_SIZE;
+const RECV_WINDOW: usize = 1 << 20;
+/// Out-of-order packets kept while waiting for a gap to be filled.
+const MAX_OUT_OF_ORDER: usize = 1024;
+
+/// LEDBAT aims to add no more than this much queueing delay (BEP 29).
+const TARGET_DELAY_MICROS: f64 = 100_000.0;
+const MAX_CWND_INCREASE_PER_RTT: f64 = 3000.0;
+/// The base delay is re-measured periodically in case the route changed.
+const BASE_DELAY_LIFETIME: Duration = Duration::from_secs(60);
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(8);
+const MAX_RESENDS: u32 = 6;
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+fn now_micros() -> u32 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wrap-around.
+fn seq_before(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    kind: u8,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+}
+
+impl Header {
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+        packet.push(self.kind << 4 | VERSION);
+        packet.push(0); // no extensions
+        packet.extend(self.connection_id.to_be_bytes());
+        packet.extend(self.timestamp.to_be_bytes());
+        packet.extend(self.timestamp_diff.to_be_bytes());
+        packet.extend(self.wnd_size.to_be_bytes());
+        packet.extend(self.seq_nr.to_be_bytes());
+        packet.extend(self.ack_nr.to_be_bytes());
+        packet.extend(payload);
+        packet
+    }
+
+    fn decode(packet: &[u8]) -> Option<(Self, &[u8])> {
+        if packet.len() < HEADER_LEN || packet[0] & 0x0f != VERSION {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(packet[i..i + 4].try_into().expect("4 bytes"));
+        let header = Self {
+            kind: packet[0] >> 4,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+        };
+        if header.kind > ST_SYN {
+            return None;
+        }
+        // We do not support any extension, such as selective acks, but must skip over them.
+        let mut extension = packet[1];
+        let mut offset = HEADER_LEN;
+        while extension != 0 {
+            let len = *packet.get(offset + 1)? as usize;
+            extension = packet[offset];
+            offset += 2 + len;
+        }
+        Some((header, packet.get(offset..)?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    Reset,
+}
+
+struct SentPacket {
+    kind: u8,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    resends: u32,
+}
+
+/// The state of one uTP connection, driven both by its stream handle and by the socket's
+/// receive loop.
+struct Connection {
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    state: State,
+    recv_id: u16,
+    send_id: u16,
+    seq_nr: u16,
+    ack_nr: u16,
+
+    in_flight: VecDeque<SentPacket>,
+    cur_window: usize,
+    max_window: usize,
+    peer_window: usize,
+    last_ack: u16,
+    duplicate_acks: usize,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    base_delay: Option<u32>,
+    base_delay_since: Instant,
+    // How long the peer's last packet took to reach us, echoed back in every packet.
+    reply_micro: u32,
+    fin_sent: bool,
+    // The stream was dropped; the connection stays until its FIN is acknowledged or times out.
+    closed: bool,
+
+    recv_buf: VecDeque<u8>,
+    out_of_order: HashMap<u16, (u8, Vec<u8>)>,
+    eof: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(socket: Arc<UdpSocket>, addr: SocketAddr, recv_id: u16, send_id: u16) -> Self {
+        Self {
+            socket,
+            addr,
+            state: State::SynSent,
+            recv_id,
+            send_id,
+            seq_nr: 1,
+            ack_nr: 0,
+            in_flight: VecDeque::new(),
+            cur_window: 0,
+            max_window: MIN_WINDOW * 2,
+            peer_window: RECV_WINDOW,
+            last_ack: 0,
+            duplicate_acks: 0,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: INITIAL_RTO,
+            base_delay: None,
+            base_delay_since: Instant::now(),
+            reply_micro: 0,
+            fin_sent: false,
+            closed: false,
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            eof: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn send_packet(&self, kind: u8, seq_nr: u16, payload: &[u8]) {
+        let header = Header {
+            kind,
+            // The SYN carries the id the peer will use to reach us.
+            connection_id: if kind == ST_SYN {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+            timestamp: now_micros(),
+            timestamp_diff: self.reply_micro,
+            wnd_size: RECV_WINDOW.saturating_sub(self.recv_buf.len()) as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+        };
+        // Lost packets are retransmitted, so a full socket buffer is no different from loss.
+        let _ = self.socket.try_send_to(&header.encode(payload), self.addr);
+    }
+
+    /// Sends a packet that takes up a sequence number and must be acknowledged.
+    fn send_reliable(&mut self, kind: u8, payload: &[u8]) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.send_packet(kind, seq_nr, payload);
+        self.cur_window += payload.len();
+        self.in_flight.push_back(SentPacket {
+            kind,
+            seq_nr,
+            payload: payload.to_vec(),
+            sent_at: Instant::now(),
+            resends: 0,
+        });
+    }
+
+    /// Whether a dropped stream's connection has nothing left to deliver.
+    fn finished(&self) -> bool {
+        self.closed && (self.in_flight.is_empty() || self.state != State::Connected)
+    }
+
+    fn send_ack(&self) {
+        self.send_packet(ST_STATE, self.seq_nr, &[]);
+    }
+
+    fn resend_oldest(&mut self) {
+        let Some(packet) = self.in_flight.front_mut() else {
+            return;
+        };
+        packet.resends += 1;
+        packet.sent_at = Instant::now();
+        let (kind, seq_nr, payload) = (packet.kind, packet.seq_nr, packet.payload.clone());
+        self.send_packet(kind, seq_nr, &payload);
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn on_packet(&mut self, header: Header, payload: &[u8]) {
+        self.reply_micro = now_micros().wrapping_sub(header.timestamp);
+        self.peer_window = header.wnd_size as usize;
+        match (self.state, header.kind) {
+            (_, ST_RESET) => {
+                self.state = State::Reset;
+                self.wake();
+                return;
+            }
+            (State::SynSent, ST_STATE) => {
+                self.state = State::Connected;
+                // The first data packet from the peer reuses the sequence number of its ack.
+                self.ack_nr = header.seq_nr.wrapping_sub(1);
+            }
+            (State::SynSent, _) | (State::Reset, _) => return,
+            // The peer missed our answer to its SYN.
+            (_, ST_SYN) => {
+                self.send_ack();
+                return;
+            }
+            _ => {}
+        }
+        self.on_ack(header.ack_nr, header.timestamp_diff);
+        if header.kind == ST_DATA || header.kind == ST_FIN {
+            self.on_data(header.kind, header.seq_nr, payload);
+        }
+        self.wake();
+    }
+
+    fn on_ack(&mut self, ack_nr: u16, delay: u32) {
+        let mut acked_bytes = 0;
+        let mut rtt_sample = None;
+        while let Some(packet) = self.in_flight.front() {
+            if seq_before(ack_nr, packet.seq_nr) {
+                break;
+            }
+            // Karn's algorithm: retransmitted packets give ambiguous round trips.
+            if packet.resends == 0 {
+                rtt_sample = Some(packet.sent_at.elapsed());
+            }
+            acked_bytes += packet.payload.len();
+            self.in_flight.pop_front();
+        }
+        self.cur_window -= acked_bytes;
+
+        if acked_bytes == 0 && rtt_sample.is_none() {
+            if ack_nr == self.last_ack && !self.in_flight.is_empty() {
+                self.duplicate_acks += 1;
+                if self.duplicate_acks == 3 {
+                    self.resend_oldest();
+                }
+            }
+            return;
+        }
+        self.last_ack = ack_nr;
+        self.duplicate_acks = 0;
+        if let Some(sample) = rtt_sample {
+            self.update_rtt(sample);
+        }
+        if acked_bytes > 0 {
+            self.update_window(acked_bytes, delay);
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let rtt = match self.rtt {
+            None => {
+                self.rtt_var = sample / 2;
+                sample
+            }
+            Some(rtt) => {
+                let delta = Duration::from_micros(
+                    (rtt.as_micros() as i64 - sample.as_micros() as i64).unsigned_abs(),
+                );
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                (rtt * 7 + sample) / 8
+            }
+        };
+        self.rtt = Some(rtt);
+        self.rto = (rtt + self.rtt_var * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// LEDBAT: grow the window while the queueing delay our packets see is under target, and
+    /// shrink it once it goes over.
+    fn update_window(&mut self, acked_bytes: usize, delay: u32) {
+        if delay == 0 {
+            // The peer has not measured anything yet.
+            return;
+        }
+        if self.base_delay_since.elapsed() > BASE_DELAY_LIFETIME {
+            self.base_delay = None;
+            self.base_delay_since = Instant::now();
+        }
+        let base_delay = self.base_delay.map_or(delay, |base| base.min(delay));
+        self.base_delay = Some(base_delay);
+        let queueing_delay = delay.wrapping_sub(base_delay) as f64;
+        let off_target = (TARGET_DELAY_MICROS - queueing_delay) / TARGET_DELAY_MICROS;
+        let window_factor =
+            acked_bytes.min(self.max_window) as f64 / acked_bytes.max(self.max_window) as f64;
+        let gain = MAX_CWND_INCREASE_PER_RTT * off_target * window_factor;
+        self.max_window = (self.max_window as f64 + gain).max(MIN_WINDOW as f64) as usize;
+    }
+
+    fn on_data(&mut self, kind: u8, seq_nr: u16, payload: &[u8]) {
+        let expected = self.ack_nr.wrapping_add(1);
+        if seq_nr == expected {
+            self.accept(kind, payload);
+            while let Some((kind, payload)) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1))
+            {
+                self.accept(kind, &payload);
+            }
+        } else if seq_before(expected, seq_nr) && self.out_of_order.len() < MAX_OUT_OF_ORDER {
+            self.out_of_order.insert(seq_nr, (kind, payload.to_vec()));
+        }
+        self.send_ack();
+    }
+
+    fn accept(&mut self, kind: u8, payload: &[u8]) {
+        self.ack_nr = self.ack_nr.wrapping_add(1);
+        if self.eof {
+            return;
+        }
+        if kind == ST_FIN {
+            self.eof = true;
+        } else {
+            self.recv_buf.extend(payload);
+        }
+    }
+
+    fn on_tick(&mut self) {
+        let Some(oldest) = self.in_flight.front() else {
+            return;
+        };
+        if self.state == State::Reset || oldest.sent_at.elapsed() < self.rto {
+            return;
+        }
+        if oldest.resends >= MAX_RESENDS {
+            self.state = State::Reset;
+            self.wake();
+            return;
+        }
+        // A timeout means heavy congestion: start over from the smallest window.
+        self.max_window = MIN_WINDOW;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.resend_oldest();
+    }
+}
+
+type ConnectionKey = (SocketAddr, u16);
+
+struct UtpInner {
+    socket: Arc<UdpSocket>,
+    connections: Mutex<HashMap<ConnectionKey, Arc<Mutex<Connection>>>>,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<(UtpStream, SocketAddr)>>,
+    shutdown: CancellationToken,
+}
+
+impl Drop for UtpInner {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+impl UtpInner {
+    fn connections(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<ConnectionKey, Arc<Mutex<Connection>>>> {
+        self.connections
+            .lock()
+            .expect("uTP connections lock is not poisoned")
+    }
+}
+
+/// A UDP socket multiplexing uTP (BEP 29) connections. Cloning yields another handle to the same
+/// socket; it is closed once every handle and every stream is dropped.
+#[derive(Clone)]
+pub struct UtpSocket {
+    inner: Arc<UtpInner>,
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await.context("bind uTP socket")?);
+        let (incoming_tx, incoming_rx) = mpsc::channel(32);
+        let inner = Arc::new(UtpInner {
+            socket: socket.clone(),
+            connections: Mutex::new(HashMap::new()),
+            incoming: tokio::sync::Mutex::new(incoming_rx),
+            shutdown: CancellationToken::new(),
+        });
+        tokio::spawn(receive_loop(
+            Arc::downgrade(&inner),
+            socket,
+            incoming_tx,
+            inner.shutdown.clone(),
+        ));
+        Ok(Self { inner })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+
+    /// Opens a connection to `addr`, failing if the peer resets it or never answers.
+    pub async fn connect(&self, addr: SocketAddr) -> anyhow::Result<UtpStream> {
+        let (recv_id, conn) = {
+            let mut connections = self.inner.connections();
+            let recv_id = loop {
+                let id = rand::random::<u16>();
+                if !connections.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let mut conn = Connection::new(
+                self.inner.socket.clone(),
+                addr,
+                recv_id,
+                recv_id.wrapping_add(1),
+            );
+            conn.send_reliable(ST_SYN, &[]);
+            let conn = Arc::new(Mutex::new(conn));
+            connections.insert((addr, recv_id), conn.clone());
+            (recv_id, conn)
+        };
+        let stream = UtpStream {
+            socket: self.inner.clone(),
+            key: (addr, recv_id),
+            conn,
+        };
+        std::future::poll_fn(|cx| {
+            let mut conn = stream.conn();
+            match conn.state {
+                State::Connected => Poll::Ready(Ok(())),
+                State::Reset => {
+                    Poll::Ready(Err(anyhow::anyhow!("uTP connection to {addr} failed")))
+                }
+                State::SynSent => {
+                    conn.write_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    /// Waits for a peer to connect to us.
+    pub async fn accept(&self) -> anyhow::Result<(UtpStream, SocketAddr)> {
+        self.inner
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .context("uTP socket was shut down")
+    }
+}
+
+async fn receive_loop(
+    inner: Weak<UtpInner>,
+    socket: Arc<UdpSocket>,
+    incoming_tx: mpsc::Sender<(UtpStream, SocketAddr)>,
+    shutdown: CancellationToken,
+) {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut tick = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        let received = tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = tick.tick() => None,
+            received = socket.recv_from(&mut buf) => Some(received),
+        };
+        // Only hold on to the socket while handling a packet, so that dropping the last handle
+        // shuts it down.
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let Some(received) = received else {
+            let connections: Vec<_> = inner.connections().values().cloned().collect();
+            for conn in connections {
+                conn.lock()
+                    .expect("uTP connection lock is not poisoned")
+                    .on_tick();
+            }
+            inner.connections().retain(|_, conn| {
+                !conn
+                    .lock()
+                    .expect("uTP connection lock is not poisoned")
+                    .finished()
+            });
+            continue;
+        };
+        let Ok((n, from)) = received else {
+            continue;
+        };
+        let Some((header, payload)) = Header::decode(&buf[..n]) else {
+            continue;
+        };
+
+        // A SYN names the id the initiator listens on; we listen on the next one.
+        let recv_id = if header.kind == ST_SYN {
+            header.connection_id.wrapping_add(1)
+        } else {
+            header.connection_id
+        };
+        let existing = {
+            let connections = inner.connections();
+            let existing = connections.get(&(from, recv_id)).cloned();
+            // A reset carries the id of the packet it answers, which is the one we send with.
+            existing.or_else(|| {
+                (header.kind == ST_RESET)
+                    .then(|| [recv_id.wrapping_sub(1), recv_id.wrapping_add(1)])
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|id| connections.get(&(from, id)))
+                    .find(|conn| {
+                        conn.lock()
+                            .expect("uTP connection lock is not poisoned")
+                            .send_id
+                            == recv_id
+                    })
+                    .cloned()
+            })
+        };
+        if let Some(conn) = existing {
+            conn.lock()
+                .expect("uTP connection lock is not poisoned")
+                .on_packet(header, payload);
+        } else if header.kind == ST_SYN {
+            let mut conn = Connection::new(socket.clone(), from, recv_id, header.connection_id);
+            conn.state = State::Connected;
+            conn.seq_nr = rand::random();
+            conn.ack_nr = header.seq_nr;
+            conn.reply_micro = now_micros().wrapping_sub(header.timestamp);
+            conn.send_ack();
+            let conn = Arc::new(Mutex::new(conn));
+            inner.connections().insert((from, recv_id), conn.clone());
+            let stream = UtpStream {
+                socket: inner.clone(),
+                key: (from, recv_id),
+                conn,
+            };
+            // Nobody accepting connections just means the stream is dropped and closed again.
+            let _ = incoming_tx.try_send((stream, from));
+        } else if header.kind != ST_RESET {
+            // Tell the peer we know nothing of the connection, so it stops sending.
+            let reset = Header {
+                kind: ST_RESET,
+                connection_id: header.connection_id,
+                timestamp: now_micros(),
+                timestamp_diff: 0,
+                wnd_size: 0,
+                seq_nr: rand::random(),
+                ack_nr: header.seq_nr,
+            };
+            let _ = socket.try_send_to(&reset.encode(&[]), from);
+        }
+    }
+}
+
+/// A uTP connection, used like a TCP stream.
+pub struct UtpStream {
+    socket: Arc<UtpInner>,
+    key: ConnectionKey,
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl UtpStream {
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn
+            .lock()
+            .expect("uTP connection lock is not poisoned")
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.key.0
+    }
+}
+
+/// Sends a FIN unless one went out already. The connection is kept until the peer acknowledges
+/// it and everything before it, so that lost packets are still sent again.
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let finished = {
+            let mut conn = self.conn();
+            if conn.state == State::Connected && !conn.fin_sent {
+                conn.fin_sent = true;
+                conn.send_reliable(ST_FIN, &[]);
+            }
+            conn.closed = true;
+            conn.finished()
+        };
+        if finished {
+            self.socket.connections().remove(&self.key);
+        }
+    }
+}
+
+fn reset_error() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "uTP connection was reset")
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut conn = self.conn();
+        if !conn.recv_buf.is_empty() {
+            let n = conn.recv_buf.len().min(buf.remaining());
+            let (front, back) = conn.recv_buf.as_slices();
+            let from_front = n.min(front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..n - from_front]);
+            conn.recv_buf.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        if conn.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if conn.state == State::Reset {
+            return Poll::Ready(Err(reset_error()));
+        }
+        conn.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut conn = self.conn();
+        if conn.state == State::Reset {
+            return Poll::Ready(Err(reset_error()));
+        }
+        if conn.fin_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let mut written = 0;
+        while written < buf.len() {
+            let window = conn.max_window.min(conn.peer_window);
+            let len = (buf.len() - written).min(PACKET_SIZE);
+            // With nothing in flight a packet always goes out, which also probes a closed
+            // receive window.
+            if conn.cur_window > 0 && conn.cur_window + len > window {
+                break;
+            }
+            conn.send_reliable(ST_DATA, &buf[written..written + len]);
+            written += len;
+        }
+        if written == 0 {
+            conn.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        // Packets leave as soon as they are written; retransmission needs no help from us.
+        Poll::Ready(Ok(()))
+    }
+
+    /// Sends a FIN and waits for everything written so far to be acknowledged.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let mut conn = self.conn();
+        if conn.state == State::Reset {
+            return Poll::Ready(Err(reset_error()));
+        }
+        if !conn.fin_sent {
+            conn.fin_sent = true;
+            conn.send_reliable(ST_FIN, &[]);
+        }
+        if conn.in_flight.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        conn.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
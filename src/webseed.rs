@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use reqwest::header::RANGE;
+use reqwest::{StatusCode, Url};
+
+use crate::torrent::{FileSpan, Info, Torrent};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An HTTP server mirroring a torrent's content (BEP 19), fetched from with range requests.
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebSeed {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.into(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The URL serving `span`. A URL ending in a slash names a directory holding the torrent's
+    /// content, otherwise it is the file itself in single-file torrents.
+    pub fn file_url(&self, info: &Info, span: &FileSpan) -> anyhow::Result<Url> {
+        let mut url = Url::parse(&self.url).with_context(|| format!("parse {}", self.url))?;
+        if info.is_single_file() && !self.url.ends_with('/') {
+            return Ok(url);
+        }
+        url.path_segments_mut()
+            .map_err(|()| anyhow::anyhow!("{} cannot have a path", self.url))?
+            .pop_if_empty()
+            .extend(&span.path);
+        Ok(url)
+    }
+
+    /// Downloads piece `piece_index`, requesting the part of every file it overlaps. The data
+    /// still needs to be checked against the piece hash.
+    pub async fn fetch_piece(
+        &self,
+        torrent: &Torrent,
+        piece_index: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let info = &torrent.info;
+        let start = piece_index * info.piece_length;
+        let end = start + info.piece_size(piece_index);
+        let mut piece = Vec::with_capacity(end - start);
+        for span in info.file_spans() {
+            let (from, to) = (start.max(span.offset), end.min(span.offset + span.length));
+            if from >= to {
+                continue;
+            }
+            let (from, to) = (from - span.offset, to - span.offset);
+            if span.padding {
+                piece.resize(piece.len() + to - from, 0);
+            } else {
+                let url = self.file_url(info, &span)?;
+                piece.extend(self.fetch_range(url, from, to).await?);
+            }
+        }
+        Ok(piece)
+    }
+
+    async fn fetch_range(&self, url: Url, from: usize, to: usize) -> anyhow::Result<Vec<u8>> {
+        let res = self
+            .client
+            .get(url.clone())
+            .header(RANGE, format!("bytes={from}-{}", to - 1))
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .with_context(|| format!("request {url}"))?;
+        let status = res.status();
+        let body = res.bytes().await.with_context(|| format!("read {url}"))?;
+        let range = match status {
+            StatusCode::PARTIAL_CONTENT => body.get(..),
+            // Servers may ignore the range and send the whole file.
+            StatusCode::OK => body.get(from..to),
+            status => anyhow::bail!("{url} answered {status}"),
+        };
+        range
+            .filter(|range| range.len() == to - from)
+            .map(<[u8]>::to_vec)
+            .with_context(|| format!("{url} sent {} bytes, not bytes {from}..{to}", body.len()))
+    }
+}
@@ -0,0 +1,164 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bittorrent_starter_rust::cache::CachedStorage;
+use bittorrent_starter_rust::download::{download_swarm, SwarmOptions, TransferStats};
+use bittorrent_starter_rust::hash_pool::HashPool;
+use bittorrent_starter_rust::mock_peer::{test_data, torrent_for, MockPeer, RecordingStorage};
+use bittorrent_starter_rust::mse::EncryptionPolicy;
+use bittorrent_starter_rust::storage::Storage;
+use bittorrent_starter_rust::torrent::Torrent;
+use tokio::sync::mpsc;
+
+const PIECE_LENGTH: usize = 1 << 14;
+const BLOCK: usize = 4096;
+
+fn setup(data: &[u8], capacity: usize) -> (Arc<Torrent>, Arc<RecordingStorage>, CachedStorage) {
+    let torrent = Arc::new(torrent_for("data.bin", data, PIECE_LENGTH));
+    let inner = Arc::new(RecordingStorage::new(torrent.clone()));
+    let cache = CachedStorage::new(inner.clone(), capacity);
+    (torrent, inner, cache)
+}
+
+fn write_piece(storage: &dyn Storage, piece_index: usize, piece: &[u8]) {
+    for (i, block) in piece.chunks(BLOCK).enumerate().rev() {
+        storage.write_block(piece_index, i * BLOCK, block).unwrap();
+    }
+}
+
+#[test]
+fn verified_pieces_are_written_in_one_go() {
+    let data = test_data(3 * PIECE_LENGTH, 0);
+    let (_, inner, cache) = setup(&data, 1 << 20);
+
+    write_piece(&cache, 1, &data[PIECE_LENGTH..2 * PIECE_LENGTH]);
+    assert_eq!(inner.take_writes(), []);
+    assert!(cache.verify(1).unwrap());
+    assert_eq!(inner.take_writes(), [(1, 0, PIECE_LENGTH)]);
+
+    // Serving the piece to peers is answered from the cache.
+    let mut block = vec![0; BLOCK];
+    cache.read_block(1, BLOCK, &mut block).unwrap();
+    assert!(block == data[PIECE_LENGTH + BLOCK..PIECE_LENGTH + 2 * BLOCK]);
+    assert_eq!(inner.take_reads(), []);
+}
+
+#[test]
+fn corrupt_pieces_never_reach_the_inner_storage() {
+    let data = test_data(2 * PIECE_LENGTH, 0);
+    let (_, inner, cache) = setup(&data, 1 << 20);
+
+    let mut piece = data[..PIECE_LENGTH].to_vec();
+    piece[100] ^= 1;
+    write_piece(&cache, 0, &piece);
+    assert!(!cache.verify(0).unwrap());
+    cache.flush().unwrap();
+    assert_eq!(inner.take_writes(), []);
+
+    write_piece(&cache, 0, &data[..PIECE_LENGTH]);
+    assert!(cache.verify(0).unwrap());
+    assert_eq!(inner.take_writes(), [(0, 0, PIECE_LENGTH)]);
+}
+
+#[test]
+fn partial_pieces_are_written_back_as_runs_of_blocks() {
+    let data = test_data(2 * PIECE_LENGTH, 0);
+    let (_, inner, cache) = setup(&data, 1 << 20);
+
+    for begin in [0, BLOCK, 3 * BLOCK] {
+        cache
+            .write_block(0, begin, &data[begin..begin + BLOCK])
+            .unwrap();
+    }
+    cache.flush().unwrap();
+    assert_eq!(
+        inner.take_writes(),
+        [(0, 0, 2 * BLOCK), (0, 3 * BLOCK, BLOCK)]
+    );
+
+    // Overlapping writes replace what they cover.
+    cache.write_block(1, 0, &[1; 2 * BLOCK]).unwrap();
+    cache.write_block(1, BLOCK, &[2; 2 * BLOCK]).unwrap();
+    let mut block = vec![0; 3 * BLOCK];
+    cache.read_block(1, 0, &mut block).unwrap();
+    assert_eq!(block[..BLOCK], [1; BLOCK]);
+    assert_eq!(block[BLOCK..], [2; 2 * BLOCK]);
+}
+
+#[test]
+fn the_oldest_pieces_are_written_out_once_the_cache_is_full() {
+    let data = test_data(4 * PIECE_LENGTH, 0);
+    let (_, inner, cache) = setup(&data, 2 * PIECE_LENGTH);
+
+    for piece_index in 0..3 {
+        let start = piece_index * PIECE_LENGTH;
+        write_piece(&cache, piece_index, &data[start..start + PIECE_LENGTH]);
+    }
+    // The first piece went out as soon as the third one started.
+    assert_eq!(inner.take_writes(), [(0, 0, PIECE_LENGTH)]);
+    assert!(cache.verify(0).unwrap());
+    assert!(cache.verify(2).unwrap());
+    assert_eq!(inner.take_writes(), [(2, 0, PIECE_LENGTH)]);
+    inner.take_reads();
+
+    // Reads keep whole pieces, dropping the least recently used one.
+    let mut block = vec![0; BLOCK];
+    for piece_index in [0, 1, 0, 2, 0] {
+        cache.read_block(piece_index, 0, &mut block).unwrap();
+    }
+    assert_eq!(inner.take_writes(), [(1, 0, PIECE_LENGTH)]);
+    assert_eq!(
+        inner.take_reads(),
+        [
+            (0, 0, PIECE_LENGTH),
+            (1, 0, PIECE_LENGTH),
+            (2, 0, PIECE_LENGTH)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn the_hash_pool_survives_panicking_jobs() {
+    let pool = HashPool::new(2);
+    let results = futures_util::future::join_all((0..8).map(|i| pool.run(move || i * 2))).await;
+    let results: Vec<_> = results.into_iter().map(Result::unwrap).collect();
+    assert_eq!(results, [0, 2, 4, 6, 8, 10, 12, 14]);
+
+    let err = pool.run(|| panic!("bad job")).await.unwrap_err();
+    assert!(err.to_string().contains("panicked"), "{err:#}");
+    assert_eq!(pool.run(|| 42).await.unwrap(), 42);
+}
+
+#[tokio::test]
+async fn swarm_downloads_write_only_verified_pieces_through_the_cache() {
+    let data = test_data(10 * PIECE_LENGTH + 100, 0);
+    let (torrent, inner, cache) = setup(&data, 1 << 20);
+    let peers: [MockPeer; 2] = [
+        MockPeer::new(torrent.clone(), &data).corrupt_piece(3),
+        MockPeer::new(torrent.clone(), &data),
+    ];
+    let (peers_tx, peers_rx) = mpsc::channel(peers.len());
+    for peer in peers {
+        let addr: SocketAddr = peer.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        peers_tx.send(addr).await.unwrap();
+    }
+
+    let options = SwarmOptions {
+        encryption: EncryptionPolicy::Plaintext,
+        hash_pool: HashPool::new(2),
+        ..Default::default()
+    };
+    let stats = Arc::new(TransferStats::new(data.len()));
+    download_swarm(torrent.clone(), peers_rx, Arc::new(cache), stats, options)
+        .await
+        .unwrap();
+    drop(peers_tx);
+
+    assert!(inner.contents() == data);
+    let mut writes = inner.take_writes();
+    writes.sort();
+    let expected: Vec<_> = (0..torrent.info.piece_count())
+        .map(|i| (i, 0, torrent.info.piece_size(i)))
+        .collect();
+    assert_eq!(writes, expected);
+}
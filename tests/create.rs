@@ -0,0 +1,146 @@
+use bittorrent_starter_rust::create::{default_piece_length, TorrentBuilder};
+use bittorrent_starter_rust::mock_peer::{test_data, torrent_for};
+use bittorrent_starter_rust::torrent::Torrent;
+
+const TRACKER: &str = "http://127.0.0.1:6969/announce";
+
+fn round_trip(torrent: &Torrent) -> Torrent {
+    let bytes = serde_bencode::to_bytes(torrent).unwrap();
+    serde_bencode::from_bytes(&bytes).unwrap()
+}
+
+#[tokio::test]
+async fn single_files_hash_like_their_in_memory_description() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.bin");
+    let data = test_data(100_000, 0);
+    std::fs::write(&path, &data).unwrap();
+
+    let torrent = TorrentBuilder::new(&path)
+        .tracker(TRACKER)
+        .piece_length(1 << 15)
+        .build()
+        .await
+        .unwrap();
+    let expected = torrent_for("data.bin", &data, 1 << 15);
+    assert_eq!(torrent.info_hash(), expected.info_hash());
+    assert_eq!(torrent.announce, TRACKER);
+    assert!(torrent.announce_list.is_none());
+    assert!(torrent.creation_date.is_some());
+
+    let parsed = round_trip(&torrent);
+    assert_eq!(parsed.info_hash(), torrent.info_hash());
+    assert_eq!(parsed.info.length(), data.len());
+}
+
+#[tokio::test]
+async fn directories_become_multi_file_torrents() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("dataset");
+    std::fs::create_dir_all(root.join("nested")).unwrap();
+    let data = test_data(90_000, 0);
+    // Pieces straddle file boundaries; files are laid out in path order.
+    std::fs::write(root.join("a.bin"), &data[..10_000]).unwrap();
+    std::fs::write(root.join("nested/b.bin"), &data[10_000..50_000]).unwrap();
+    std::fs::write(root.join("z.bin"), &data[50_000..]).unwrap();
+
+    let torrent = TorrentBuilder::new(&root)
+        .tracker(TRACKER)
+        .tracker("udp://127.0.0.1:6969")
+        .comment("test data")
+        .private(true)
+        .web_seed("http://127.0.0.1:8080/")
+        .build()
+        .await
+        .unwrap();
+    let parsed = round_trip(&torrent);
+
+    assert_eq!(parsed.info.name, "dataset");
+    assert!(parsed.info.length.is_none());
+    let files: Vec<_> = parsed
+        .info
+        .files
+        .iter()
+        .flatten()
+        .map(|file| (file.path.join("/"), file.length))
+        .collect();
+    assert_eq!(
+        files,
+        [
+            (String::from("a.bin"), 10_000),
+            (String::from("nested/b.bin"), 40_000),
+            (String::from("z.bin"), 40_000),
+        ]
+    );
+    assert_eq!(parsed.info.piece_length, default_piece_length(data.len()));
+    let expected = torrent_for("dataset", &data, parsed.info.piece_length);
+    assert_eq!(parsed.info.pieces, expected.info.pieces);
+
+    assert!(parsed.info.is_private());
+    assert_eq!(parsed.comment.as_deref(), Some("test data"));
+    assert_eq!(
+        parsed.announce_tiers(),
+        [
+            vec![TRACKER.to_string()],
+            vec!["udp://127.0.0.1:6969".to_string()]
+        ]
+    );
+    assert_eq!(
+        parsed.url_list.as_deref(),
+        Some(&["http://127.0.0.1:8080/".to_string()][..])
+    );
+}
+
+#[tokio::test]
+async fn leaving_out_the_date_makes_output_reproducible() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.bin");
+    std::fs::write(&path, test_data(50_000, 0)).unwrap();
+
+    let build = || {
+        TorrentBuilder::new(&path)
+            .tracker(TRACKER)
+            .creation_date(None)
+            .build()
+    };
+    let first = serde_bencode::to_bytes(&build().await.unwrap()).unwrap();
+    let second = serde_bencode::to_bytes(&build().await.unwrap()).unwrap();
+    assert_eq!(first, second);
+}
+
+#[tokio::test]
+async fn invalid_input_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.bin");
+    std::fs::write(&path, test_data(1000, 0)).unwrap();
+
+    let no_tracker = TorrentBuilder::new(&path).build().await.unwrap_err();
+    assert!(no_tracker.to_string().contains("tracker"), "{no_tracker:#}");
+
+    let odd_pieces = TorrentBuilder::new(&path)
+        .tracker(TRACKER)
+        .piece_length(50_000)
+        .build()
+        .await
+        .unwrap_err();
+    assert!(
+        odd_pieces.to_string().contains("power of two"),
+        "{odd_pieces:#}"
+    );
+
+    let empty = dir.path().join("empty");
+    std::fs::create_dir(&empty).unwrap();
+    let empty = TorrentBuilder::new(&empty)
+        .tracker(TRACKER)
+        .build()
+        .await
+        .unwrap_err();
+    assert!(empty.to_string().contains("empty"), "{empty:#}");
+}
+
+#[test]
+fn url_list_may_be_a_single_string() {
+    let raw = b"d8:announce4:http4:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces0:e8:url-list10:http://a/be";
+    let torrent: Torrent = serde_bencode::from_bytes(raw).unwrap();
+    assert_eq!(torrent.url_list, Some(vec![String::from("http://a/b")]));
+}
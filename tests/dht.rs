@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use bittorrent_starter_rust::dht::{Dht, DhtConfig, NodeInfo, RoutingTable, K};
+use bittorrent_starter_rust::message::{Message, MessageFramer, MessageTag};
+use bytes::BytesMut;
+use serde_bencode::value::Value;
+use tokio::net::UdpSocket;
+use tokio_util::codec::{Decoder, Encoder};
+
+fn localhost_config(bootstrap: Option<SocketAddr>) -> DhtConfig {
+    DhtConfig {
+        bind_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        bootstrap_nodes: bootstrap.iter().map(SocketAddr::to_string).collect(),
+        state_path: None,
+    }
+}
+
+async fn spawn_swarm(size: usize) -> Vec<Dht> {
+    let seed = Dht::bind(localhost_config(None)).await.unwrap();
+    let seed_addr = seed.local_addr().unwrap();
+    let mut nodes = vec![seed];
+    for _ in 1..size {
+        let node = Dht::bind(localhost_config(Some(seed_addr))).await.unwrap();
+        node.bootstrap().await.unwrap();
+        nodes.push(node);
+    }
+    nodes
+}
+
+#[tokio::test]
+async fn nodes_bootstrap_from_each_other() {
+    let nodes = spawn_swarm(5).await;
+    let seed_addr = nodes[0].local_addr().unwrap();
+
+    assert_eq!(nodes[0].routing_table_len(), 4);
+    for node in &nodes[1..] {
+        assert!(node.routing_table_len() >= 1);
+        assert_eq!(node.ping(seed_addr).await.unwrap(), nodes[0].id());
+    }
+
+    let last = nodes.last().unwrap();
+    let found = last.find_node(seed_addr, &nodes[1].id()).await.unwrap();
+    assert!(found.iter().any(|node| node.id == nodes[1].id()));
+}
+
+#[tokio::test]
+async fn announced_peers_are_found_by_other_nodes() {
+    let nodes = spawn_swarm(6).await;
+    let info_hash = [7u8; 20];
+
+    assert!(nodes[5].get_peers(&info_hash).await.is_empty());
+    nodes[1].announce(&info_hash, 51413).await;
+
+    let peers = nodes[5].get_peers(&info_hash).await;
+    assert_eq!(peers, vec![SocketAddr::from(([127, 0, 0, 1], 51413))]);
+}
+
+#[tokio::test]
+async fn routing_table_survives_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let state_path = dir.path().join("dht.dat");
+    let nodes = spawn_swarm(3).await;
+
+    let config = DhtConfig {
+        state_path: Some(state_path.clone()),
+        ..localhost_config(Some(nodes[0].local_addr().unwrap()))
+    };
+    let node = Dht::bind(config).await.unwrap();
+    node.bootstrap().await.unwrap();
+    let id = node.id();
+    let table_len = node.routing_table_len();
+    node.save_state().unwrap();
+    assert!(!dir.path().join("dht.dat.tmp").exists());
+    drop(node);
+
+    let config = DhtConfig {
+        state_path: Some(state_path),
+        ..localhost_config(None)
+    };
+    let restored = Dht::bind(config).await.unwrap();
+    assert_eq!(restored.id(), id);
+    assert_eq!(restored.routing_table_len(), table_len);
+}
+
+/// A node sharing no prefix with the all-zero id, so that all of them land in the same bucket.
+fn far_node(n: u8) -> NodeInfo {
+    let mut id = [0u8; 20];
+    id[0] = 0x80;
+    id[19] = n;
+    NodeInfo {
+        id,
+        addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6000 + n as u16),
+    }
+}
+
+#[test]
+fn nodes_are_dropped_only_after_repeated_failures() {
+    let mut table = RoutingTable::new([0; 20]);
+    let node = far_node(1);
+    table.insert(node);
+
+    table.failed(&node.id);
+    table.failed(&node.id);
+    assert_eq!(
+        table.len(),
+        1,
+        "a node is questionable before it is dropped"
+    );
+    // Hearing from it again clears the failures.
+    table.insert(node);
+    table.failed(&node.id);
+    table.failed(&node.id);
+    assert_eq!(table.len(), 1);
+    table.failed(&node.id);
+    assert!(table.is_empty());
+}
+
+#[test]
+fn questionable_nodes_make_room_in_full_buckets() {
+    let mut table = RoutingTable::new([0; 20]);
+    for n in 0..K as u8 {
+        assert!(table.insert(far_node(n)));
+    }
+    assert!(
+        !table.insert(far_node(100)),
+        "the bucket is full of good nodes"
+    );
+
+    table.failed(&far_node(3).id);
+    assert!(table.insert(far_node(100)));
+    let nodes: Vec<NodeInfo> = table.nodes().collect();
+    assert_eq!(nodes.len(), K);
+    assert!(!nodes.contains(&far_node(3)));
+    assert!(nodes.contains(&far_node(100)));
+}
+
+#[test]
+fn port_messages_carry_the_dht_port() {
+    let msg = Message {
+        tag: MessageTag::Port,
+        payload: 6881u16.to_be_bytes().to_vec(),
+    };
+    let mut bytes = BytesMut::new();
+    MessageFramer.encode(msg, &mut bytes).unwrap();
+    assert_eq!(&bytes[..], [0, 0, 0, 3, 9, 0x1a, 0xe1]);
+
+    let decoded = MessageFramer.decode(&mut bytes).unwrap().unwrap();
+    assert_eq!(decoded.tag, MessageTag::Port);
+    assert_eq!(decoded.payload, [0x1a, 0xe1]);
+    assert!(bytes.is_empty());
+}
+
+/// Sends a KRPC query from `socket` to `node` and returns the reply's top-level dictionary.
+async fn krpc(
+    socket: &UdpSocket,
+    node: SocketAddr,
+    method: &str,
+    args: Vec<(&str, Value)>,
+) -> HashMap<Vec<u8>, Value> {
+    let mut args: HashMap<Vec<u8>, Value> = args
+        .into_iter()
+        .map(|(key, value)| (key.as_bytes().to_vec(), value))
+        .collect();
+    args.insert(b"id".to_vec(), Value::Bytes(vec![0x55; 20]));
+    let query = Value::Dict(HashMap::from([
+        (b"t".to_vec(), Value::Bytes(b"tt".to_vec())),
+        (b"y".to_vec(), Value::Bytes(b"q".to_vec())),
+        (b"q".to_vec(), Value::Bytes(method.as_bytes().to_vec())),
+        (b"a".to_vec(), Value::Dict(args)),
+    ]));
+    let bytes = serde_bencode::to_bytes(&query).unwrap();
+    socket.send_to(&bytes, node).await.unwrap();
+    let mut buf = [0; 1500];
+    let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+    match serde_bencode::from_bytes(&buf[..len]).unwrap() {
+        Value::Dict(reply) => reply,
+        reply => panic!("reply is not a dictionary: {reply:?}"),
+    }
+}
+
+#[tokio::test]
+async fn announces_with_ports_out_of_range_are_refused() {
+    let node = Dht::bind(localhost_config(None)).await.unwrap();
+    let node_addr = node.local_addr().unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let info_hash = Value::Bytes(vec![9; 20]);
+
+    let reply = krpc(
+        &socket,
+        node_addr,
+        "get_peers",
+        vec![("info_hash", info_hash.clone())],
+    )
+    .await;
+    let Some(Value::Dict(values)) = reply.get(&b"r"[..]) else {
+        panic!("no response values: {reply:?}");
+    };
+    let token = values[&b"token"[..]].clone();
+
+    let reply = krpc(
+        &socket,
+        node_addr,
+        "announce_peer",
+        vec![
+            ("info_hash", info_hash.clone()),
+            ("port", Value::Int(65536 + 6881)),
+            ("token", token),
+        ],
+    )
+    .await;
+    assert_eq!(reply[&b"y"[..]], Value::Bytes(b"e".to_vec()));
+    let Some(Value::List(error)) = reply.get(&b"e"[..]) else {
+        panic!("no error: {reply:?}");
+    };
+    assert_eq!(error[0], Value::Int(203));
+
+    // Nothing was stored under a truncated port.
+    let reply = krpc(
+        &socket,
+        node_addr,
+        "get_peers",
+        vec![("info_hash", info_hash)],
+    )
+    .await;
+    let Some(Value::Dict(values)) = reply.get(&b"r"[..]) else {
+        panic!("no response values: {reply:?}");
+    };
+    assert!(!values.contains_key(&b"values"[..]));
+}
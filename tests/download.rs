@@ -0,0 +1,307 @@
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bittorrent_starter_rust::download::{
+    download_file, download_piece, download_swarm, SwarmOptions, TransferStats,
+};
+use bittorrent_starter_rust::handshake::{perform_handshake, Extensions};
+use bittorrent_starter_rust::message::MessageFramer;
+use bittorrent_starter_rust::mock_peer::{test_data, torrent_for, MockAction, MockPeer};
+use bittorrent_starter_rust::mse::EncryptionPolicy;
+use bittorrent_starter_rust::storage::{
+    FsStorage, MemoryStorage, MmapStorage, NullStorage, Storage,
+};
+use bittorrent_starter_rust::torrent::Torrent;
+use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
+
+const PIECE_LENGTH: usize = 1 << 15;
+
+fn offline_options() -> SwarmOptions {
+    SwarmOptions {
+        encryption: EncryptionPolicy::Plaintext,
+        ..Default::default()
+    }
+}
+
+/// Runs the single-peer download path against `peer` over an in-memory pipe.
+async fn download_from(peer: MockPeer, torrent: &Arc<Torrent>) -> anyhow::Result<Vec<u8>> {
+    let (mut ours, theirs) = tokio::io::duplex(1 << 16);
+    tokio::spawn(async move { peer.serve(theirs).await });
+    perform_handshake(torrent, &mut ours, Extensions::default()).await?;
+    let mut framed = Framed::new(&mut ours, MessageFramer);
+    let memory = Arc::new(MemoryStorage::new(torrent.clone()));
+    let storage: Arc<dyn Storage> = memory.clone();
+    download_file(torrent, &mut framed, &storage).await?;
+    Ok(memory.contents())
+}
+
+#[tokio::test]
+async fn downloads_a_file_from_a_single_peer() {
+    let data = test_data(100_000, 0);
+    let torrent = Arc::new(torrent_for("data", &data, PIECE_LENGTH));
+    let peer = MockPeer::new(torrent.clone(), data.clone());
+    assert!(download_from(peer, &torrent).await.unwrap() == data);
+}
+
+#[tokio::test]
+async fn waits_out_a_choke_in_the_middle_of_a_piece() {
+    let data = test_data(100_000, 0);
+    let torrent = Arc::new(torrent_for("data", &data, PIECE_LENGTH));
+    // Piece 2 is held back until the peer announces it, before we get to it.
+    let peer = MockPeer::new(torrent.clone(), data.clone())
+        .withhold_piece(2)
+        .after_blocks(1, MockAction::Choke(Duration::from_millis(50)))
+        .after_blocks(3, MockAction::Have(2));
+    assert!(download_from(peer, &torrent).await.unwrap() == data);
+}
+
+#[tokio::test]
+async fn a_single_piece_fails_its_hash_check_when_corrupted() {
+    let data = test_data(100_000, 0);
+    let torrent = Arc::new(torrent_for("data", &data, PIECE_LENGTH));
+    let peer = MockPeer::new(torrent.clone(), data.clone()).corrupt_piece(1);
+    let (mut ours, theirs) = tokio::io::duplex(1 << 16);
+    tokio::spawn(async move { peer.serve(theirs).await });
+    perform_handshake(&torrent, &mut ours, Extensions::default())
+        .await
+        .unwrap();
+    let mut framed = Framed::new(&mut ours, MessageFramer);
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new(torrent.clone()));
+    let err = download_piece(&torrent, &mut framed, 1, &storage)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("hash check"), "{err:#}");
+}
+
+async fn listen(peer: MockPeer) -> SocketAddr {
+    peer.listen("127.0.0.1:0".parse().unwrap()).await.unwrap()
+}
+
+#[tokio::test]
+async fn swarm_routes_around_misbehaving_peers() {
+    let data = test_data(300_000, 0);
+    let torrent = Arc::new(torrent_for("data", &data, PIECE_LENGTH));
+    let peers = [
+        MockPeer::new(torrent.clone(), data.clone()).after_blocks(3, MockAction::Drop),
+        MockPeer::new(torrent.clone(), data.clone())
+            .corrupt_piece(0)
+            .corrupt_piece(4),
+        MockPeer::new(torrent.clone(), data.clone())
+            .after_blocks(2, MockAction::Choke(Duration::from_millis(100))),
+        MockPeer::new(torrent.clone(), data.clone())
+            .fast()
+            .after_blocks(1, MockAction::Choke(Duration::from_millis(100)))
+            .delay_blocks(Duration::from_millis(2)),
+    ];
+    let (peers_tx, peers_rx) = mpsc::channel(peers.len());
+    for peer in peers {
+        peers_tx.send(listen(peer).await).await.unwrap();
+    }
+
+    let out = tempfile::NamedTempFile::new().unwrap();
+    let storage = FsStorage::new(torrent.clone(), out.path()).unwrap();
+    let stats = Arc::new(TransferStats::new(data.len()));
+    download_swarm(
+        torrent,
+        peers_rx,
+        Arc::new(storage),
+        stats.clone(),
+        offline_options(),
+    )
+    .await
+    .unwrap();
+
+    assert!(tokio::fs::read(out.path()).await.unwrap() == data);
+    assert_eq!(stats.downloaded.load(Ordering::Relaxed), data.len());
+    assert_eq!(stats.left.load(Ordering::Relaxed), 0);
+    drop(peers_tx);
+}
+
+#[tokio::test]
+async fn seeds_and_downloads_through_memory_mappings() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = test_data(200_000, 0);
+    let torrent = Arc::new(torrent_for("data", &data, PIECE_LENGTH));
+    let source = dir.path().join("source");
+    std::fs::write(&source, &data).unwrap();
+    let (peers_tx, peers_rx) = mpsc::channel(2);
+    for _ in 0..2 {
+        let peer = MockPeer::seeding(Arc::new(
+            MmapStorage::new(torrent.clone(), &source).unwrap(),
+        ));
+        peers_tx.send(listen(peer).await).await.unwrap();
+    }
+    drop(peers_tx);
+
+    let out = dir.path().join("out");
+    let storage = MmapStorage::new(torrent.clone(), &out).unwrap();
+    let stats = Arc::new(TransferStats::new(data.len()));
+    download_swarm(
+        torrent,
+        peers_rx,
+        Arc::new(storage),
+        stats,
+        offline_options(),
+    )
+    .await
+    .unwrap();
+
+    assert!(std::fs::read(&out).unwrap() == data);
+    assert!(std::fs::read(&source).unwrap() == data);
+}
+
+#[tokio::test]
+async fn swarm_gives_up_once_every_peer_is_gone() {
+    let data = test_data(300_000, 0);
+    let torrent = Arc::new(torrent_for("data", &data, PIECE_LENGTH));
+    let peer = MockPeer::new(torrent.clone(), data.clone()).after_blocks(2, MockAction::Drop);
+    let (peers_tx, peers_rx) = mpsc::channel(1);
+    peers_tx.send(listen(peer).await).await.unwrap();
+    drop(peers_tx);
+
+    let storage = Arc::new(MemoryStorage::new(torrent.clone()));
+    let stats = Arc::new(TransferStats::new(data.len()));
+    let err = download_swarm(torrent, peers_rx, storage, stats, offline_options())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("ran out of peers"), "{err:#}");
+}
+
+#[tokio::test]
+async fn swarm_gives_up_waiting_for_peers_after_the_idle_timeout() {
+    let data = test_data(300_000, 0);
+    let torrent = Arc::new(torrent_for("data", &data, PIECE_LENGTH));
+    let peer = MockPeer::new(torrent.clone(), data.clone()).after_blocks(2, MockAction::Drop);
+    // The channel stays open, as it does while trackers keep announcing.
+    let (peers_tx, peers_rx) = mpsc::channel(1);
+    peers_tx.send(listen(peer).await).await.unwrap();
+
+    let storage = Arc::new(MemoryStorage::new(torrent.clone()));
+    let stats = Arc::new(TransferStats::new(data.len()));
+    let options = SwarmOptions {
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..offline_options()
+    };
+    let download = download_swarm(torrent, peers_rx, storage, stats, options);
+    let err = tokio::time::timeout(Duration::from_secs(10), download)
+        .await
+        .expect("download gave up")
+        .unwrap_err();
+    assert!(err.to_string().contains("ran out of peers"), "{err:#}");
+    drop(peers_tx);
+}
+
+#[tokio::test]
+async fn null_storage_takes_whole_downloads_without_hash_checks() {
+    let data = test_data(300_000, 0);
+    let torrent = Arc::new(torrent_for("data", &data, PIECE_LENGTH));
+    let (peers_tx, peers_rx) = mpsc::channel(2);
+    for _ in 0..2 {
+        let peer = MockPeer::new(torrent.clone(), data.clone());
+        peers_tx.send(listen(peer).await).await.unwrap();
+    }
+    drop(peers_tx);
+
+    let storage = Arc::new(NullStorage::new(torrent.clone()));
+    let stats = Arc::new(TransferStats::new(data.len()));
+    let options = SwarmOptions {
+        skip_hash_check: true,
+        ..offline_options()
+    };
+    download_swarm(torrent, peers_rx, storage, stats.clone(), options)
+        .await
+        .unwrap();
+    assert_eq!(stats.downloaded.load(Ordering::Relaxed), data.len());
+    assert_eq!(stats.left.load(Ordering::Relaxed), 0);
+}
+
+/// Downloads `torrent` in memory from the given peers alone, returning the data.
+async fn download_from_peers(
+    torrent: &Arc<Torrent>,
+    peers: impl IntoIterator<Item = MockPeer>,
+    options: SwarmOptions,
+) -> anyhow::Result<Vec<u8>> {
+    let peers: Vec<MockPeer> = peers.into_iter().collect();
+    let (peers_tx, peers_rx) = mpsc::channel(peers.len());
+    for peer in peers {
+        peers_tx.send(listen(peer).await).await.unwrap();
+    }
+    drop(peers_tx);
+    let memory = Arc::new(MemoryStorage::new(torrent.clone()));
+    let stats = Arc::new(TransferStats::new(torrent.info.length()));
+    download_swarm(torrent.clone(), peers_rx, memory.clone(), stats, options).await?;
+    Ok(memory.contents())
+}
+
+#[tokio::test]
+async fn choked_peers_serve_their_allowed_fast_pieces() {
+    let data = test_data(100_000, 0);
+    let torrent = Arc::new(torrent_for("data", &data, PIECE_LENGTH));
+    let mut peer = MockPeer::new(torrent.clone(), data.clone())
+        .fast()
+        .stay_choked();
+    for piece_index in 0..torrent.info.piece_count() {
+        peer = peer.allow_fast(piece_index);
+    }
+    let downloaded = download_from_peers(&torrent, [peer], offline_options());
+    assert!(downloaded.await.unwrap() == data);
+}
+
+#[tokio::test]
+async fn only_the_rejected_block_is_fetched_again() {
+    let data = test_data(100_000, 0);
+    let torrent = Arc::new(torrent_for("data", &data, PIECE_LENGTH));
+    // The fourth request is for the second half of piece 1.
+    let peer = MockPeer::new(torrent.clone(), data.clone())
+        .fast()
+        .after_blocks(3, MockAction::RejectNext);
+    let blocks = data.len().div_ceil(1 << 14);
+    let downloaded = download_from_peers(&torrent, [peer.clone()], offline_options());
+    assert!(downloaded.await.unwrap() == data);
+    assert_eq!(peer.blocks_served(), blocks);
+}
+
+#[tokio::test]
+async fn peers_rejecting_every_request_are_given_up_on() {
+    let data = test_data(100_000, 0);
+    let torrent = Arc::new(torrent_for("data", &data, PIECE_LENGTH));
+    let refusing = MockPeer::new(torrent.clone(), data.clone())
+        .fast()
+        .reject_requests();
+    let honest =
+        MockPeer::new(torrent.clone(), data.clone()).delay_blocks(Duration::from_millis(5));
+    let downloaded = download_from_peers(&torrent, [refusing.clone(), honest], offline_options());
+    assert!(downloaded.await.unwrap() == data);
+
+    // On its own, the refusing peer is dropped rather than asked forever.
+    let download = download_from_peers(&torrent, [refusing], offline_options());
+    let err = tokio::time::timeout(Duration::from_secs(10), download)
+        .await
+        .expect("download gave up")
+        .unwrap_err();
+    assert!(err.to_string().contains("ran out of peers"), "{err:#}");
+}
+
+#[tokio::test]
+async fn peers_arriving_while_the_swarm_is_full_are_tried_later() {
+    // More blocks than the peers hanging up below serve between them.
+    let data = test_data(40 << 14, 0);
+    let torrent = Arc::new(torrent_for("data", &data, PIECE_LENGTH));
+    // As many peers as the swarm connects to at once, each hanging up after a slow block.
+    let mut peers: Vec<MockPeer> = (0..30)
+        .map(|_| {
+            MockPeer::new(torrent.clone(), data.clone())
+                .delay_blocks(Duration::from_millis(300))
+                .after_blocks(1, MockAction::Drop)
+        })
+        .collect();
+    peers.push(MockPeer::new(torrent.clone(), data.clone()));
+    let download = download_from_peers(&torrent, peers, offline_options());
+    let downloaded = tokio::time::timeout(Duration::from_secs(20), download)
+        .await
+        .expect("download finished");
+    assert!(downloaded.unwrap() == data);
+}
@@ -0,0 +1,67 @@
+use bittorrent_starter_rust::handshake::{perform_handshake, Extensions};
+use bittorrent_starter_rust::peer_id::{generate_peer_id, local_peer_id};
+use bittorrent_starter_rust::torrent::{Info, Torrent};
+use serde_bytes::ByteBuf;
+
+fn torrent() -> Torrent {
+    Torrent {
+        announce: String::from("http://127.0.0.1/announce"),
+        info: Info {
+            length: Some(4),
+            name: String::from("tiny"),
+            piece_length: 4,
+            pieces: ByteBuf::from(vec![0u8; 20]),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn handshake_runs_over_in_memory_pipes() {
+    let torrent = torrent();
+    let (mut a, mut b) = tokio::io::duplex(1024);
+    let fast = Extensions {
+        fast: true,
+        ..Default::default()
+    };
+    let (from_b, from_a) = tokio::join!(
+        perform_handshake(&torrent, &mut a, Extensions::default()),
+        perform_handshake(&torrent, &mut b, fast),
+    );
+    let (from_b, from_a) = (from_b.unwrap(), from_a.unwrap());
+
+    assert_eq!(from_b.info_hash, torrent.info_hash());
+    assert_eq!(&from_b.peer_id, local_peer_id());
+    assert!(from_b.supports_fast());
+    assert!(!from_a.supports_fast());
+}
+
+#[test]
+fn peer_ids_are_azureus_style() {
+    let peer_id = generate_peer_id();
+    // Client code and the crate version padded to four digits, then random characters.
+    assert_eq!(&peer_id[..8], b"-BR0100-");
+    assert!(peer_id[8..].iter().all(u8::is_ascii_alphanumeric));
+    assert_ne!(generate_peer_id()[8..], peer_id[8..]);
+}
+
+#[tokio::test]
+async fn dht_support_is_the_last_reserved_bit() {
+    let dht = Extensions {
+        dht: true,
+        ..Default::default()
+    };
+    assert_eq!(dht.reserved(), [0, 0, 0, 0, 0, 0, 0, 0x01]);
+
+    let torrent = torrent();
+    let (mut a, mut b) = tokio::io::duplex(1024);
+    let (from_b, from_a) = tokio::join!(
+        perform_handshake(&torrent, &mut a, Extensions::default()),
+        perform_handshake(&torrent, &mut b, dht),
+    );
+    let (from_b, from_a) = (from_b.unwrap(), from_a.unwrap());
+    assert!(from_b.supports_dht());
+    assert!(!from_b.supports_fast());
+    assert!(!from_a.supports_dht());
+}
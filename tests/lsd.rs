@@ -0,0 +1,91 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use bittorrent_starter_rust::lsd::{Lsd, LsdAnnounce, LsdConfig, LSD_MULTICAST_ADDR};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+fn loopback_config(group_port: u16, port: Option<u16>) -> LsdConfig {
+    LsdConfig {
+        multicast_addr: SocketAddrV4::new(*LSD_MULTICAST_ADDR.ip(), group_port),
+        interface: Ipv4Addr::LOCALHOST,
+        port,
+        announce_interval: Duration::from_secs(3600),
+    }
+}
+
+#[test]
+fn announce_round_trips() {
+    let announce = LsdAnnounce {
+        port: 51413,
+        info_hashes: vec![[0xab; 20], [0x01; 20]],
+        cookie: Some(String::from("c00k1e")),
+    };
+    let bytes = announce.to_bytes(&LSD_MULTICAST_ADDR);
+    assert!(bytes.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+    assert_eq!(LsdAnnounce::parse(&bytes), Some(announce));
+    assert_eq!(LsdAnnounce::parse(b"M-SEARCH * HTTP/1.1\r\n\r\n"), None);
+}
+
+#[tokio::test]
+async fn peers_on_the_same_network_find_each_other() {
+    let group_port = 20000 + rand::random::<u16>() % 20000;
+    let info_hash = [0x42; 20];
+    let shutdown = CancellationToken::new();
+
+    let seeder = Lsd::bind(loopback_config(group_port, Some(5000))).unwrap();
+    let (seeder_tx, mut seeder_rx) = mpsc::channel(8);
+    seeder.add_torrent(info_hash, seeder_tx);
+
+    let leecher = Lsd::bind(loopback_config(group_port, Some(6000))).unwrap();
+    let (leecher_tx, mut leecher_rx) = mpsc::channel(8);
+    leecher.add_torrent(info_hash, leecher_tx);
+    leecher.add_torrent([0x13; 20], mpsc::channel(8).0);
+    tokio::spawn(leecher.clone().run(shutdown.clone()));
+    tokio::spawn(seeder.clone().run(shutdown.clone()));
+
+    let found = tokio::time::timeout(Duration::from_secs(5), leecher_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(found, SocketAddr::from(([127, 0, 0, 1], 5000)));
+
+    let found = tokio::time::timeout(Duration::from_secs(5), seeder_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(found, SocketAddr::from(([127, 0, 0, 1], 6000)));
+
+    // Neither side reports itself.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(leecher_rx.try_recv().is_err());
+    assert!(seeder_rx.try_recv().is_err());
+    shutdown.cancel();
+}
+
+#[tokio::test]
+async fn peers_not_accepting_connections_only_listen() {
+    let group_port = 20000 + rand::random::<u16>() % 20000;
+    let info_hash = [0x24; 20];
+    let shutdown = CancellationToken::new();
+
+    let seeder = Lsd::bind(loopback_config(group_port, Some(5000))).unwrap();
+    let (seeder_tx, mut seeder_rx) = mpsc::channel(8);
+    seeder.add_torrent(info_hash, seeder_tx);
+
+    let leecher = Lsd::bind(loopback_config(group_port, None)).unwrap();
+    let (leecher_tx, mut leecher_rx) = mpsc::channel(8);
+    leecher.add_torrent(info_hash, leecher_tx);
+    tokio::spawn(leecher.clone().run(shutdown.clone()));
+    tokio::spawn(seeder.clone().run(shutdown.clone()));
+
+    let found = tokio::time::timeout(Duration::from_secs(5), leecher_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(found, SocketAddr::from(([127, 0, 0, 1], 5000)));
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(seeder_rx.try_recv().is_err(), "nothing announced a port");
+    shutdown.cancel();
+}
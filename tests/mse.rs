@@ -0,0 +1,71 @@
+use bittorrent_starter_rust::mse::{EncryptionPolicy, MseStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const INFO_HASH: [u8; 20] = [0x42; 20];
+
+#[tokio::test]
+async fn encrypted_streams_carry_data_both_ways() {
+    let (a, b) = tokio::io::duplex(4096);
+    let (initiator, responder) = tokio::join!(
+        MseStream::connect(a, &INFO_HASH, EncryptionPolicy::Require),
+        MseStream::accept(b, &[[0x01; 20], INFO_HASH], EncryptionPolicy::Prefer),
+    );
+    let mut initiator = initiator.unwrap();
+    let (mut responder, info_hash) = responder.unwrap();
+    assert_eq!(info_hash, Some(INFO_HASH));
+    assert!(initiator.is_encrypted() && responder.is_encrypted());
+
+    // Larger than the pipe, so writes have to wait for the other side.
+    let request: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+    let (written, received) = tokio::join!(
+        async {
+            initiator.write_all(&request).await?;
+            initiator.flush().await
+        },
+        async {
+            let mut buf = vec![0u8; request.len()];
+            responder.read_exact(&mut buf).await.map(|_| buf)
+        },
+    );
+    written.unwrap();
+    assert_eq!(received.unwrap(), request);
+
+    responder.write_all(b"pong").await.unwrap();
+    responder.flush().await.unwrap();
+    let mut pong = [0u8; 4];
+    initiator.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"pong");
+}
+
+#[tokio::test]
+async fn plaintext_handshakes_pass_through_unless_encryption_is_required() {
+    let handshake = b"\x13BitTorrent protocol\0\0\0\0\0\0\0\0";
+
+    let (mut a, b) = tokio::io::duplex(4096);
+    a.write_all(handshake).await.unwrap();
+    let (mut responder, info_hash) = MseStream::accept(b, &[INFO_HASH], EncryptionPolicy::Prefer)
+        .await
+        .unwrap();
+    assert_eq!(info_hash, None);
+    assert!(!responder.is_encrypted());
+    let mut received = [0u8; 28];
+    responder.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, handshake);
+
+    let (mut a, b) = tokio::io::duplex(4096);
+    a.write_all(handshake).await.unwrap();
+    let refused = MseStream::accept(b, &[INFO_HASH], EncryptionPolicy::Require).await;
+    assert!(refused.is_err());
+}
+
+#[tokio::test]
+async fn unknown_torrents_are_refused() {
+    let (a, b) = tokio::io::duplex(4096);
+    let (initiator, responder) = tokio::join!(
+        MseStream::connect(a, &INFO_HASH, EncryptionPolicy::Prefer),
+        // Failing drops the connection, so the initiator is not left waiting either.
+        MseStream::accept(b, &[[0x01; 20]], EncryptionPolicy::Prefer),
+    );
+    assert!(responder.is_err());
+    assert!(initiator.is_err());
+}
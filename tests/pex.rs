@@ -0,0 +1,58 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+use bittorrent_starter_rust::pex::{PexMessage, PexState, MAX_PEX_PEERS};
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn messages_round_trip_through_bencode() {
+    let added = [addr("10.0.0.1:6881"), addr("[2001:db8::1]:51413")];
+    let dropped = [addr("10.0.0.2:80")];
+    let msg = PexMessage::new(&added, &dropped);
+    assert_eq!(&msg.added[..], [10, 0, 0, 1, 0x1a, 0xe1]);
+    assert_eq!(msg.added_flags.len(), 1);
+    assert_eq!(msg.added6.len(), 18);
+    assert_eq!(msg.added6_flags.len(), 1);
+
+    let bytes = serde_bencode::to_bytes(&msg).unwrap();
+    let decoded: PexMessage = serde_bencode::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded.added_peers(), added);
+    assert_eq!(decoded.dropped_peers(), dropped);
+}
+
+#[test]
+fn missing_keys_and_partial_entries_are_ignored() {
+    let decoded: PexMessage =
+        serde_bencode::from_bytes(b"d5:added8:\x0a\x00\x00\x01\x1a\xe1\x00\x00e").unwrap();
+    assert_eq!(decoded.added_peers(), [addr("10.0.0.1:6881")]);
+    assert!(decoded.dropped_peers().is_empty());
+}
+
+#[test]
+fn outgoing_messages_carry_changes_at_most_once_a_minute() {
+    let us = addr("10.0.0.9:6881");
+    let mut live: HashSet<SocketAddr> = (1..=MAX_PEX_PEERS + 10)
+        .map(|n| addr(&format!("10.0.1.{}:{}", n % 250, 1000 + n)))
+        .collect();
+    live.insert(us);
+    let mut state = PexState::default();
+
+    let msg = state.next_message(&live, us).unwrap();
+    let added = msg.added_peers();
+    assert_eq!(added.len(), MAX_PEX_PEERS);
+    assert!(!added.contains(&us), "peers are not told about themselves");
+    // Nothing more goes out until the interval has passed.
+    live.clear();
+    assert!(state.next_message(&live, us).is_none());
+}
+
+#[test]
+fn incoming_messages_are_ignored_until_the_interval_passes() {
+    let mut state = PexState::default();
+    assert!(state.accept_incoming());
+    assert!(!state.accept_incoming());
+    assert!(!state.accept_incoming());
+}
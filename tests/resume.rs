@@ -0,0 +1,217 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use bittorrent_starter_rust::create::TorrentBuilder;
+use bittorrent_starter_rust::download::{download_swarm, SwarmOptions, TransferStats};
+use bittorrent_starter_rust::hash_pool::HashPool;
+use bittorrent_starter_rust::mock_peer::{test_data, MockAction, MockPeer, RecordingStorage};
+use bittorrent_starter_rust::mse::EncryptionPolicy;
+use bittorrent_starter_rust::resume::{ResumeData, ResumeState};
+use bittorrent_starter_rust::selection::FileSelection;
+use bittorrent_starter_rust::storage::{FsStorage, Storage};
+use bittorrent_starter_rust::torrent::Torrent;
+use tokio::sync::mpsc;
+
+const PIECE_LENGTH: usize = 1 << 16;
+const BLOCK: usize = 1 << 14;
+
+/// Two files on a piece boundary: pieces 0 and 1 hold `a.bin`, 2 to 4 `b.bin`.
+async fn dataset(root: &Path) -> (Arc<Torrent>, Vec<u8>) {
+    let files = [
+        ("a.bin", test_data(2 * PIECE_LENGTH, 1)),
+        ("b.bin", test_data(150_000, 2)),
+    ];
+    std::fs::create_dir_all(root).unwrap();
+    for (path, content) in &files {
+        std::fs::write(root.join(path), content).unwrap();
+    }
+    let torrent = TorrentBuilder::new(root)
+        .tracker("http://127.0.0.1:6969/announce")
+        .piece_length(PIECE_LENGTH)
+        .build()
+        .await
+        .unwrap();
+    let content = files.into_iter().flat_map(|(_, content)| content).collect();
+    (Arc::new(torrent), content)
+}
+
+async fn peers(peers: Vec<MockPeer>) -> mpsc::Receiver<SocketAddr> {
+    let (peers_tx, peers_rx) = mpsc::channel(peers.len().max(1));
+    for peer in peers {
+        let addr = peer.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        peers_tx.send(addr).await.unwrap();
+    }
+    peers_rx
+}
+
+/// Opens storage on `out` the way the download command does, checking resume data first.
+async fn open(torrent: &Arc<Torrent>, out: &Path) -> (ResumeState, Arc<dyn Storage>) {
+    let selection = FileSelection::new(&torrent.info);
+    let resume = ResumeState::load(torrent.clone(), resume_path(out), out, &selection).unwrap();
+    let storage = FsStorage::with_selection(torrent.clone(), out, &selection).unwrap();
+    (resume, Arc::new(storage))
+}
+
+fn resume_path(out: &Path) -> std::path::PathBuf {
+    out.with_extension("resume")
+}
+
+fn options(resume: &ResumeState) -> SwarmOptions {
+    SwarmOptions {
+        encryption: EncryptionPolicy::Plaintext,
+        resume: Some(resume.clone()),
+        ..Default::default()
+    }
+}
+
+async fn download(
+    torrent: &Arc<Torrent>,
+    peers_rx: mpsc::Receiver<SocketAddr>,
+    storage: &Arc<dyn Storage>,
+    resume: &ResumeState,
+) -> anyhow::Result<()> {
+    let stats = Arc::new(TransferStats::new(torrent.info.length()));
+    let result = download_swarm(
+        torrent.clone(),
+        peers_rx,
+        storage.clone(),
+        stats.clone(),
+        options(resume),
+    )
+    .await;
+    resume.save(storage, &stats).await.unwrap();
+    result
+}
+
+#[tokio::test]
+async fn unchanged_files_are_trusted_without_hashing() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, content) = dataset(&dir.path().join("dataset")).await;
+    let out = dir.path().join("out");
+
+    let (resume, storage) = open(&torrent, &out).await;
+    assert!(resume.recheck_pieces().is_empty());
+    let peers_rx = peers(vec![MockPeer::new(torrent.clone(), &content)]).await;
+    download(&torrent, peers_rx, &storage, &resume)
+        .await
+        .unwrap();
+    drop(storage);
+
+    let saved: ResumeData =
+        serde_bencode::from_bytes(&std::fs::read(resume_path(&out)).unwrap()).unwrap();
+    assert_eq!(saved.pieces[..], [0b1111_1000]);
+    assert_eq!(
+        (saved.uploaded, saved.downloaded),
+        (0, content.len() as u64)
+    );
+    assert_eq!(saved.files.len(), 2);
+    assert!(saved.partial.is_empty());
+
+    // Nothing is hashed again, nothing is fetched, and the peer is remembered.
+    let (resume, storage) = open(&torrent, &out).await;
+    assert!(resume.recheck_pieces().is_empty());
+    assert!((0..5).all(|piece_index| resume.has_piece(piece_index)));
+    let selection = FileSelection::new(&torrent.info);
+    assert_eq!(resume.missing_length(&selection), 0);
+    assert_eq!(resume.peers().len(), 1);
+    download(&torrent, peers(Vec::new()).await, &storage, &resume)
+        .await
+        .unwrap();
+    drop(storage);
+
+    // A file touched since is hashed again; a piece changed in it is fetched again.
+    let b_path = out.join("b.bin");
+    let mut b = std::fs::read(&b_path).unwrap();
+    b[PIECE_LENGTH + 10] ^= 0xff;
+    std::fs::write(&b_path, &b).unwrap();
+    let file = std::fs::File::options().write(true).open(&b_path).unwrap();
+    file.set_modified(SystemTime::now() + Duration::from_secs(5))
+        .unwrap();
+    drop(file);
+
+    let (resume, storage) = open(&torrent, &out).await;
+    assert_eq!(resume.recheck_pieces(), [2, 3, 4]);
+    assert_eq!(
+        resume.recheck(&storage, &HashPool::new(2)).await.unwrap(),
+        2
+    );
+    assert!(resume.has_piece(1) && !resume.has_piece(3));
+    let peers_rx = peers(vec![MockPeer::new(torrent.clone(), &content)]).await;
+    download(&torrent, peers_rx, &storage, &resume)
+        .await
+        .unwrap();
+    assert!(std::fs::read(&b_path).unwrap() == content[2 * PIECE_LENGTH..]);
+}
+
+#[tokio::test]
+async fn files_on_disk_are_hashed_without_resume_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, content) = dataset(&dir.path().join("dataset")).await;
+    let out = dir.path().join("out");
+    std::fs::create_dir_all(&out).unwrap();
+    std::fs::write(out.join("a.bin"), &content[..2 * PIECE_LENGTH]).unwrap();
+
+    let (resume, storage) = open(&torrent, &out).await;
+    assert_eq!(resume.recheck_pieces(), [0, 1]);
+    assert_eq!(
+        resume.recheck(&storage, &HashPool::new(2)).await.unwrap(),
+        2
+    );
+    let selection = FileSelection::new(&torrent.info);
+    assert_eq!(
+        resume.missing_length(&selection),
+        content.len() - 2 * PIECE_LENGTH
+    );
+
+    // Resume data that does not parse is no better than none.
+    std::fs::write(resume_path(&out), b"d9:info-hash3:abce").unwrap();
+    let (resume, _) = open(&torrent, &out).await;
+    assert_eq!(resume.recheck_pieces(), [0, 1, 2, 3, 4]);
+}
+
+#[tokio::test]
+async fn blocks_of_unfinished_pieces_are_not_fetched_again() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, content) = dataset(&dir.path().join("dataset")).await;
+    let out = dir.path().join("out");
+
+    // The peer hangs up halfway through the second piece.
+    let (resume, storage) = open(&torrent, &out).await;
+    let peer = MockPeer::new(torrent.clone(), &content).after_blocks(6, MockAction::Drop);
+    let err = download(&torrent, peers(vec![peer]).await, &storage, &resume)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("ran out of peers"), "{err:#}");
+    drop(storage);
+    let saved: ResumeData =
+        serde_bencode::from_bytes(&std::fs::read(resume_path(&out)).unwrap()).unwrap();
+    assert_eq!(saved.pieces[..], [0b1000_0000]);
+    assert_eq!(saved.partial.len(), 1);
+    assert_eq!(saved.partial[0].piece, 1);
+    assert_eq!(saved.partial[0].blocks[..], [0b1100_0000]);
+
+    let seed = Arc::new(RecordingStorage::new(torrent.clone()));
+    for piece_index in 0..torrent.info.piece_count() {
+        let start = piece_index * PIECE_LENGTH;
+        let piece = &content[start..start + torrent.info.piece_size(piece_index)];
+        seed.write_block(piece_index, 0, piece).unwrap();
+    }
+    let (resume, storage) = open(&torrent, &out).await;
+    assert!(resume.recheck_pieces().is_empty());
+    let peer = MockPeer::seeding(seed.clone());
+    download(&torrent, peers(vec![peer]).await, &storage, &resume)
+        .await
+        .unwrap();
+
+    let reads: Vec<_> = seed
+        .take_reads()
+        .into_iter()
+        .map(|(piece_index, begin, _)| (piece_index, begin))
+        .collect();
+    assert!(!reads.iter().any(|&(piece_index, _)| piece_index == 0));
+    assert!(!reads.contains(&(1, 0)) && !reads.contains(&(1, BLOCK)));
+    assert!(reads.contains(&(1, 2 * BLOCK)));
+    assert!(std::fs::read(out.join("a.bin")).unwrap() == content[..2 * PIECE_LENGTH]);
+}
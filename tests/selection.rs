@@ -0,0 +1,161 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use bittorrent_starter_rust::create::TorrentBuilder;
+use bittorrent_starter_rust::download::{download_swarm, SwarmOptions, TransferStats};
+use bittorrent_starter_rust::mock_peer::{test_data, MockPeer, RecordingStorage};
+use bittorrent_starter_rust::mse::EncryptionPolicy;
+use bittorrent_starter_rust::selection::{FilePriority, FileSelection};
+use bittorrent_starter_rust::storage::{FsStorage, PartFile, Storage};
+use bittorrent_starter_rust::torrent::Torrent;
+use tokio::sync::mpsc;
+
+const PIECE_LENGTH: usize = 1 << 14;
+
+/// Files straddling piece boundaries: pieces 0 and 1 hold `a.bin`, 1 and 2 `b.txt`, 2 to 4
+/// `sub/c.bin`.
+async fn dataset(root: &Path) -> (Torrent, Vec<(&'static str, Vec<u8>)>) {
+    let files = vec![
+        ("a.bin", test_data(20_000, 1)),
+        ("b.txt", test_data(15_000, 2)),
+        ("sub/c.bin", test_data(35_000, 3)),
+    ];
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    for (path, content) in &files {
+        std::fs::write(root.join(path), content).unwrap();
+    }
+    let torrent = TorrentBuilder::new(root)
+        .tracker("http://127.0.0.1:6969/announce")
+        .piece_length(PIECE_LENGTH)
+        .build()
+        .await
+        .unwrap();
+    (torrent, files)
+}
+
+fn options(selection: FileSelection) -> SwarmOptions {
+    SwarmOptions {
+        encryption: EncryptionPolicy::Plaintext,
+        selection: Some(selection),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn pieces_take_the_highest_priority_of_their_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, _) = dataset(&dir.path().join("dataset")).await;
+    let info = &torrent.info;
+    use FilePriority::*;
+
+    let selection = FileSelection::new(info);
+    assert_eq!(selection.piece_priorities(), [Normal; 5]);
+    assert_eq!(selection.wanted_length(), info.length());
+
+    let selection = FileSelection::new(info)
+        .prioritize("*.bin", Low)
+        .unwrap()
+        .prioritize("sub/*", High)
+        .unwrap();
+    assert_eq!(selection.priorities(), [Low, Normal, High]);
+    assert_eq!(
+        selection.piece_priorities(),
+        [Low, Normal, High, High, High]
+    );
+
+    let selection = FileSelection::new(info).only(&["b.txt"]).unwrap();
+    assert_eq!(selection.priorities(), [Skip, Normal, Skip]);
+    assert_eq!(
+        selection.piece_priorities(),
+        [Skip, Normal, Normal, Skip, Skip]
+    );
+    assert_eq!(selection.wanted_length(), 2 * PIECE_LENGTH);
+
+    let selection = FileSelection::new(info).skip(&["**/*.bin"]).unwrap();
+    assert_eq!(selection.priorities(), [Skip, Normal, Skip]);
+
+    let err = FileSelection::new(info).only(&["a[.bin"]).unwrap_err();
+    assert!(err.to_string().contains("a[.bin"), "{err:#}");
+}
+
+#[tokio::test]
+async fn skipped_files_are_never_created() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, files) = dataset(&dir.path().join("dataset")).await;
+    let torrent = Arc::new(torrent);
+    let content: Vec<u8> = files.iter().flat_map(|(_, data)| data.clone()).collect();
+    let peer = MockPeer::new(torrent.clone(), &content)
+        .listen("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let (peers_tx, peers_rx) = mpsc::channel(1);
+    peers_tx.send(peer).await.unwrap();
+
+    let selection = FileSelection::new(&torrent.info).only(&["b.txt"]).unwrap();
+    let out = dir.path().join("out");
+    let storage = FsStorage::with_selection(torrent.clone(), &out, &selection).unwrap();
+    let stats = Arc::new(TransferStats::new(selection.wanted_length()));
+    download_swarm(
+        torrent.clone(),
+        peers_rx,
+        Arc::new(storage),
+        stats.clone(),
+        options(selection),
+    )
+    .await
+    .unwrap();
+
+    assert!(std::fs::read(out.join("b.txt")).unwrap() == files[1].1);
+    assert!(!out.join("a.bin").exists());
+    assert!(!out.join("sub").exists());
+    assert_eq!(stats.left.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+    // The neighbouring files' share of the boundary pieces went to the part file.
+    let part = std::fs::read(PartFile::path(&out)).unwrap();
+    assert_eq!(part.len(), content.len());
+    let (b_start, b_end) = (20_000, 35_000);
+    assert!(part[PIECE_LENGTH..b_start] == content[PIECE_LENGTH..b_start]);
+    assert!(part[b_end..3 * PIECE_LENGTH] == content[b_end..3 * PIECE_LENGTH]);
+    assert!(part[..PIECE_LENGTH].iter().all(|&byte| byte == 0));
+
+    // Reopening with the same selection reads the boundary pieces back whole.
+    let selection = FileSelection::new(&torrent.info).only(&["b.txt"]).unwrap();
+    let storage = FsStorage::with_selection(torrent.clone(), &out, &selection).unwrap();
+    assert!(storage.verify(1).unwrap());
+    assert!(storage.verify(2).unwrap());
+    assert!(!out.join("a.bin").exists());
+}
+
+#[tokio::test]
+async fn higher_priority_pieces_are_fetched_first() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, files) = dataset(&dir.path().join("dataset")).await;
+    let torrent = Arc::new(torrent);
+    let content: Vec<u8> = files.iter().flat_map(|(_, data)| data.clone()).collect();
+    let peer = MockPeer::new(torrent.clone(), &content)
+        .listen("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let (peers_tx, peers_rx) = mpsc::channel(1);
+    peers_tx.send(peer).await.unwrap();
+
+    let selection = FileSelection::new(&torrent.info)
+        .prioritize("a.bin", FilePriority::Low)
+        .unwrap()
+        .prioritize("sub/c.bin", FilePriority::High)
+        .unwrap();
+    let storage = Arc::new(RecordingStorage::new(torrent.clone()));
+    let stats = Arc::new(TransferStats::new(content.len()));
+    download_swarm(
+        torrent,
+        peers_rx,
+        storage.clone(),
+        stats,
+        options(selection),
+    )
+    .await
+    .unwrap();
+
+    assert!(storage.contents() == content);
+    assert_eq!(storage.pieces_started(), [2, 3, 4, 1, 0]);
+}
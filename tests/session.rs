@@ -0,0 +1,168 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bittorrent_starter_rust::mock_peer::{test_data, torrent_for, MockPeer};
+use bittorrent_starter_rust::mse::EncryptionPolicy;
+use bittorrent_starter_rust::rate_limit::RateLimiter;
+use bittorrent_starter_rust::resume::{self, ResumeData};
+use bittorrent_starter_rust::session::{Session, SessionConfig, TorrentState, TorrentStatus};
+
+const PIECE_LENGTH: usize = 1 << 14;
+
+/// A session on a free local port that only meets the peers it is given.
+async fn session(encryption: EncryptionPolicy) -> Session {
+    Session::start(SessionConfig {
+        listen_addr: "127.0.0.1:0".parse().unwrap(),
+        dht: None,
+        utp: false,
+        web_seeds: false,
+        encryption,
+        ..Default::default()
+    })
+    .await
+    .unwrap()
+}
+
+/// Polls the torrent's status until `done` holds for it.
+async fn wait_until(
+    session: &Session,
+    info_hash: &[u8; 20],
+    done: impl Fn(&TorrentStatus) -> bool,
+) -> TorrentStatus {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    loop {
+        let status = session.status(info_hash).unwrap();
+        if done(&status) {
+            return status;
+        }
+        assert!(Instant::now() < deadline, "gave up waiting: {status:?}");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn downloads_torrents_side_by_side() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = session(EncryptionPolicy::Plaintext).await;
+    let mut added = Vec::new();
+    for (name, seed) in [("one.bin", 1), ("two.bin", 2)] {
+        let data = test_data(5 * PIECE_LENGTH + 321, seed);
+        let torrent = torrent_for(name, &data, PIECE_LENGTH);
+        let peer = MockPeer::new(Arc::new(torrent.clone()), &data)
+            .listen("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let info_hash = session.add(torrent, dir.path().join(name)).unwrap();
+        session.add_peer(&info_hash, peer).unwrap();
+        added.push((info_hash, name, data));
+    }
+    let torrent = torrent_for("one.bin", &added[0].2, PIECE_LENGTH);
+    assert!(session.add(torrent, dir.path().join("again.bin")).is_err());
+    // A different torrent with the same name would write over the first one's file.
+    let torrent = torrent_for("one.bin", &test_data(PIECE_LENGTH, 9), PIECE_LENGTH);
+    let err = session
+        .add(torrent, dir.path().join("one.bin"))
+        .unwrap_err();
+    assert!(err.to_string().contains("already used"), "{err:#}");
+    assert_eq!(session.torrents().len(), 2);
+
+    for (info_hash, name, data) in &added {
+        let status = wait_until(&session, info_hash, |status| {
+            status.state != TorrentState::Checking && status.state != TorrentState::Downloading
+        })
+        .await;
+        assert_eq!(status.state, TorrentState::Finished);
+        assert_eq!(&status.name, name);
+        assert_eq!((status.left, status.downloaded), (0, data.len()));
+        assert!(std::fs::read(dir.path().join(name)).unwrap() == *data);
+    }
+
+    // Removing keeps the files; the session carries on without the torrent.
+    session.remove(&added[0].0).await.unwrap();
+    assert!(session.status(&added[0].0).is_none());
+    assert!(dir.path().join("one.bin").exists());
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn paused_torrents_pick_up_where_they_left_off() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = session(EncryptionPolicy::Plaintext).await;
+    let data = test_data(20 * PIECE_LENGTH, 3);
+    let torrent = torrent_for("slow.bin", &data, PIECE_LENGTH);
+    let peer = MockPeer::new(Arc::new(torrent.clone()), &data)
+        .delay_blocks(Duration::from_millis(30))
+        .listen("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let info_hash = session.add(torrent, dir.path().join("slow.bin")).unwrap();
+    session.add_peer(&info_hash, peer).unwrap();
+
+    wait_until(&session, &info_hash, |status| status.downloaded > 0).await;
+    session.pause(&info_hash).await.unwrap();
+    let status = session.status(&info_hash).unwrap();
+    assert_eq!(status.state, TorrentState::Paused);
+    assert!(status.left > 0);
+    assert!(session.add_peer(&info_hash, peer).is_err());
+
+    // Resuming checks nothing again; the peers saved on pausing are tried first.
+    session.resume(&info_hash).unwrap();
+    let status = wait_until(&session, &info_hash, |status| {
+        status.state == TorrentState::Finished
+    })
+    .await;
+    assert_eq!(status.downloaded, data.len());
+    assert!(std::fs::read(dir.path().join("slow.bin")).unwrap() == data);
+    session.shutdown().await.unwrap();
+
+    // Both runs are counted in the saved total, once each.
+    let resume_path = resume::default_path(&dir.path().join("slow.bin"));
+    let saved: ResumeData =
+        serde_bencode::from_bytes(&std::fs::read(resume_path).unwrap()).unwrap();
+    assert_eq!(saved.downloaded, data.len() as u64);
+}
+
+#[tokio::test]
+async fn peers_connecting_to_us_are_handed_to_their_torrent() {
+    let dir = tempfile::tempdir().unwrap();
+    // Plaintext peers get through to a session preferring encryption.
+    let session = session(EncryptionPolicy::Prefer).await;
+    let data = test_data(4 * PIECE_LENGTH + 99, 4);
+    let torrent = torrent_for("incoming.bin", &data, PIECE_LENGTH);
+    let peer = MockPeer::new(Arc::new(torrent.clone()), &data);
+    let stranger = {
+        let other = data[..PIECE_LENGTH].to_vec();
+        let torrent = torrent_for("other.bin", &other, PIECE_LENGTH);
+        MockPeer::new(Arc::new(torrent), &other)
+    };
+    let info_hash = session
+        .add(torrent, dir.path().join("incoming.bin"))
+        .unwrap();
+
+    // A peer for a torrent we do not have is turned away.
+    assert!(stranger.connect(session.local_addr()).await.is_err());
+    tokio::spawn(peer.connect(session.local_addr()));
+    wait_until(&session, &info_hash, |status| {
+        status.state == TorrentState::Finished
+    })
+    .await;
+    assert!(std::fs::read(dir.path().join("incoming.bin")).unwrap() == data);
+    session.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rate_limiter_holds_transfers_to_its_rate() {
+    let limiter = RateLimiter::new(100_000);
+    let start = Instant::now();
+    // A second's worth goes through at once, the next second's has to wait for it.
+    let transfers = (0..10).map(|_| {
+        let limiter = limiter.clone();
+        tokio::spawn(async move { limiter.acquire(20_000).await })
+    });
+    for transfer in transfers.collect::<Vec<_>>() {
+        transfer.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(900), "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+}
@@ -0,0 +1,236 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+
+use bittorrent_starter_rust::create::{MetaVersion, TorrentBuilder};
+use bittorrent_starter_rust::mock_peer::{test_data, torrent_for};
+use bittorrent_starter_rust::storage::{
+    check_plain_name, FsStorage, MemoryStorage, MmapStorage, NullStorage, Storage,
+};
+use bittorrent_starter_rust::torrent::{FileEntry, Torrent};
+
+const PIECE_LENGTH: usize = 1 << 14;
+const BLOCK: usize = 4096;
+
+/// The content of every piece, laid out as the torrent's pieces cover it.
+fn pieces_of(torrent: &Torrent, content: &[u8]) -> Vec<Vec<u8>> {
+    (0..torrent.info.piece_count())
+        .map(|i| {
+            let start = i * torrent.info.piece_length;
+            content[start..start + torrent.info.piece_size(i)].to_vec()
+        })
+        .collect()
+}
+
+/// Writes every piece in blocks, last block of the last piece first.
+fn write_backwards(storage: &dyn Storage, pieces: &[Vec<u8>]) {
+    for (piece_index, piece) in pieces.iter().enumerate().rev() {
+        let blocks: Vec<_> = piece.chunks(BLOCK).enumerate().collect();
+        for (i, block) in blocks.into_iter().rev() {
+            storage.write_block(piece_index, i * BLOCK, block).unwrap();
+        }
+    }
+}
+
+async fn hybrid_torrent(root: &Path) -> (Torrent, Vec<u8>, Vec<u8>) {
+    let big = test_data(2 * PIECE_LENGTH + 3000, 1);
+    let small = test_data(5000, 2);
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(root.join("big.bin"), &big).unwrap();
+    std::fs::write(root.join("sub/small.bin"), &small).unwrap();
+    let torrent = TorrentBuilder::new(root)
+        .tracker("http://127.0.0.1:6969/announce")
+        .piece_length(PIECE_LENGTH)
+        .meta_version(MetaVersion::Hybrid)
+        .build()
+        .await
+        .unwrap();
+    (torrent, big, small)
+}
+
+#[tokio::test]
+async fn fs_storage_splits_pieces_over_files_and_skips_padding() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, big, small) = hybrid_torrent(&dir.path().join("source")).await;
+    let torrent = Arc::new(torrent);
+    let mut content = big.clone();
+    content.resize(3 * PIECE_LENGTH, 0);
+    content.extend(&small);
+    content.resize(torrent.info.length(), 0);
+    let pieces = pieces_of(&torrent, &content);
+
+    let out = dir.path().join("out");
+    let storage = FsStorage::new(torrent.clone(), &out).unwrap();
+    write_backwards(&storage, &pieces);
+    storage.flush().unwrap();
+
+    assert!(std::fs::read(out.join("big.bin")).unwrap() == big);
+    assert!(std::fs::read(out.join("sub/small.bin")).unwrap() == small);
+    assert!(!out.join(".pad").exists());
+    for piece_index in 0..pieces.len() {
+        assert!(storage.verify(piece_index).unwrap(), "{piece_index}");
+    }
+
+    // Reopening keeps what is already on disk.
+    let reopened = FsStorage::new(torrent, &out).unwrap();
+    let mut piece = vec![0; PIECE_LENGTH];
+    reopened.read_block(2, 0, &mut piece).unwrap();
+    assert!(piece == pieces[2]);
+}
+
+#[test]
+fn fs_storage_preallocates_and_trims_single_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = test_data(3 * PIECE_LENGTH + 100, 3);
+    let torrent = Arc::new(torrent_for("data.bin", &data, PIECE_LENGTH));
+    let path = dir.path().join("data.bin");
+    std::fs::write(&path, vec![1; data.len() * 2]).unwrap();
+
+    let storage = FsStorage::new(torrent.clone(), &path).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), data.len() as u64);
+    assert!(!storage.verify(3).unwrap());
+
+    write_backwards(&storage, &pieces_of(&torrent, &data));
+    storage.flush().unwrap();
+    assert!(std::fs::read(&path).unwrap() == data);
+}
+
+#[tokio::test]
+async fn mmap_storage_splits_pieces_over_files_and_skips_padding() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, big, small) = hybrid_torrent(&dir.path().join("source")).await;
+    let torrent = Arc::new(torrent);
+    let mut content = big.clone();
+    content.resize(3 * PIECE_LENGTH, 0);
+    content.extend(&small);
+    content.resize(torrent.info.length(), 0);
+    let pieces = pieces_of(&torrent, &content);
+
+    let out = dir.path().join("out");
+    let storage = MmapStorage::new(torrent.clone(), &out).unwrap();
+    write_backwards(&storage, &pieces);
+    storage.flush().unwrap();
+
+    assert!(std::fs::read(out.join("big.bin")).unwrap() == big);
+    assert!(std::fs::read(out.join("sub/small.bin")).unwrap() == small);
+    assert!(!out.join(".pad").exists());
+    for piece_index in 0..pieces.len() {
+        assert!(storage.verify(piece_index).unwrap(), "{piece_index}");
+    }
+
+    // Reopening keeps what is already on disk.
+    let reopened = MmapStorage::new(torrent, &out).unwrap();
+    let mut piece = vec![0; PIECE_LENGTH];
+    reopened.read_block(2, 0, &mut piece).unwrap();
+    assert!(piece == pieces[2]);
+}
+
+#[test]
+fn mmap_storage_preallocates_and_trims_single_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = test_data(3 * PIECE_LENGTH + 100, 3);
+    let torrent = Arc::new(torrent_for("data.bin", &data, PIECE_LENGTH));
+    let path = dir.path().join("data.bin");
+    std::fs::write(&path, vec![1; data.len() * 2]).unwrap();
+
+    let storage = MmapStorage::new(torrent.clone(), &path).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), data.len() as u64);
+    assert!(!storage.verify(3).unwrap());
+
+    write_backwards(&storage, &pieces_of(&torrent, &data));
+    storage.flush().unwrap();
+    assert!(std::fs::read(&path).unwrap() == data);
+}
+
+#[test]
+fn memory_storage_fills_gaps_with_zeros() {
+    let data = test_data(2 * PIECE_LENGTH + 10, 4);
+    let torrent = Arc::new(torrent_for("data.bin", &data, PIECE_LENGTH));
+    let storage = MemoryStorage::new(torrent.clone());
+    let pieces = pieces_of(&torrent, &data);
+
+    storage.write_block(2, 0, &pieces[2]).unwrap();
+    storage.write_block(0, BLOCK, &pieces[0][BLOCK..]).unwrap();
+    assert!(storage.verify(2).unwrap());
+    assert!(!storage.verify(0).unwrap());
+    assert!(!storage.verify(1).unwrap());
+
+    let mut expected = data.clone();
+    expected[..BLOCK].fill(0);
+    expected[PIECE_LENGTH..2 * PIECE_LENGTH].fill(0);
+    assert!(storage.contents() == expected);
+}
+
+#[test]
+fn blocks_outside_their_piece_are_rejected() {
+    let data = test_data(PIECE_LENGTH + 10, 5);
+    let torrent = Arc::new(torrent_for("data.bin", &data, PIECE_LENGTH));
+    let dir = tempfile::tempdir().unwrap();
+    let storages: [Box<dyn Storage>; 4] = [
+        Box::new(FsStorage::new(torrent.clone(), dir.path().join("data.bin")).unwrap()),
+        Box::new(MmapStorage::new(torrent.clone(), dir.path().join("mmap")).unwrap()),
+        Box::new(MemoryStorage::new(torrent.clone())),
+        Box::new(NullStorage::new(torrent.clone())),
+    ];
+    for storage in &storages {
+        storage.write_block(1, 0, &[0; 10]).unwrap();
+        for (piece_index, begin, len) in [(1, 0, 11), (1, 5, 6), (2, 0, 1)] {
+            let err = storage
+                .write_block(piece_index, begin, &vec![0; len])
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+}
+
+#[test]
+fn null_storage_keeps_nothing_to_read() {
+    let data = test_data(PIECE_LENGTH, 6);
+    let torrent = Arc::new(torrent_for("data.bin", &data, PIECE_LENGTH));
+    let storage = NullStorage::new(torrent);
+
+    storage.write_block(0, 0, &data).unwrap();
+    let err = storage.verify(0).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+}
+
+#[test]
+fn file_paths_may_not_leave_the_download_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    for bad in ["..", "", ".", "/etc", "a/b"] {
+        let data = test_data(PIECE_LENGTH, 7);
+        let mut torrent = torrent_for("dir", &data, PIECE_LENGTH);
+        torrent.info.length = None;
+        torrent.info.files = Some(vec![
+            FileEntry {
+                length: 10,
+                path: vec![String::from("fine")],
+                attr: None,
+            },
+            FileEntry {
+                length: PIECE_LENGTH - 10,
+                path: vec![bad.to_string(), String::from("escaped")],
+                attr: None,
+            },
+        ]);
+        let torrent = Arc::new(torrent);
+        let root = dir.path().join("downloads");
+        let err = FsStorage::new(torrent.clone(), &root).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{bad:?}");
+        let err = MmapStorage::new(torrent, &root).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{bad:?}");
+        assert!(!root.exists(), "nothing is created for {bad:?}");
+    }
+    assert!(!dir.path().join("escaped").exists());
+}
+
+#[test]
+fn only_plain_names_pass_the_name_check() {
+    for good in ["data.bin", "my movie", "..hidden"] {
+        assert!(check_plain_name(good).is_ok(), "{good:?}");
+    }
+    for bad in ["..", "", ".", "/etc", "a/b", "dir/"] {
+        let err = check_plain_name(bad).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{bad:?}");
+    }
+}
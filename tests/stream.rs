@@ -0,0 +1,124 @@
+use std::io::SeekFrom;
+use std::sync::Arc;
+
+use bittorrent_starter_rust::download::{download_swarm, SwarmOptions, TransferStats};
+use bittorrent_starter_rust::mock_peer::{test_data, torrent_for, MockPeer, RecordingStorage};
+use bittorrent_starter_rust::mse::EncryptionPolicy;
+use bittorrent_starter_rust::storage::{MemoryStorage, Storage};
+use bittorrent_starter_rust::stream::StreamControl;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc;
+
+const PIECE_LENGTH: usize = 1 << 14;
+
+fn options(streaming: &StreamControl) -> SwarmOptions {
+    SwarmOptions {
+        encryption: EncryptionPolicy::Plaintext,
+        streaming: Some(streaming.clone()),
+        ..Default::default()
+    }
+}
+
+async fn swarm_of(peers: Vec<MockPeer>) -> mpsc::Receiver<std::net::SocketAddr> {
+    let (peers_tx, peers_rx) = mpsc::channel(peers.len().max(1));
+    for peer in peers {
+        let addr = peer.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        peers_tx.send(addr).await.unwrap();
+    }
+    peers_rx
+}
+
+#[tokio::test]
+async fn reads_the_file_while_it_downloads() {
+    let data = test_data(10 * PIECE_LENGTH + 1234, 0);
+    let torrent = Arc::new(torrent_for("movie.bin", &data, PIECE_LENGTH));
+    let peers_rx = swarm_of(vec![
+        MockPeer::new(torrent.clone(), &data),
+        MockPeer::new(torrent.clone(), &data),
+    ])
+    .await;
+
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new(torrent.clone()));
+    let streaming = StreamControl::new();
+    let mut reader = streaming.open_file(storage.clone(), 0).unwrap();
+    assert_eq!(reader.len(), data.len() as u64);
+    let download = tokio::spawn(download_swarm(
+        torrent.clone(),
+        peers_rx,
+        storage,
+        Arc::new(TransferStats::new(data.len())),
+        options(&streaming),
+    ));
+
+    let mut read = Vec::new();
+    reader.read_to_end(&mut read).await.unwrap();
+    assert!(read == data);
+    download.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn pieces_after_a_seek_are_fetched_first() {
+    let data = test_data(10 * PIECE_LENGTH, 0);
+    let torrent = Arc::new(torrent_for("movie.bin", &data, PIECE_LENGTH));
+    let peers_rx = swarm_of(vec![MockPeer::new(torrent.clone(), &data)]).await;
+
+    let storage = Arc::new(RecordingStorage::new(torrent.clone()));
+    let streaming = StreamControl::new();
+    let mut reader = streaming
+        .open_file(storage.clone(), 0)
+        .unwrap()
+        .readahead(2 * PIECE_LENGTH);
+    let start = 6 * PIECE_LENGTH + 100;
+    assert_eq!(
+        reader.seek(SeekFrom::Start(start as u64)).await.unwrap(),
+        start as u64
+    );
+    let download = tokio::spawn(download_swarm(
+        torrent.clone(),
+        peers_rx,
+        storage.clone(),
+        Arc::new(TransferStats::new(data.len())),
+        options(&streaming),
+    ));
+
+    let mut read = vec![0; PIECE_LENGTH];
+    reader.read_exact(&mut read).await.unwrap();
+    assert!(read == data[start..start + PIECE_LENGTH]);
+    assert_eq!(storage.pieces_started()[..2], [6, 7]);
+
+    // Seeking back from the end reads what came before.
+    reader.seek(SeekFrom::End(-10)).await.unwrap();
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail).await.unwrap();
+    assert!(tail == data[data.len() - 10..]);
+    assert!(reader.seek(SeekFrom::Current(-100_000_000)).await.is_err());
+    download.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn reads_fail_once_the_download_does() {
+    let data = test_data(4 * PIECE_LENGTH, 0);
+    let torrent = Arc::new(torrent_for("movie.bin", &data, PIECE_LENGTH));
+    let storage = Arc::new(MemoryStorage::new(torrent.clone()));
+    // A piece on hand before the download is readable without it.
+    storage.write_block(0, 0, &data[..PIECE_LENGTH]).unwrap();
+    let streaming = StreamControl::new();
+    streaming.piece_verified(0);
+    let mut reader = streaming.open_file(storage.clone(), 0).unwrap();
+
+    let peers_rx = swarm_of(Vec::new()).await;
+    let download = download_swarm(
+        torrent,
+        peers_rx,
+        storage,
+        Arc::new(TransferStats::new(data.len())),
+        options(&streaming),
+    );
+    assert!(download.await.is_err());
+
+    let mut read = vec![0; PIECE_LENGTH];
+    reader.read_exact(&mut read).await.unwrap();
+    assert!(read == data[..PIECE_LENGTH]);
+    let err = reader.read_exact(&mut read).await.unwrap_err();
+    assert!(err.to_string().contains("ran out of peers"), "{err}");
+}
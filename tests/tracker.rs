@@ -0,0 +1,479 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bittorrent_starter_rust::download::TransferStats;
+use bittorrent_starter_rust::mock_peer::torrent_for;
+use bittorrent_starter_rust::scrape::scrape;
+use bittorrent_starter_rust::torrent::Torrent;
+use bittorrent_starter_rust::tracker::{
+    announce, AnnounceEvent, TrackerError, TrackerRequest, TrackerResponse, TrackerSession,
+    TrackerTiers,
+};
+use bittorrent_starter_rust::tracker_server::{TrackerServer, TrackerServerConfig};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+
+const INFO_HASH: [u8; 20] = [7; 20];
+
+async fn start(config: TrackerServerConfig) -> (String, CancellationToken) {
+    let server = TrackerServer::bind(TrackerServerConfig {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        ..config
+    })
+    .await
+    .unwrap();
+    let url = server.announce_url().unwrap();
+    let shutdown = CancellationToken::new();
+    tokio::spawn(server.run(shutdown.clone()));
+    (url, shutdown)
+}
+
+fn request(peer_id: &str, port: u16, left: usize) -> TrackerRequest {
+    TrackerRequest {
+        peer_id: format!("{peer_id:-<20}"),
+        port,
+        ..TrackerRequest::new(left)
+    }
+}
+
+#[tokio::test]
+async fn announces_return_the_other_peers_in_compact_form() {
+    let (url, _shutdown) = start(TrackerServerConfig::default()).await;
+
+    let seeder = announce(&url, &INFO_HASH, &request("seeder", 7001, 0))
+        .await
+        .unwrap();
+    assert!(seeder.peer_addrs().is_empty());
+    assert_eq!(seeder.interval, 1800);
+
+    let leecher = announce(&url, &INFO_HASH, &request("leecher", 7002, 100))
+        .await
+        .unwrap();
+    let seeder_addr: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+    assert_eq!(leecher.peer_addrs(), vec![seeder_addr]);
+    assert_eq!(leecher.complete, Some(1));
+    assert_eq!(leecher.incomplete, Some(1));
+
+    // Another torrent has a swarm of its own.
+    let other = announce(&url, &[8; 20], &request("leecher", 7002, 100))
+        .await
+        .unwrap();
+    assert!(other.peer_addrs().is_empty());
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerDict {
+    #[serde(rename = "peer id")]
+    peer_id: ByteBuf,
+    ip: String,
+    port: u16,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListResponse {
+    peers: Vec<PeerDict>,
+}
+
+#[tokio::test]
+async fn announces_can_ask_for_a_list_of_dictionaries() {
+    let (url, _shutdown) = start(TrackerServerConfig::default()).await;
+    announce(&url, &INFO_HASH, &request("seeder", 7001, 0))
+        .await
+        .unwrap();
+
+    let url = format!(
+        "{url}?info_hash={}&peer_id={:-<20}&port=7002&left=100&compact=0",
+        "%07".repeat(20),
+        "leecher"
+    );
+    let body = reqwest::get(&url).await.unwrap().bytes().await.unwrap();
+    let response: ListResponse = serde_bencode::from_bytes(&body).unwrap();
+    assert_eq!(response.peers.len(), 1);
+    let peer = &response.peers[0];
+    assert_eq!(&peer.peer_id[..], format!("{:-<20}", "seeder").as_bytes());
+    assert_eq!(peer.ip, "127.0.0.1");
+    assert_eq!(peer.port, 7001);
+}
+
+#[tokio::test]
+async fn scrapes_count_seeders_leechers_and_completions() {
+    let (url, _shutdown) = start(TrackerServerConfig::default()).await;
+    announce(&url, &INFO_HASH, &request("seeder", 7001, 0))
+        .await
+        .unwrap();
+    announce(&url, &INFO_HASH, &request("leecher", 7002, 100))
+        .await
+        .unwrap();
+    let completed = TrackerRequest {
+        event: Some(AnnounceEvent::Completed),
+        ..request("finisher", 7003, 0)
+    };
+    announce(&url, &INFO_HASH, &completed).await.unwrap();
+    let stopped = TrackerRequest {
+        event: Some(AnnounceEvent::Stopped),
+        ..request("leecher", 7002, 100)
+    };
+    announce(&url, &INFO_HASH, &stopped).await.unwrap();
+
+    let stats = scrape(&url, &[INFO_HASH, [8; 20]]).await.unwrap();
+    assert_eq!(stats.len(), 1, "unknown torrents are left out");
+    let stats = stats[&INFO_HASH];
+    assert_eq!(stats.complete, 2);
+    assert_eq!(stats.incomplete, 0);
+    assert_eq!(stats.downloaded, 1);
+}
+
+#[tokio::test]
+async fn peers_that_stop_announcing_expire() {
+    let (url, _shutdown) = start(TrackerServerConfig {
+        interval: Duration::from_millis(50),
+        peer_ttl: Duration::from_millis(100),
+        ..Default::default()
+    })
+    .await;
+    announce(&url, &INFO_HASH, &request("seeder", 7001, 0))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let leecher = announce(&url, &INFO_HASH, &request("leecher", 7002, 100))
+        .await
+        .unwrap();
+    assert!(leecher.peer_addrs().is_empty());
+    assert_eq!(leecher.complete, Some(0));
+}
+
+#[tokio::test]
+async fn malformed_announces_are_rejected() {
+    let (url, _shutdown) = start(TrackerServerConfig::default()).await;
+    let body = reqwest::get(format!("{url}?info_hash=short&port=1"))
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    let err = TrackerResponse::from_bytes(&body).unwrap_err();
+    assert!(err.to_string().contains("info_hash"), "{err}");
+}
+
+#[test]
+fn responses_carry_the_failure_reason_and_warning() {
+    let err = TrackerResponse::from_bytes(b"d14:failure reason11:not allowede").unwrap_err();
+    assert!(matches!(&err, TrackerError::Failure(reason) if reason == "not allowed"));
+
+    let res = TrackerResponse::from_bytes(
+        b"d8:intervali900e12:min intervali1200e15:warning message4:slow5:peers6:\x7f\x00\x00\x01\x1a\xe1e",
+    )
+    .unwrap();
+    assert_eq!(res.warning_message.as_deref(), Some("slow"));
+    assert_eq!(res.reannounce_interval(), Duration::from_secs(1200));
+    assert_eq!(res.get_peers(), ["127.0.0.1:6881"]);
+
+    let err = TrackerResponse::from_bytes(b"d5:peers0:e").unwrap_err();
+    assert!(matches!(err, TrackerError::MissingField("interval")));
+}
+
+#[test]
+fn responses_may_list_peers_as_dictionaries() {
+    let res = TrackerResponse::from_bytes(
+        b"d8:intervali60e5:peersl\
+          d7:peer id20:aaaaaaaaaaaaaaaaaaaa2:ip8:10.0.0.14:porti6881ee\
+          d2:ip3:::14:porti51413ee\
+          d2:ip11:example.org4:porti1ee\
+          ee",
+    )
+    .unwrap();
+    let expected: Vec<SocketAddr> = vec![
+        "10.0.0.1:6881".parse().unwrap(),
+        "[::1]:51413".parse().unwrap(),
+    ];
+    assert_eq!(res.peer_addrs(), expected);
+}
+
+const ANNOUNCE_OK: &[u8] = b"d8:intervali1800e5:peers0:e";
+const ANNOUNCE_FAILED: &[u8] = b"d14:failure reason4:busye";
+
+/// An HTTP tracker noting the query of each announce, and answering the `n`th with `answer(n)`.
+async fn scripted_tracker(
+    answer: impl Fn(usize) -> &'static [u8] + Send + 'static,
+) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let queries = Arc::new(Mutex::new(Vec::new()));
+    let recorded = queries.clone();
+    tokio::spawn(async move {
+        let mut answered = 0;
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8_lossy(&request);
+            let query = request
+                .split(' ')
+                .nth(1)
+                .and_then(|target| target.split_once('?'))
+                .map_or("", |(_, query)| query);
+            recorded.lock().unwrap().push(query.to_string());
+            let body = answer(answered);
+            answered += 1;
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+    });
+    (url, queries)
+}
+
+/// A tracker rejecting the first `failures` announces and accepting the rest.
+async fn recording_tracker(failures: usize) -> (String, Arc<Mutex<Vec<String>>>) {
+    scripted_tracker(move |n| {
+        if n < failures {
+            ANNOUNCE_FAILED
+        } else {
+            ANNOUNCE_OK
+        }
+    })
+    .await
+}
+
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .find_map(|param| param.strip_prefix(name)?.strip_prefix('='))
+}
+
+fn events(queries: &Mutex<Vec<String>>) -> Vec<String> {
+    let queries = queries.lock().unwrap();
+    queries
+        .iter()
+        .map(|query| param(query, "event").unwrap_or("none").to_string())
+        .collect()
+}
+
+async fn wait_for_announces(queries: &Mutex<Vec<String>>, count: usize) {
+    for _ in 0..200 {
+        if queries.lock().unwrap().len() >= count {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("tracker saw {:?}", queries.lock().unwrap());
+}
+
+fn tracked_torrent(url: &str) -> Torrent {
+    let mut torrent = torrent_for("data", &[1; 100], 1 << 14);
+    torrent.announce = url.to_string();
+    torrent
+}
+
+#[tokio::test]
+async fn sessions_announce_started_completed_and_stopped() {
+    let (url, queries) = recording_tracker(0).await;
+    let (peers_tx, _peers_rx) = mpsc::channel(1);
+    let session = TrackerSession::new(
+        &tracked_torrent(&url),
+        Arc::new(TransferStats::new(100)),
+        peers_tx,
+    );
+    let (completed_tx, completed_rx) = watch::channel(false);
+    let shutdown = CancellationToken::new();
+    let running = tokio::spawn(session.run(completed_rx, shutdown.clone()));
+
+    wait_for_announces(&queries, 1).await;
+    completed_tx.send_replace(true);
+    wait_for_announces(&queries, 2).await;
+    shutdown.cancel();
+    running.await.unwrap().unwrap();
+    assert_eq!(events(&queries), ["started", "completed", "stopped"]);
+}
+
+#[tokio::test]
+async fn completion_waits_for_started_to_get_through() {
+    let (url, queries) = recording_tracker(0).await;
+    let (peers_tx, _peers_rx) = mpsc::channel(1);
+    let session = TrackerSession::new(
+        &tracked_torrent(&url),
+        Arc::new(TransferStats::new(0)),
+        peers_tx,
+    );
+    // Done before the first announce.
+    let (completed_tx, completed_rx) = watch::channel(false);
+    completed_tx.send_replace(true);
+    let shutdown = CancellationToken::new();
+    let running = tokio::spawn(session.run(completed_rx, shutdown.clone()));
+
+    wait_for_announces(&queries, 2).await;
+    shutdown.cancel();
+    running.await.unwrap().unwrap();
+    assert_eq!(events(&queries), ["started", "completed", "stopped"]);
+}
+
+#[tokio::test]
+async fn nothing_is_stopped_when_started_never_got_through() {
+    let (url, queries) = recording_tracker(usize::MAX).await;
+    let (peers_tx, _peers_rx) = mpsc::channel(1);
+    let session = TrackerSession::new(
+        &tracked_torrent(&url),
+        Arc::new(TransferStats::new(100)),
+        peers_tx,
+    );
+    let (completed_tx, completed_rx) = watch::channel(false);
+    let shutdown = CancellationToken::new();
+    let running = tokio::spawn(session.run(completed_rx, shutdown.clone()));
+
+    wait_for_announces(&queries, 1).await;
+    completed_tx.send_replace(true);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    shutdown.cancel();
+    running.await.unwrap().unwrap();
+    assert_eq!(events(&queries), ["started"]);
+}
+
+#[tokio::test]
+async fn tracker_ids_only_go_back_to_the_tracker_that_issued_them() {
+    // The first tracker hands out an id, then fails once, leaving the second to step in.
+    let (first, first_queries) = scripted_tracker(|n| match n {
+        0 => b"d8:intervali1800e10:tracker id3:abc5:peers0:e",
+        1 => ANNOUNCE_FAILED,
+        _ => ANNOUNCE_OK,
+    })
+    .await;
+    let (second, second_queries) = recording_tracker(0).await;
+    let mut torrent = tracked_torrent("");
+    torrent.announce_list = Some(vec![vec![first], vec![second]]);
+    let mut trackers = TrackerTiers::new(&torrent);
+
+    for _ in 0..3 {
+        trackers
+            .announce(&INFO_HASH, &request("leecher", 7002, 100))
+            .await
+            .unwrap();
+    }
+    let first_queries = first_queries.lock().unwrap();
+    assert_eq!(first_queries.len(), 3);
+    assert_eq!(param(&first_queries[0], "trackerid"), None);
+    assert_eq!(param(&first_queries[1], "trackerid"), Some("abc"));
+    assert_eq!(param(&first_queries[2], "trackerid"), Some("abc"));
+    let second_queries = second_queries.lock().unwrap();
+    assert_eq!(second_queries.len(), 1);
+    assert_eq!(param(&second_queries[0], "trackerid"), None);
+}
+
+#[test]
+fn torrents_may_list_trackers_in_announce_list_alone() {
+    let mut torrent = tracked_torrent("");
+    torrent.announce_list = Some(vec![
+        vec![
+            "http://a/announce".to_string(),
+            "http://b/announce".to_string(),
+        ],
+        vec!["udp://c:80".to_string()],
+    ]);
+    let bytes = serde_bencode::to_bytes(&torrent).unwrap();
+    assert!(!bytes.windows(10).any(|key| key == b"8:announce"));
+    let parsed: Torrent = serde_bencode::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.announce_tiers(), torrent.announce_list.unwrap());
+
+    // Without either, there is no tracker to ask.
+    let trackerless: Torrent =
+        serde_bencode::from_bytes(&serde_bencode::to_bytes(&tracked_torrent("")).unwrap()).unwrap();
+    assert!(trackerless.announce_tiers().is_empty());
+    assert!(TrackerTiers::new(&trackerless).tiers().is_empty());
+}
+
+#[tokio::test]
+async fn scrapes_may_leave_out_the_completion_count() {
+    let mut body = b"d5:filesd20:".to_vec();
+    body.extend(INFO_HASH);
+    body.extend(b"d8:completei3e10:incompletei1eeee");
+    let body: &'static [u8] = body.leak();
+    let (url, _) = scripted_tracker(move |_| body).await;
+
+    let stats = scrape(&url, &[INFO_HASH]).await.unwrap()[&INFO_HASH];
+    assert_eq!((stats.complete, stats.incomplete), (3, 1));
+    assert_eq!(stats.downloaded, 0);
+}
+
+/// A UDP tracker answering connects with `connect_action`, and scrapes with one set of stats
+/// per info hash: seeders, completions and leechers counting up from the hash's first byte.
+async fn udp_tracker(connect_action: u32) -> String {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut buf = [0; 2048];
+        while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+            let action = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+            let mut res = Vec::new();
+            match action {
+                0 => {
+                    assert_eq!(buf[..8], 0x41727101980u64.to_be_bytes());
+                    res.extend(connect_action.to_be_bytes());
+                    res.extend(&buf[12..16]);
+                    res.extend(42u64.to_be_bytes());
+                }
+                2 => {
+                    assert_eq!(
+                        buf[..8],
+                        42u64.to_be_bytes(),
+                        "scrape uses the connection id"
+                    );
+                    res.extend(2u32.to_be_bytes());
+                    res.extend(&buf[12..16]);
+                    for info_hash in buf[16..n].chunks(20) {
+                        for count in 0..3 {
+                            res.extend((info_hash[0] as i32 + count).to_be_bytes());
+                        }
+                    }
+                }
+                _ => panic!("unexpected action {action}"),
+            }
+            socket.send_to(&res, from).await.unwrap();
+        }
+    });
+    url
+}
+
+#[tokio::test]
+async fn scrapes_udp_trackers() {
+    let url = udp_tracker(0).await;
+    let stats = scrape(&url, &[INFO_HASH, [20; 20]]).await.unwrap();
+    assert_eq!(stats.len(), 2);
+    let counts = |info_hash| {
+        let stats = stats[&info_hash];
+        (stats.complete, stats.downloaded, stats.incomplete)
+    };
+    assert_eq!(counts(INFO_HASH), (7, 8, 9));
+    assert_eq!(counts([20; 20]), (20, 21, 22));
+}
+
+#[tokio::test]
+async fn udp_answers_to_the_wrong_action_are_rejected() {
+    // Answering a connect as if it were an announce.
+    let url = udp_tracker(1).await;
+    let err = scrape(&url, &[INFO_HASH]).await.unwrap_err();
+    assert!(err.to_string().contains("action"), "{err}");
+}
+
+#[tokio::test]
+async fn udp_tracker_errors_are_failures() {
+    let url = udp_tracker(3).await;
+    let err = scrape(&url, &[INFO_HASH]).await.unwrap_err();
+    assert!(
+        matches!(err.downcast_ref(), Some(TrackerError::Failure(_))),
+        "{err}"
+    );
+}
@@ -0,0 +1,178 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bittorrent_starter_rust::utp::UtpSocket;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn streams_transfer_data_and_close() {
+    let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let data = payload(1 << 20);
+    let expected = data.clone();
+    let receiver = tokio::spawn(async move {
+        let (mut stream, _) = server.accept().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        stream.write_all(b"thanks").await.unwrap();
+        stream.shutdown().await.unwrap();
+        received
+    });
+
+    let mut stream = client.connect(server_addr).await.unwrap();
+    stream.write_all(&data).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await.unwrap();
+
+    assert_eq!(reply, b"thanks");
+    assert!(receiver.await.unwrap() == expected);
+}
+
+/// Forwards datagrams between a client and `server`, dropping every `nth` of them.
+async fn lossy_proxy(server: SocketAddr, nth: usize) -> SocketAddr {
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut client = None;
+        let mut buf = vec![0u8; 65536];
+        let mut count = 0;
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+            count += 1;
+            if count % nth == 0 {
+                continue;
+            }
+            let to = if from == server {
+                match client {
+                    Some(client) => client,
+                    None => continue,
+                }
+            } else {
+                client = Some(from);
+                server
+            };
+            let _ = socket.send_to(&buf[..n], to).await;
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn lost_packets_are_retransmitted() {
+    let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let proxy = lossy_proxy(server.local_addr().unwrap(), 20).await;
+
+    let data = payload(100_000);
+    let expected = data.clone();
+    let receiver = tokio::spawn(async move {
+        let (mut stream, _) = server.accept().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        received
+    });
+
+    let mut stream = client.connect(proxy).await.unwrap();
+    stream.write_all(&data).await.unwrap();
+    stream.shutdown().await.unwrap();
+    assert!(receiver.await.unwrap() == expected);
+}
+
+#[tokio::test]
+async fn connecting_to_a_silent_address_fails() {
+    let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    // Bound but never answering.
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let res = tokio::time::timeout(
+        std::time::Duration::from_secs(2),
+        client.connect(silent.local_addr().unwrap()),
+    )
+    .await;
+    assert!(res.is_err());
+}
+
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+
+/// A bare uTP packet: kind, connection id, sequence and ack numbers, and no payload.
+fn packet(kind: u8, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Vec<u8> {
+    let mut packet = vec![kind << 4 | 1, 0];
+    packet.extend(connection_id.to_be_bytes());
+    packet.extend([0; 12]);
+    packet.extend(seq_nr.to_be_bytes());
+    packet.extend(ack_nr.to_be_bytes());
+    packet
+}
+
+/// The kind, connection id and sequence number of the next packet to arrive.
+async fn next_packet(socket: &UdpSocket) -> (u8, u16, u16) {
+    let mut buf = [0; 1500];
+    let timeout = std::time::Duration::from_secs(5);
+    let n = tokio::time::timeout(timeout, socket.recv(&mut buf))
+        .await
+        .expect("a packet arrives")
+        .unwrap();
+    assert!(n >= 20);
+    let u16_at = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+    (buf[0] >> 4, u16_at(2), u16_at(16))
+}
+
+#[tokio::test]
+async fn dropped_streams_resend_their_fin_until_it_is_acked() {
+    let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    peer.connect(server.local_addr().unwrap()).await.unwrap();
+
+    // We listen on 100 and send on 101.
+    peer.send(&packet(ST_SYN, 100, 1, 0)).await.unwrap();
+    assert_eq!(next_packet(&peer).await.0, ST_STATE);
+    let (stream, _) = server.accept().await.unwrap();
+    drop(stream);
+
+    let (kind, connection_id, fin_seq) = next_packet(&peer).await;
+    assert_eq!((kind, connection_id), (ST_FIN, 100));
+    // Left unacknowledged, the FIN comes again.
+    assert_eq!(next_packet(&peer).await, (ST_FIN, 100, fin_seq));
+
+    peer.send(&packet(ST_STATE, 101, 1, fin_seq)).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    // Now the connection is gone, and anything sent on it is answered with a reset.
+    peer.send(&packet(0, 101, 2, fin_seq)).await.unwrap();
+    let (kind, connection_id, _) = next_packet(&peer).await;
+    assert_eq!((kind, connection_id), (ST_RESET, 101));
+}
+
+#[tokio::test]
+async fn resets_answering_our_packets_close_the_stream() {
+    let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let answer = async {
+        let mut buf = [0; 1500];
+        let (_, from) = peer.recv_from(&mut buf).await.unwrap();
+        peer.connect(from).await.unwrap();
+        let listen_id = u16::from_be_bytes([buf[2], buf[3]]);
+        peer.send(&packet(ST_STATE, listen_id, 7, 1)).await.unwrap();
+        listen_id
+    };
+    let (stream, listen_id) = tokio::join!(client.connect(peer_addr), answer);
+    let mut stream = stream.unwrap();
+
+    // A reset names the id our packets carry, one past the id we listen on.
+    peer.send(&packet(ST_RESET, listen_id.wrapping_add(1), 8, 1))
+        .await
+        .unwrap();
+    let mut buf = [0; 16];
+    let err = stream.read(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+}
@@ -0,0 +1,206 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use bittorrent_starter_rust::create::{MetaVersion, TorrentBuilder};
+use bittorrent_starter_rust::download::download_file;
+use bittorrent_starter_rust::handshake::{perform_handshake, Extensions};
+use bittorrent_starter_rust::merkle::{self, Hash, BLOCK_SIZE};
+use bittorrent_starter_rust::message::MessageFramer;
+use bittorrent_starter_rust::mock_peer::{test_data, torrent_for, MockPeer};
+use bittorrent_starter_rust::storage::{FsStorage, Storage};
+use bittorrent_starter_rust::torrent::Torrent;
+use serde_bytes::ByteBuf;
+use tokio_util::codec::Framed;
+
+const PIECE_LENGTH: usize = 1 << 15;
+
+/// Straight from the definition: hash every block, pad the leaves with zeros to a power of two
+/// and pair them up to the root.
+fn reference_root(data: &[u8]) -> Hash {
+    let mut layer: Vec<Hash> = data.chunks(BLOCK_SIZE).map(merkle::sha256).collect();
+    layer.resize(layer.len().next_power_of_two(), [0; 32]);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| merkle::sha256(&[pair[0], pair[1]].concat()))
+            .collect();
+    }
+    layer[0]
+}
+
+async fn create(path: &Path, meta_version: MetaVersion) -> Torrent {
+    let torrent = TorrentBuilder::new(path)
+        .tracker("http://127.0.0.1:6969/announce")
+        .piece_length(PIECE_LENGTH)
+        .meta_version(meta_version)
+        .creation_date(None)
+        .build()
+        .await
+        .unwrap();
+    let bytes = serde_bencode::to_bytes(&torrent).unwrap();
+    let parsed: Torrent = serde_bencode::from_bytes(&bytes).unwrap();
+    assert_eq!(serde_bencode::to_bytes(&parsed).unwrap(), bytes);
+    parsed
+}
+
+/// Three files: one spanning several pieces with a short last one, one smaller than a piece
+/// and one empty.
+fn write_dataset(root: &Path) -> [Vec<u8>; 2] {
+    let big = test_data(3 * PIECE_LENGTH + 5000, 1);
+    let small = test_data(20_000, 2);
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(root.join("big.bin"), &big).unwrap();
+    std::fs::write(root.join("sub/small.bin"), &small).unwrap();
+    std::fs::write(root.join("sub/zero.bin"), b"").unwrap();
+    [big, small]
+}
+
+#[tokio::test]
+async fn v2_torrents_carry_merkle_roots_and_piece_layers() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("dataset");
+    let [big, small] = write_dataset(&root);
+    let torrent = create(&root, MetaVersion::V2).await;
+
+    assert!(torrent.info.is_v2() && !torrent.info.is_v1());
+    let files = torrent.info.file_tree.as_ref().unwrap().files();
+    let files: Vec<_> = files
+        .iter()
+        .map(|(path, file)| (path.join("/"), file.length, file.pieces_root.clone()))
+        .collect();
+    let root_of = |data: &[u8]| Some(ByteBuf::from(reference_root(data).to_vec()));
+    assert_eq!(
+        files,
+        [
+            (String::from("big.bin"), big.len(), root_of(&big)),
+            (String::from("sub/small.bin"), small.len(), root_of(&small)),
+            (String::from("sub/zero.bin"), 0, None),
+        ]
+    );
+    // Only files larger than a piece need a layer.
+    assert_eq!(torrent.piece_layers.as_ref().unwrap().len(), 1);
+    torrent.check_piece_layers().unwrap();
+
+    let info_hash_v2 = torrent.info_hash_v2().unwrap();
+    assert_eq!(torrent.info_hash(), info_hash_v2[..20]);
+    assert_eq!(torrent.swarm_hashes(), [torrent.info_hash()]);
+}
+
+#[tokio::test]
+async fn v2_pieces_are_verified_against_their_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("dataset");
+    let [big, small] = write_dataset(&root);
+    let torrent = create(&root, MetaVersion::V2).await;
+
+    // Pieces never span files: four for the big file, one for the small one.
+    assert_eq!(torrent.info.piece_count(), 5);
+    assert_eq!(torrent.info.piece_size(3), 5000);
+    assert_eq!(torrent.info.piece_size(4), small.len());
+    // Padding after every file but the last keeps the next one on a piece boundary.
+    assert_eq!(torrent.info.length(), 5 * PIECE_LENGTH);
+
+    let pieces: Vec<&[u8]> = big.chunks(PIECE_LENGTH).chain([&small[..]]).collect();
+    for (piece_index, piece) in pieces.iter().enumerate() {
+        assert!(torrent.verify_piece(piece_index, piece), "{piece_index}");
+    }
+    let mut corrupt = pieces[1].to_vec();
+    corrupt[100] ^= 1;
+    assert!(!torrent.verify_piece(1, &corrupt));
+    assert!(!torrent.verify_piece(4, pieces[3]));
+    assert!(!torrent.verify_piece(5, pieces[4]));
+}
+
+#[tokio::test]
+async fn tampered_piece_layers_are_caught() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("dataset");
+    write_dataset(&root);
+    let mut torrent = create(&root, MetaVersion::V2).await;
+
+    let layer = torrent
+        .piece_layers
+        .as_mut()
+        .unwrap()
+        .values_mut()
+        .next()
+        .unwrap();
+    layer[0] ^= 1;
+    let err = torrent.check_piece_layers().unwrap_err();
+    assert!(err.to_string().contains("big.bin"), "{err:#}");
+
+    torrent.piece_layers = None;
+    let err = torrent.check_piece_layers().unwrap_err();
+    assert!(err.to_string().contains("no piece layer"), "{err:#}");
+}
+
+#[tokio::test]
+async fn hybrid_torrents_share_piece_boundaries_between_versions() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("dataset");
+    let [big, small] = write_dataset(&root);
+    let hybrid = create(&root, MetaVersion::Hybrid).await;
+    let v2 = create(&root, MetaVersion::V2).await;
+
+    assert!(hybrid.info.is_hybrid());
+    let v1_files: Vec<_> = hybrid
+        .info
+        .files
+        .iter()
+        .flatten()
+        .map(|file| (file.path.join("/"), file.length, file.is_padding()))
+        .collect();
+    let padding = PIECE_LENGTH - 5000;
+    assert_eq!(
+        v1_files,
+        [
+            (String::from("big.bin"), big.len(), false),
+            (format!(".pad/{padding}"), padding, true),
+            (String::from("sub/small.bin"), small.len(), false),
+            (
+                format!(".pad/{}", PIECE_LENGTH - small.len()),
+                PIECE_LENGTH - small.len(),
+                true
+            ),
+            (String::from("sub/zero.bin"), 0, false),
+        ]
+    );
+
+    // The v1 pieces hash the same data laid out with zeros in the padding.
+    let mut padded = big.clone();
+    padded.resize(4 * PIECE_LENGTH, 0);
+    padded.extend(&small);
+    padded.resize(5 * PIECE_LENGTH, 0);
+    let expected = torrent_for("dataset", &padded, PIECE_LENGTH);
+    assert_eq!(hybrid.info.pieces, expected.info.pieces);
+    assert_eq!(hybrid.info.file_tree.as_ref().unwrap().files().len(), 3);
+    assert_eq!(hybrid.piece_layers, v2.piece_layers);
+
+    let swarms = hybrid.swarm_hashes();
+    assert_eq!(swarms.len(), 2);
+    assert_eq!(swarms[1], hybrid.info_hash_v2().unwrap()[..20]);
+    assert_ne!(swarms[0], swarms[1]);
+}
+
+#[tokio::test]
+async fn v2_only_torrents_download_from_a_peer() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.bin");
+    let data = test_data(5 * PIECE_LENGTH + 123, 3);
+    std::fs::write(&path, &data).unwrap();
+    let torrent = Arc::new(create(&path, MetaVersion::V2).await);
+
+    let peer = MockPeer::new(torrent.clone(), data.clone());
+    let (mut ours, theirs) = tokio::io::duplex(1 << 16);
+    tokio::spawn(async move { peer.serve(theirs).await });
+    perform_handshake(&torrent, &mut ours, Extensions::default())
+        .await
+        .unwrap();
+    let mut framed = Framed::new(&mut ours, MessageFramer);
+    let out = tempfile::NamedTempFile::new().unwrap();
+    let storage: Arc<dyn Storage> = Arc::new(FsStorage::new(torrent.clone(), out.path()).unwrap());
+    download_file(&torrent, &mut framed, &storage)
+        .await
+        .unwrap();
+    assert!(std::fs::read(out.path()).unwrap() == data);
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bittorrent_starter_rust::create::TorrentBuilder;
+use bittorrent_starter_rust::download::{download_swarm, SwarmOptions, TransferStats};
+use bittorrent_starter_rust::mock_peer::{test_data, torrent_for, MockPeer};
+use bittorrent_starter_rust::mse::EncryptionPolicy;
+use bittorrent_starter_rust::storage::MemoryStorage;
+use bittorrent_starter_rust::torrent::Torrent;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+const PIECE_LENGTH: usize = 1 << 14;
+
+/// A bare-bones HTTP server for files kept in memory.
+#[derive(Default)]
+struct MirrorConfig {
+    files: HashMap<String, Vec<u8>>,
+    ignore_ranges: bool,
+    corrupt: bool,
+}
+
+async fn serve_mirror(config: MirrorConfig) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let config = Arc::new(config);
+    let counter = requests.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let config = config.clone();
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        return;
+                    }
+                    request.extend(&buf[..n]);
+                }
+                counter.fetch_add(1, Ordering::Relaxed);
+                let request = String::from_utf8(request).unwrap();
+                let path = request.split(' ').nth(1).unwrap();
+                let range = request.lines().find_map(|line| {
+                    let spec = line.to_ascii_lowercase();
+                    let spec = spec.strip_prefix("range: bytes=")?.to_string();
+                    let (from, to) = spec.split_once('-')?;
+                    Some((from.parse::<usize>().ok()?, to.parse::<usize>().ok()?))
+                });
+                let response = match config.files.get(path) {
+                    None => b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n".to_vec(),
+                    Some(content) => {
+                        let (status, mut body) = match range {
+                            Some((from, to)) if !config.ignore_ranges => {
+                                ("206 Partial Content", content[from..=to].to_vec())
+                            }
+                            _ => ("200 OK", content.clone()),
+                        };
+                        if config.corrupt {
+                            body.iter_mut().for_each(|byte| *byte ^= 0x55);
+                        }
+                        let mut response = format!(
+                            "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                            body.len()
+                        )
+                        .into_bytes();
+                        response.extend(body);
+                        response
+                    }
+                };
+                let _ = stream.write_all(&response).await;
+            });
+        }
+    });
+    (addr, requests)
+}
+
+fn options() -> SwarmOptions {
+    SwarmOptions {
+        encryption: EncryptionPolicy::Plaintext,
+        web_seeds: true,
+        ..Default::default()
+    }
+}
+
+/// Downloads `torrent` from its web seeds and the given peers only.
+async fn download(torrent: Torrent, peers: &[SocketAddr]) -> anyhow::Result<Vec<u8>> {
+    let torrent = Arc::new(torrent);
+    let (peers_tx, peers_rx) = mpsc::channel(peers.len().max(1));
+    for &peer in peers {
+        peers_tx.send(peer).await.unwrap();
+    }
+    drop(peers_tx);
+    let storage = Arc::new(MemoryStorage::new(torrent.clone()));
+    let stats = Arc::new(TransferStats::new(torrent.info.length()));
+    download_swarm(torrent, peers_rx, storage.clone(), stats, options()).await?;
+    Ok(storage.contents())
+}
+
+#[tokio::test]
+async fn single_files_download_from_a_web_seed_alone() {
+    let data = test_data(100_000, 1);
+    for ignore_ranges in [false, true] {
+        let (addr, requests) = serve_mirror(MirrorConfig {
+            files: HashMap::from([(String::from("/mirror/data.bin"), data.clone())]),
+            ignore_ranges,
+            ..Default::default()
+        })
+        .await;
+        let mut torrent = torrent_for("data.bin", &data, PIECE_LENGTH);
+        torrent.url_list = Some(vec![format!("http://{addr}/mirror/data.bin")]);
+
+        assert!(download(torrent, &[]).await.unwrap() == data);
+        assert_eq!(
+            requests.load(Ordering::Relaxed),
+            data.len().div_ceil(PIECE_LENGTH)
+        );
+    }
+}
+
+#[tokio::test]
+async fn multi_file_pieces_are_fetched_from_each_file_url() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("dataset");
+    std::fs::create_dir_all(root.join("sub dir")).unwrap();
+    // Small files put several of them into one piece.
+    let files = [
+        ("a.bin", test_data(10_000, 1)),
+        ("sub dir/b c.bin", test_data(5_000, 2)),
+        ("sub dir/d.bin", test_data(40_000, 3)),
+    ];
+    for (path, content) in &files {
+        std::fs::write(root.join(path), content).unwrap();
+    }
+    let (addr, _) = serve_mirror(MirrorConfig {
+        files: files
+            .iter()
+            .map(|(path, content)| {
+                let path = format!("/files/dataset/{}", path.replace(' ', "%20"));
+                (path, content.clone())
+            })
+            .collect(),
+        ..Default::default()
+    })
+    .await;
+    let torrent = TorrentBuilder::new(&root)
+        .tracker("http://127.0.0.1:6969/announce")
+        .piece_length(PIECE_LENGTH)
+        .web_seed(format!("http://{addr}/files/"))
+        .build()
+        .await
+        .unwrap();
+
+    let expected: Vec<u8> = files
+        .iter()
+        .flat_map(|(_, content)| content.clone())
+        .collect();
+    assert!(download(torrent, &[]).await.unwrap() == expected);
+}
+
+#[tokio::test]
+async fn peers_take_over_from_a_corrupt_web_seed() {
+    let data = test_data(200_000, 4);
+    let (addr, requests) = serve_mirror(MirrorConfig {
+        files: HashMap::from([(String::from("/data.bin"), data.clone())]),
+        corrupt: true,
+        ..Default::default()
+    })
+    .await;
+    let mut torrent = torrent_for("data.bin", &data, PIECE_LENGTH);
+    torrent.url_list = Some(vec![format!("http://{addr}/data.bin")]);
+    let peer = MockPeer::new(Arc::new(torrent.clone()), data.clone())
+        .listen("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+
+    assert!(download(torrent, &[peer]).await.unwrap() == data);
+    assert_eq!(requests.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn unreachable_web_seeds_do_not_hold_up_a_failed_download() {
+    let data = test_data(50_000, 5);
+    let (addr, _) = serve_mirror(MirrorConfig::default()).await;
+    let mut torrent = torrent_for("data.bin", &data, PIECE_LENGTH);
+    torrent.url_list = Some(vec![format!("http://{addr}/missing.bin")]);
+
+    let err = download(torrent, &[]).await.unwrap_err();
+    assert!(err.to_string().contains("ran out of peers"), "{err:#}");
+}